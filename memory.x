@@ -4,7 +4,9 @@
 MEMORY {
   /* RP2040 external QSPI flash mapped at XIP */
   BOOT2 (rx)  : ORIGIN = 0x10000000, LENGTH = 0x100       /* 256-byte second-stage bootloader */
-  FLASH (rx)  : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
+  FLASH (rx)  : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100 - 128K
+  /* 末尾128KBはプログラムに使わず、すれ違いログ/設定の保存領域として予約 */
+  STORAGE (r) : ORIGIN = 0x101E0000, LENGTH = 128K
   RAM   (rwx) : ORIGIN = 0x20000000, LENGTH = 264K
 }
 
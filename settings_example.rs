@@ -1,15 +1,20 @@
 //! ユーザー設定
 //! 補足:
 //! - デベロッパーモード(true): API送信は30秒ごと。ログ詳細。動作確認に便利。
-//! - 通常モード(false): API送信は毎日 午前3時(JST)。実運用向け。
+//! - 通常モード(false): API送信は毎日 午前3時〜4時(JST) の端末ごとに決まる時刻。実運用向け。
 //! - APIエンドポイント: あなたのサーバのURL/ポート/パスに合わせてください。
 //! - WiFi情報: `Steps/wifi_config.rs` に SSID/パスワードを設定してください（このファイルでは変更しません）。
 //! 
+//! - DEVELOPER_MODE / API_HOST / API_PORT / API_PATH / WIFI_SSID / WIFI_PSK は既定値です。
+//!   設定ポータルで保存した設定がフラッシュにあればそちらが使われます（1つのファームを複数台で使える）。
+//! 
 //! このファイル名をsettings_example.rsからsettins.rsに変更して使用してください。
 //! 
 
-/// 初心者向け: デベロッパーモードを切り替える（true か false を変えるだけ）
-pub const DEVELOPER_MODE: bool = true; // true=30秒毎送信 / false=毎日3時に送信
+use pico_w_id_beacon::wifi_select::KnownNetwork;
+
+/// 初心者向け: デベロッパーモードを切り替える（true か false を変えるだけ。既定値）
+pub const DEVELOPER_MODE: bool = true; // true=30秒毎送信 / false=毎日3〜4時に送信
 
 /// APIサーバのホスト名 or IP
 pub const API_HOST: &str = "192.168.1.23"; // 例: "192.168.1.23" や "example.com"
@@ -20,12 +25,108 @@ pub const API_PORT: u16 = 3000; // 例: 80, 3000, 8080 など
 /// APIのパス
 pub const API_PATH: &str = "/"; // 例: "/api/encounters"
 
-/// デベロッパーモードかどうか（内部用）
-#[inline]
-pub fn is_developer_mode() -> bool { DEVELOPER_MODE }
+/// HTTPS で送信するか（設定が未保存のときの既定値。`config set tls on|off` で変更可）
+pub const API_TLS: bool = false; // 例: 本番サーバは true
+
+/// サーバ証明書を発行した CA 証明書（DER 形式）の一覧。`config set ca <番号>` で使う証明書を選ぶ（既定は 0）。
+/// HTTPS を使わないなら空でよい。
+/// 例: `&[include_bytes!("ca.der")]`（`openssl x509 -in ca.pem -outform der -out ca.der`）
+pub const API_TLS_CAS: &[&[u8]] = &[];
+
+/// 一時ID用の秘密鍵（32バイト推奨）。全端末で同じでよい（一時ID・IRK は BD_ADDR と組み合わせて端末ごとに導出する）。
+/// サーバに同じ鍵と各端末の BD_ADDR を登録しておく。
+/// 設定すると広告に BD_ADDR の代わりに10分ごとに変わる一時ID（v2）を載せる（空なら BD_ADDR のまま＝v1）。
+pub const PRIVACY_ID_KEY: &[u8] = b"";
+
+/// BLE アドレスを RPA（Resolvable Private Address）にして一時IDと同じ10分ごとに切り替えるか。
+/// false なら BD_ADDR から決まる端末ごとの静的ランダムアドレス（起動しても変わらない）。
+/// PRIVACY_ID_KEY を設定した場合は false でも RPA にする（固定アドレスで一時IDが結び付けられるため）。
+/// RPA の IRK は PRIVACY_ID_KEY（空なら BD_ADDR）から導出する。
+/// PRIVACY_ID_KEY が空のまま true にしても広告のペイロードは BD_ADDR（v1）のままなので、端末の追跡は防げない。
+pub const BLE_RPA: bool = false;
+
+/// 広告を TLV 形式（v3）にするか。false なら8バイト固定の形式（v1/v2、旧ファームの端末も受信できる）。
+pub const ADV_TLV: bool = false;
+
+/// TLV 形式で広告に載せるニックネーム（UTF-8、空なら載せない）。入りきらない分は切り詰める。
+pub const ADV_NICKNAME: &str = "";
+
+/// スキャン応答を返すか。true ならスキャン可能な広告にし、短縮名 "PicoStreet-XXXX"（広告中の ID の下位2バイト）と
+/// ADV_NICKNAME（12バイトまで）を返す（ADV_TLV=false でもニックネームを相手に届けられる）。
+pub const ADV_SCAN_RESPONSE: bool = false;
+
+/// アクティブスキャンにするか。true なら相手のスキャン応答を受け取り、ニックネームをすれ違いの記録に付ける。
+/// スキャン要求を送るぶん電力が増え、自分の存在も相手に知られる（パッシブスキャンは受信だけ）。
+pub const ACTIVE_SCAN: bool = false;
+
+/// 拡張広告（Bluetooth 5）にするか。true なら TLV 形式で TX 電力と切り詰めないニックネームを載せ、拡張スキャンで受信する
+/// （ADV_SCAN_RESPONSE の名前も広告に含める）。受信できるのは拡張スキャンに対応した端末だけ。
+/// コントローラが受け付けなければ通常の広告に戻す。RPA のとき（BLE_RPA=true または PRIVACY_ID_KEY 設定時）は使えない（通常の広告になる）。
+pub const ADV_EXTENDED: bool = false;
+
+/// 拡張広告を Coded PHY（長距離、最大で約4倍）にするか。ADV_EXTENDED=true のときのみ有効。
+/// 受信側も ADV_CODED_PHY=true（Coded PHY でスキャン）である必要がある。非対応なら 1M PHY に戻す。
+pub const ADV_CODED_PHY: bool = false;
+
+/// 相互確認（GATT 接続での ID 交換）を行うか。true なら接続可能な広告にし、記録した相手のうち
+/// BLE アドレスの小さい方が接続して互いの ID を交換する（両方が true の端末どうしで成立）。
+pub const MUTUAL_EXCHANGE: bool = false;
+
+/// GATT 情報サービスを公開するか。true なら接続可能な広告にし、スマホから広告中の ID・ファームウェアの
+/// バージョン・記録件数・最終送信時刻・電池残量を読めるようにする。
+pub const GATT_INFO: bool = false;
 
-/// WiFi アクセスポイントのSSID（ネットワーク名）
-pub const WIFI_SSID: &str = "AP_NAME"; // ← WiFiのSSIDを入絵よく
+/// GATT 設定サービスを公開するか。true なら接続可能な広告にし、スマホから WiFi / API の設定と
+/// デベロッパーモードを変更できるようにする（GATT_CONFIG_KEY で暗号化・認証。再起動後に反映）。
+pub const GATT_CONFIG: bool = false;
+
+/// GATT 情報サービスの制御コマンド（送信・ログ消去・再起動）の認証鍵（32バイト推奨）。
+/// 空なら制御コマンドは受け付けない。アプリに同じ鍵を設定しておく。
+pub const GATT_CONTROL_KEY: &[u8] = b"";
+
+/// GATT 設定サービス（WiFi のパスワードなどの変更）の暗号化・認証鍵（32バイト推奨、管理者だけが持つ）。
+/// 空、または GATT_CONTROL_KEY と同じなら設定の書き込みは受け付けない（制御用の鍵を配ったアプリで設定を変えられないようにする）。
+pub const GATT_CONFIG_KEY: &[u8] = b"";
+
+/// 広告に載せる TX 電力の補正値（dB）。コントローラの値に足す（ケースやアンテナの損失ぶんを負の値で）。
+/// 受信側はこの値と RSSI から距離を推定する。
+pub const TX_POWER_OFFSET_DB: i8 = 0;
+
+/// すれ違いとみなす平滑化 RSSI の下限（dBm）。壁越しや遠くを通り過ぎた相手を除く。
+pub const ENCOUNTER_MIN_RSSI: i8 = -80;
+
+/// すれ違いとみなすのに必要な、ENCOUNTER_WINDOW_SECS 秒以内の検出回数（1〜8）
+pub const ENCOUNTER_MIN_SIGHTINGS: u8 = 3;
+
+/// 検出回数を数える窓（秒）。これより長く途切れた相手は数え直す
+pub const ENCOUNTER_WINDOW_SECS: u32 = 30;
+
+/// すれ違いとみなすのに必要な滞在時間（秒）。RSSI が下限以上の状態がこれだけ続いたら確定する。
+/// 下限 -128・回数 1・滞在 0 にすると受信した検出をすべて記録する（従来の動作）。
+pub const ENCOUNTER_MIN_DWELL_SECS: u32 = 10;
+
+/// 通常モードの送信ウィンドウ開始時刻（JST、0:00 からの秒数）
+pub const UPLOAD_WINDOW_START_SECS: u32 = 3 * 3600; // 03:00
+
+/// 送信ウィンドウの幅（秒）。端末ごとに BD_ADDR から決まる時刻へ分散して送信する
+pub const UPLOAD_WINDOW_LEN_SECS: u32 = 3600; // 03:00〜04:00
+
+/// WiFi アクセスポイントのSSID（ネットワーク名、既定値）。空（または例の "AP_NAME" のまま）だと初回起動時に設定ポータルが開く
+pub const WIFI_SSID: &str = ""; // ← WiFiのSSIDを入力（例: "AP_NAME"）
 
 /// WiFi パスワード（PSK）
-pub const WIFI_PSK: &str = "PASSWORD"; // ← パスワードを入力
\ No newline at end of file
+pub const WIFI_PSK: &str = "PASSWORD"; // ← パスワードを入力
+
+/// 追加の登録済みWiFiネットワーク（WIFI_SSID と合わせて最大8件）。起動/再接続時にスキャンし、
+/// 見えているものを優先度の高い順（同じなら電波の強い順）に試す。
+/// 設定の AP（WIFI_SSID または設定ポータルで保存した AP）はこれより優先される。
+pub const WIFI_NETWORKS: &[KnownNetwork] = &[
+    // KnownNetwork { ssid: "SCHOOL_AP", psk: "PASSWORD", priority: 5 },
+    // KnownNetwork { ssid: "OFFICE_AP", psk: "PASSWORD", priority: 5 },
+];
+
+/// 設定ポータル（ソフトAP "PicoStreet-XXXX"）のパスワードを導出する秘密値（16バイト以上推奨）。
+/// パスワードは端末ごとに `HMAC-SHA256(この値, "PicoStreet-AP" || BD_ADDR)` の先頭8バイトの16進（16文字）で、
+/// 起動ログにも表示される。空の場合はポータルを起動しない（全台共通のパスワードにしないため）。
+/// ポータルは接続先が未設定のとき、または起動時に BOOTSEL ボタンを3秒押し続けたときに起動する。
+pub const PROVISION_AP_SECRET: &[u8] = b"";
@@ -1,28 +1,144 @@
 //! PicoStreet X交換特化プロトコル
-//! - Service Data(0x16) の中に8バイト固定ペイロード
-//! - Version(1) + DeviceType(1) + BD_ADDR(6)
+//! - Service Data(0x16) の中にペイロード
+//! - v1/v2: 8バイト固定 Version(1) + DeviceType(1) + ID(6)
+//!   - v1: ID = BD_ADDR（固定。誰でも同じ端末を追跡できる）
+//!   - v2: ID = 一時ID（端末秘密鍵と時刻エポックの HMAC。`ROTATION_SECS` ごとに変わり、サーバだけが持ち主を特定できる）
+//! - v3: Version(1) + DeviceType(1) + TLV(Type(1) Length(1) Value)...
+//!   - ID（BD_ADDR か一時ID）は必須。TX電力・電池残量・フラグ・ニックネームは任意
+//!   - 未知の Type は読み飛ばす（前方互換）。`TlvBuilder` で 31バイトの広告に収まるように組み立てる
+//!   - 拡張広告（Bluetooth 5）では `MAX_EXTENDED_PAYLOAD` まで使える（ニックネームを切り詰めずに載せられる）
+//! - スキャン応答（任意）: Shortened Local Name "PicoStreet-XXXX"（ID 下位2バイト）+ Complete Local Name にニックネーム
 
 use core::fmt;
 
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
 use crate::constants::SERVICE_UUID_16;
 
+/// BD_ADDR をそのまま載せる形式
+pub const VERSION_BD_ADDR: u8 = 0x01;
+/// 一時IDを載せる形式
+pub const VERSION_EPHEMERAL: u8 = 0x02;
+/// TLV 形式
+pub const VERSION_TLV: u8 = 0x03;
+
+/// TLV の Type
+pub const TLV_ID_BD_ADDR: u8 = 0x01;
+pub const TLV_ID_EPHEMERAL: u8 = 0x02;
+/// TX電力（i8, dBm）
+pub const TLV_TX_POWER: u8 = 0x03;
+/// 電池残量（u8, %）
+pub const TLV_BATTERY: u8 = 0x04;
+/// フラグ（u8、未定義のビットは無視する）
+pub const TLV_FLAGS: u8 = 0x05;
+/// ニックネーム（UTF-8）
+pub const TLV_NICKNAME: u8 = 0x06;
+
+/// AD Type: TX Power Level（i8, dBm）
+pub const AD_TYPE_TX_POWER: u8 = 0x0A;
+/// AD Type: Shortened Local Name
+pub const AD_TYPE_SHORT_NAME: u8 = 0x08;
+/// AD Type: Complete Local Name
+pub const AD_TYPE_COMPLETE_NAME: u8 = 0x09;
+
+/// スキャン応答の短縮名の接頭辞（後ろに ID の [4][5] を16進4桁。設定ポータルの AP 名と同じ形）
+pub const SHORT_NAME_PREFIX: &str = "PicoStreet-";
+
+/// スキャン応答の最大長（レガシー）
+pub const MAX_SCAN_RESPONSE: usize = 31;
+
+/// 通常（レガシー）広告で Service Data に使えるペイロード長
+/// 31B - Flags(3B) - TX Power Level(3B) - Service Data ヘッダ(len, type, UUID = 4B)
+pub const MAX_LEGACY_PAYLOAD: usize = 21;
+
+/// 拡張広告で Service Data に使うペイロード長（今の TLV をすべて載せても余裕がある長さ。
+/// AD 全体は拡張広告データの1回の HCI コマンドに収まる）
+pub const MAX_EXTENDED_PAYLOAD: usize = 64;
+
+/// ニックネームの最大長（バイト）
+pub const MAX_NICKNAME_LEN: usize = 16;
+
+const DEVICE_TYPE_PICOSTREET: u8 = 0x50; // 'P'
+
+/// 一時IDを切り替える間隔（秒）
+pub const ROTATION_SECS: u64 = 600;
+
+/// 時刻未同期のエポック（最上位ビットで区別する。サーバでは解決できない）
+pub const UNSYNCED_EPOCH_FLAG: u32 = 0x8000_0000;
+
+/// 一時IDの導出に使うラベル（HMAC の入力先頭）
+const EPHEMERAL_ID_LABEL: &[u8] = b"PicoStreet-EID";
+
 /// 解析結果（簡素化版）
 #[derive(Copy, Clone, Eq, PartialEq)]
 pub struct Parsed {
     pub version: u8,
     pub device_type: u8,
-    pub bd_addr: [u8; 6],
+    /// BD_ADDR または一時ID
+    pub id: [u8; 6],
+    /// `id` が一時IDかどうか
+    pub ephemeral: bool,
+    /// 相手の TX 電力（TLV または TX Power Level AD）
+    pub tx_power: Option<i8>,
+    /// 以下は v3（TLV）で載っていた場合のみ
+    pub battery: Option<u8>,
+    pub flags: Option<u8>,
+    pub nickname: Option<Nickname>,
+}
+
+/// ニックネーム（UTF-8、最大 `MAX_NICKNAME_LEN` バイト）
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct Nickname {
+    len: u8,
+    bytes: [u8; MAX_NICKNAME_LEN],
+}
+
+impl Nickname {
+    /// 長すぎる・UTF-8 でない場合は None
+    pub fn new(s: &[u8]) -> Option<Self> {
+        if s.len() > MAX_NICKNAME_LEN || core::str::from_utf8(s).is_err() {
+            return None;
+        }
+        let mut bytes = [0u8; MAX_NICKNAME_LEN];
+        bytes[..s.len()].copy_from_slice(s);
+        Some(Self { len: s.len() as u8, bytes })
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len as usize]).unwrap_or("")
+    }
+}
+
+impl fmt::Debug for Nickname {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
 }
 
 impl fmt::Debug for Parsed {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "Parsed {{ ver: {}, dev_type: 0x{:02X}, bd_addr: {:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X} }}",
+            "Parsed {{ ver: {}, dev_type: 0x{:02X}, id: {:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}{}",
             self.version, self.device_type,
-            self.bd_addr[0], self.bd_addr[1], self.bd_addr[2],
-            self.bd_addr[3], self.bd_addr[4], self.bd_addr[5]
-        )
+            self.id[0], self.id[1], self.id[2],
+            self.id[3], self.id[4], self.id[5],
+            if self.ephemeral { " (ephemeral)" } else { "" }
+        )?;
+        if let Some(p) = self.tx_power {
+            write!(f, ", tx_power: {}dBm", p)?;
+        }
+        if let Some(b) = self.battery {
+            write!(f, ", battery: {}%", b)?;
+        }
+        if let Some(fl) = self.flags {
+            write!(f, ", flags: 0x{:02X}", fl)?;
+        }
+        if let Some(n) = &self.nickname {
+            write!(f, ", nickname: {:?}", n.as_str())?;
+        }
+        write!(f, " }}")
     }
 }
 
@@ -30,21 +146,176 @@ impl fmt::Debug for Parsed {
 /// buf に書き込み、書き込んだサイズを返す
 /// 構造: [version(1), device_type(1), bd_addr(6)]
 pub fn build_adv_payload(buf: &mut [u8], bd_addr: &[u8; 6]) -> usize {
+    build_payload(buf, VERSION_BD_ADDR, bd_addr)
+}
+
+/// 一時ID（v2）のペイロードを構築
+/// 構造: [version(1), device_type(1), ephemeral_id(6)]
+pub fn build_adv_payload_v2(buf: &mut [u8], ephemeral_id: &[u8; 6]) -> usize {
+    build_payload(buf, VERSION_EPHEMERAL, ephemeral_id)
+}
+
+fn build_payload(buf: &mut [u8], version: u8, id: &[u8; 6]) -> usize {
     if buf.len() < 8 { 
         return 0; 
     }
     
-    buf[0] = 0x01; // Version
-    buf[1] = 0x50; // DeviceType: PicoStreet ('P' = 0x50)
-    buf[2..8].copy_from_slice(bd_addr); // BD_ADDR (6 bytes)
+    buf[0] = version;
+    buf[1] = DEVICE_TYPE_PICOSTREET;
+    buf[2..8].copy_from_slice(id); // ID (6 bytes)
     
     8 // 8バイト固定
 }
 
+/// v3（TLV）ペイロードの組み立て。入らないフィールドは書かずに false を返す。
+pub struct TlvBuilder<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> TlvBuilder<'a> {
+    /// 通常広告に収まる長さ（`MAX_LEGACY_PAYLOAD`）で組み立てる。`buf` が2バイト未満なら None。
+    pub fn new(buf: &'a mut [u8]) -> Option<Self> {
+        Self::with_limit(buf, MAX_LEGACY_PAYLOAD)
+    }
+
+    /// ペイロード長の上限を指定して組み立てる（拡張広告用）。
+    pub fn with_limit(buf: &'a mut [u8], limit: usize) -> Option<Self> {
+        let limit = limit.min(buf.len());
+        if limit < 2 {
+            return None;
+        }
+        let buf = &mut buf[..limit];
+        buf[0] = VERSION_TLV;
+        buf[1] = DEVICE_TYPE_PICOSTREET;
+        Some(Self { buf, len: 2 })
+    }
+
+    /// ID（必須）
+    pub fn id(&mut self, id: &[u8; 6], ephemeral: bool) -> bool {
+        self.push(if ephemeral { TLV_ID_EPHEMERAL } else { TLV_ID_BD_ADDR }, id)
+    }
+
+    pub fn tx_power(&mut self, dbm: i8) -> bool {
+        self.push(TLV_TX_POWER, &[dbm as u8])
+    }
+
+    pub fn battery(&mut self, percent: u8) -> bool {
+        self.push(TLV_BATTERY, &[percent.min(100)])
+    }
+
+    pub fn flags(&mut self, flags: u8) -> bool {
+        self.push(TLV_FLAGS, &[flags])
+    }
+
+    /// ニックネーム。残りに入る長さ（最大 `MAX_NICKNAME_LEN`）まで文字単位で切り詰める。
+    /// 1文字も入らなければ false。
+    pub fn nickname(&mut self, name: &str) -> bool {
+        let room = self.remaining().saturating_sub(2).min(MAX_NICKNAME_LEN);
+        let name = truncate_utf8(name, room);
+        !name.is_empty() && self.push(TLV_NICKNAME, name.as_bytes())
+    }
+
+    /// 残りのバイト数
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.len
+    }
+
+    /// 書き込んだ長さを返す
+    pub fn finish(self) -> usize {
+        self.len
+    }
+
+    fn push(&mut self, ty: u8, value: &[u8]) -> bool {
+        if value.len() > u8::MAX as usize || 2 + value.len() > self.remaining() {
+            return false;
+        }
+        let i = self.len;
+        self.buf[i] = ty;
+        self.buf[i + 1] = value.len() as u8;
+        self.buf[i + 2..i + 2 + value.len()].copy_from_slice(value);
+        self.len += 2 + value.len();
+        true
+    }
+}
+
+/// `max` バイト以内に収まるよう文字単位で切り詰める
+fn truncate_utf8(s: &str, max: usize) -> &str {
+    let mut end = 0;
+    for (i, c) in s.char_indices() {
+        if i + c.len_utf8() > max {
+            break;
+        }
+        end = i + c.len_utf8();
+    }
+    &s[..end]
+}
+
+/// Unix秒からエポック番号（`ROTATION_SECS` 単位）を求める
+pub fn epoch_for(unix: u64) -> u32 {
+    (unix / ROTATION_SECS) as u32
+}
+
+/// 時刻未同期のときのエポック番号。起動ごとの乱数 `nonce` から始めて稼働時間で進める
+/// （起動のたびに同じIDを繰り返さないため。同期済みのエポックとは最上位ビットで区別する）。
+pub fn unsynced_epoch(nonce: u32, uptime_secs: u64) -> u32 {
+    UNSYNCED_EPOCH_FLAG | (nonce.wrapping_add((uptime_secs / ROTATION_SECS) as u32) & !UNSYNCED_EPOCH_FLAG)
+}
+
+/// 一時IDを導出する: HMAC-SHA256(secret, "PicoStreet-EID" || bd_addr || epoch(LE 4B)) の先頭6バイト。
+/// 全端末で同じ鍵（1つのイメージ）でも BD_ADDR で端末ごとに変わる。
+/// サーバは登録済みの端末ごとに検出時刻前後のエポックで同じ計算をして持ち主を特定する。
+pub fn ephemeral_id(secret: &[u8], bd_addr: &[u8; 6], epoch: u32) -> [u8; 6] {
+    // HMAC は任意長の鍵を受け付けるため失敗しない
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC key");
+    mac.update(EPHEMERAL_ID_LABEL);
+    mac.update(bd_addr);
+    mac.update(&epoch.to_le_bytes());
+    let tag = mac.finalize().into_bytes();
+    let mut id = [0u8; 6];
+    id.copy_from_slice(&tag[..6]);
+    id
+}
+
+/// 解析エラー（後ろの分類ほど PicoStreet の広告に近い）
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ParseError {
+    /// Service Data（0x16）が無い
+    NoServiceData,
+    /// Service Data の UUID が違う（PicoStreet 以外）
+    WrongUuid,
+    /// AD 構造の長さが不正（途中で切れている）
+    MalformedAd,
+    /// UUID は一致したが DeviceType が違う
+    WrongDeviceType,
+    /// 未対応のバージョン（新しいファームの端末など）
+    UnsupportedVersion(u8),
+    /// ペイロード長が不正（固定長違い・TLV が途中で切れている・ID が無い）
+    BadLength,
+}
+
+impl ParseError {
+    /// 診断表示用の短い名前
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::NoServiceData => "no_service_data",
+            Self::WrongUuid => "wrong_uuid",
+            Self::MalformedAd => "malformed_ad",
+            Self::WrongDeviceType => "wrong_device_type",
+            Self::UnsupportedVersion(_) => "unsupported_version",
+            Self::BadLength => "bad_length",
+        }
+    }
+}
+
 /// AD全体（[len][type][data]...）を走査して Service Data 0x16 のうち
 /// UUID=SERVICE_UUID_16 のペイロードをパース。
-/// 見つかったら Parsed を返す。
-pub fn parse_service_data(ad: &[u8]) -> Option<Parsed> {
+/// 見つかったら Parsed を返す。見つからなければ最も PicoStreet に近かったエラーを返す。
+/// TX Power Level AD があれば `tx_power` に入れる（TLV の値を優先）。
+pub fn parse_service_data(ad: &[u8]) -> Result<Parsed, ParseError> {
+    let mut err = ParseError::NoServiceData;
+    let mut found: Option<Parsed> = None;
+    let mut tx_power_ad = None;
     let mut i = 0usize;
     while i < ad.len() {
         let len = ad[i] as usize; 
@@ -53,6 +324,7 @@ pub fn parse_service_data(ad: &[u8]) -> Option<Parsed> {
             continue; 
         }
         if i + len > ad.len() { 
+            err = err.max(ParseError::MalformedAd);
             break; 
         }
         
@@ -60,43 +332,164 @@ pub fn parse_service_data(ad: &[u8]) -> Option<Parsed> {
         let data = &ad[i+1 .. i+len];
         i += len;
 
-        if ty != 0x16 { 
+        if ty == AD_TYPE_TX_POWER && data.len() == 1 {
+            tx_power_ad = Some(data[0] as i8);
+            continue;
+        }
+        if ty != 0x16 || found.is_some() { 
             continue; // Service Data - 16-bit UUID
         }
         if data.len() < 2 { 
+            err = err.max(ParseError::MalformedAd);
             continue; 
         }
         
         let uuid = u16::from_le_bytes([data[0], data[1]]);
         if uuid != SERVICE_UUID_16 { 
+            err = err.max(ParseError::WrongUuid);
             continue; 
         }
         
         let payload = &data[2..];
-        
-        // PicoStreetペイロード検証: 8バイト固定
-        if payload.len() != 8 { 
-            continue; 
+        let parsed = match payload {
+            [] | [_] => Err(ParseError::BadLength),
+            [_, device_type, ..] if *device_type != DEVICE_TYPE_PICOSTREET => Err(ParseError::WrongDeviceType),
+            [VERSION_BD_ADDR | VERSION_EPHEMERAL, ..] => parse_fixed(payload),
+            [VERSION_TLV, ..] => parse_tlv(payload),
+            [version, ..] => Err(ParseError::UnsupportedVersion(*version)),
+        };
+        match parsed {
+            Ok(p) => found = Some(p),
+            Err(e) => err = err.max(e),
         }
-        
-        let version = payload[0];
-        let device_type = payload[1];
-        
-        // Version=0x01, DeviceType=0x50 を確認
-        if version != 0x01 || device_type != 0x50 { 
-            continue; 
+    }
+    match found {
+        Some(mut p) => {
+            p.tx_power = p.tx_power.or(tx_power_ad);
+            Ok(p)
+        }
+        None => Err(err),
+    }
+}
+
+/// v1/v2（8バイト固定）
+fn parse_fixed(payload: &[u8]) -> Result<Parsed, ParseError> {
+    if payload.len() != 8 { 
+        return Err(ParseError::BadLength); 
+    }
+    let mut id = [0u8; 6];
+    id.copy_from_slice(&payload[2..8]);
+    Ok(Parsed {
+        version: payload[0],
+        device_type: payload[1],
+        id,
+        ephemeral: payload[0] == VERSION_EPHEMERAL,
+        tx_power: None,
+        battery: None,
+        flags: None,
+        nickname: None,
+    })
+}
+
+/// v3（TLV）。ID が無い・TLV が途中で切れている場合は BadLength。未知の Type は読み飛ばす。
+fn parse_tlv(payload: &[u8]) -> Result<Parsed, ParseError> {
+    let mut id = None;
+    let mut parsed = Parsed {
+        version: payload[0],
+        device_type: payload[1],
+        id: [0; 6],
+        ephemeral: false,
+        tx_power: None,
+        battery: None,
+        flags: None,
+        nickname: None,
+    };
+    let mut i = 2;
+    while i < payload.len() {
+        if i + 2 > payload.len() {
+            return Err(ParseError::BadLength);
+        }
+        let (ty, len) = (payload[i], payload[i + 1] as usize);
+        let value = payload.get(i + 2..i + 2 + len).ok_or(ParseError::BadLength)?;
+        i += 2 + len;
+        match (ty, len) {
+            (TLV_ID_BD_ADDR | TLV_ID_EPHEMERAL, 6) => {
+                let mut v = [0u8; 6];
+                v.copy_from_slice(value);
+                id = Some((v, ty == TLV_ID_EPHEMERAL));
+            }
+            (TLV_TX_POWER, 1) => parsed.tx_power = Some(value[0] as i8),
+            (TLV_BATTERY, 1) => parsed.battery = Some(value[0]),
+            (TLV_FLAGS, 1) => parsed.flags = Some(value[0]),
+            (TLV_NICKNAME, _) => parsed.nickname = Nickname::new(value),
+            _ => {} // 未知の Type・長さ違いは読み飛ばす
         }
-        
-        let mut bd_addr = [0u8; 6];
-        bd_addr.copy_from_slice(&payload[2..8]);
-        
-        return Some(Parsed {
-            version,
-            device_type,
-            bd_addr,
-        });
     }
-    None
+    let (v, ephemeral) = id.ok_or(ParseError::BadLength)?;
+    parsed.id = v;
+    parsed.ephemeral = ephemeral;
+    Ok(parsed)
+}
+
+/// スキャン応答の解析結果
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScanResponse {
+    /// 短縮名（"PicoStreet-XXXX"）
+    pub short_name: Nickname,
+    /// 相手が設定したニックネーム（無ければ None）
+    pub nickname: Option<Nickname>,
+}
+
+/// スキャン応答の AD を構築し、書き込んだ長さを返す。
+/// 短縮名は `id`（広告している ID）の [4][5] から作る（一時IDなら名前も一緒に変わる）。
+/// ニックネームは残り（12バイト）に入るまで文字単位で切り詰め、空なら載せない。
+pub fn build_scan_response(buf: &mut [u8; MAX_SCAN_RESPONSE], id: &[u8; 6], nickname: &str) -> usize {
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+    let prefix = SHORT_NAME_PREFIX.as_bytes();
+    let name_len = prefix.len() + 4;
+    buf[0] = (1 + name_len) as u8;
+    buf[1] = AD_TYPE_SHORT_NAME;
+    buf[2..2 + prefix.len()].copy_from_slice(prefix);
+    let hex = &mut buf[2 + prefix.len()..2 + name_len];
+    for (i, b) in [id[4], id[5]].into_iter().enumerate() {
+        hex[i * 2] = HEX[(b >> 4) as usize];
+        hex[i * 2 + 1] = HEX[(b & 0x0F) as usize];
+    }
+    let mut used = 2 + name_len;
+    let nickname = truncate_utf8(nickname, (MAX_SCAN_RESPONSE - used - 2).min(MAX_NICKNAME_LEN));
+    if !nickname.is_empty() {
+        buf[used] = (1 + nickname.len()) as u8;
+        buf[used + 1] = AD_TYPE_COMPLETE_NAME;
+        buf[used + 2..used + 2 + nickname.len()].copy_from_slice(nickname.as_bytes());
+        used += 2 + nickname.len();
+    }
+    used
+}
+
+/// スキャン応答を解析する。短縮名が "PicoStreet-" で始まらなければ None（他の機器の応答）。
+pub fn parse_scan_response(ad: &[u8]) -> Option<ScanResponse> {
+    let mut short_name = None;
+    let mut nickname = None;
+    let mut i = 0usize;
+    while i < ad.len() {
+        let len = ad[i] as usize;
+        i += 1;
+        if len == 0 {
+            continue;
+        }
+        if i + len > ad.len() {
+            return None;
+        }
+        let ty = ad[i];
+        let data = &ad[i + 1..i + len];
+        i += len;
+        match ty {
+            AD_TYPE_SHORT_NAME if data.starts_with(SHORT_NAME_PREFIX.as_bytes()) => short_name = Nickname::new(data),
+            AD_TYPE_COMPLETE_NAME if !data.is_empty() => nickname = Nickname::new(data),
+            _ => {}
+        }
+    }
+    Some(ScanResponse { short_name: short_name?, nickname })
 }
 
 #[cfg(test)]
@@ -107,8 +500,8 @@ mod tests {
     // テスト用BD_ADDR
     const TEST_BD_ADDR: [u8; 6] = [0x28, 0xCD, 0xC1, 0x15, 0x26, 0x11];
 
-    fn build_service_data_ad(payload: &[u8]) -> heapless::Vec<u8, 64> {
-        let mut ad: heapless::Vec<u8, 64> = heapless::Vec::new();
+    fn build_service_data_ad(payload: &[u8]) -> heapless::Vec<u8, 96> {
+        let mut ad: heapless::Vec<u8, 96> = heapless::Vec::new();
         // Flags 0x01
         ad.extend_from_slice(&[0x02, 0x01, 0x06]).unwrap();
         // Service Data 0x16: len = 1(type) + 2(uuid) + payload.len()
@@ -133,7 +526,111 @@ mod tests {
         let parsed = parse_service_data(&ad).expect("must parse");
         assert_eq!(parsed.version, 0x01);
         assert_eq!(parsed.device_type, 0x50);
-        assert_eq!(parsed.bd_addr, TEST_BD_ADDR);
+        assert_eq!(parsed.id, TEST_BD_ADDR);
+        assert!(!parsed.ephemeral);
+    }
+
+    #[test]
+    fn build_and_parse_ephemeral() {
+        let secret = b"device-secret-0123456789abcdef!!";
+        let epoch = epoch_for(1_700_000_000);
+        let eid = ephemeral_id(secret, &[0x28, 0xCD, 0xC1, 0x15, 0x26, 0x01], epoch);
+        let mut buf = [0u8; 8];
+        let n = build_adv_payload_v2(&mut buf, &eid);
+        let parsed = parse_service_data(&build_service_data_ad(&buf[..n])).expect("must parse");
+        assert_eq!(parsed.version, VERSION_EPHEMERAL);
+        assert_eq!(parsed.id, eid);
+        assert!(parsed.ephemeral);
+    }
+
+    #[test]
+    fn tlv_builds_within_legacy_budget_and_parses() {
+        let mut buf = [0u8; 64];
+        let mut b = TlvBuilder::new(&mut buf).unwrap();
+        assert!(b.id(&TEST_BD_ADDR, false));
+        assert!(b.tx_power(-8));
+        assert!(b.battery(87));
+        // 21 - 2 - 8 - 3 - 3 = 5 バイト残り → ニックネームは3バイトまで（マルチバイト文字の途中では切らない）
+        assert!(b.nickname("たなか"));
+        assert!(!b.flags(0x01));
+        let n = b.finish();
+        assert_eq!(n, MAX_LEGACY_PAYLOAD);
+
+        let parsed = parse_service_data(&build_service_data_ad(&buf[..n])).expect("must parse");
+        assert_eq!(parsed.version, VERSION_TLV);
+        assert_eq!(parsed.id, TEST_BD_ADDR);
+        assert!(!parsed.ephemeral);
+        assert_eq!(parsed.tx_power, Some(-8));
+        assert_eq!(parsed.battery, Some(87));
+        assert_eq!(parsed.flags, None);
+        assert_eq!(parsed.nickname.map(|n| n.as_str() == "た"), Some(true));
+    }
+
+    #[test]
+    fn tlv_extended_budget_fits_all_fields() {
+        let mut buf = [0u8; MAX_EXTENDED_PAYLOAD];
+        let mut b = TlvBuilder::with_limit(&mut buf, MAX_EXTENDED_PAYLOAD).unwrap();
+        assert!(b.id(&TEST_BD_ADDR, true));
+        assert!(b.tx_power(-20));
+        assert!(b.battery(50));
+        assert!(b.flags(0x01));
+        // 拡張広告ではニックネームを最大長（16バイト）まで載せられる
+        assert!(b.nickname("すれちがい太郎です"));
+        let n = b.finish();
+        assert!(n <= MAX_EXTENDED_PAYLOAD);
+
+        let parsed = parse_service_data(&build_service_data_ad(&buf[..n])).expect("must parse");
+        assert!(parsed.ephemeral);
+        assert_eq!(parsed.tx_power, Some(-20));
+        assert_eq!(parsed.flags, Some(0x01));
+        assert_eq!(parsed.nickname.unwrap().as_str(), "すれちがい");
+    }
+
+    #[test]
+    fn parse_picks_up_tx_power_ad() {
+        let mut buf = [0u8; 8];
+        let n = build_adv_payload(&mut buf, &TEST_BD_ADDR);
+        let mut ad = build_service_data_ad(&buf[..n]);
+        // Service Data の後ろにある TX Power Level も拾う
+        ad.extend_from_slice(&[0x02, AD_TYPE_TX_POWER, (-4i8) as u8]).unwrap();
+        assert_eq!(parse_service_data(&ad).map(|p| p.tx_power), Ok(Some(-4)));
+    }
+
+    #[test]
+    fn tlv_skips_unknown_and_rejects_truncated() {
+        // [ver][type] [0x7F len2 ..] [ID]
+        let mut payload: heapless::Vec<u8, 32> = heapless::Vec::new();
+        payload.extend_from_slice(&[VERSION_TLV, 0x50, 0x7F, 0x02, 0xAA, 0xBB, TLV_ID_EPHEMERAL, 0x06]).unwrap();
+        payload.extend_from_slice(&TEST_BD_ADDR).unwrap();
+        let parsed = parse_service_data(&build_service_data_ad(&payload)).expect("must parse");
+        assert!(parsed.ephemeral);
+        assert_eq!(parsed.id, TEST_BD_ADDR);
+
+        // 長さが残りを超える TLV
+        payload.extend_from_slice(&[TLV_BATTERY, 0x05, 0x10]).unwrap();
+        assert_eq!(parse_service_data(&build_service_data_ad(&payload)), Err(ParseError::BadLength));
+
+        // ID なし
+        let no_id = [VERSION_TLV, 0x50, TLV_BATTERY, 0x01, 50];
+        assert_eq!(parse_service_data(&build_service_data_ad(&no_id)), Err(ParseError::BadLength));
+    }
+
+    #[test]
+    fn ephemeral_id_rotates_per_epoch_and_device() {
+        let key = b"fleet-secret";
+        let a = [0x28, 0xCD, 0xC1, 0x15, 0x26, 0x01];
+        let b = [0x28, 0xCD, 0xC1, 0x15, 0x26, 0x02];
+        // 同じ端末・同じエポックなら同じID（サーバで再計算できる）
+        assert_eq!(ephemeral_id(key, &a, 100), ephemeral_id(key, &a, 100));
+        assert_ne!(ephemeral_id(key, &a, 100), ephemeral_id(key, &a, 101));
+        // 同じ鍵を書き込んだ端末どうしでも ID は重ならない
+        assert_ne!(ephemeral_id(key, &a, 100), ephemeral_id(key, &b, 100));
+        assert_ne!(ephemeral_id(key, &a, 100), ephemeral_id(b"other-secret", &a, 100));
+        assert_eq!(epoch_for(ROTATION_SECS * 5 + ROTATION_SECS - 1), 5);
+        // 未同期のエポックは同期済みのものと重ならない
+        let e = unsynced_epoch(0xFFFF_FFFF, ROTATION_SECS * 2);
+        assert_ne!(e & UNSYNCED_EPOCH_FLAG, 0);
+        assert_eq!(e, UNSYNCED_EPOCH_FLAG | 1);
     }
 
     #[test]
@@ -145,7 +642,7 @@ mod tests {
         // Service Data 0x16, UUID=0xBEEF
         ad.extend_from_slice(&[2 + 1 + n as u8, 0x16, 0xEF, 0xBE]).unwrap();
         ad.extend_from_slice(&payload[..n]).unwrap();
-        assert!(parse_service_data(&ad).is_none());
+        assert_eq!(parse_service_data(&ad), Err(ParseError::WrongUuid));
     }
 
     #[test]
@@ -155,19 +652,49 @@ mod tests {
         let payload = [0x01, 0x99, 0x28, 0xCD, 0xC1, 0x15, 0x26, 0x11]; // DeviceType=0x99
         ad.extend_from_slice(&[2 + 1 + payload.len() as u8, 0x16, (SERVICE_UUID_16 & 0xFF) as u8, (SERVICE_UUID_16 >> 8) as u8]).unwrap();
         ad.extend_from_slice(&payload).unwrap();
-        assert!(parse_service_data(&ad).is_none());
+        assert_eq!(parse_service_data(&ad), Err(ParseError::WrongDeviceType));
+    }
+
+    #[test]
+    fn parse_reports_unsupported_version() {
+        let payload = [0x7E, 0x50, 0x28, 0xCD, 0xC1, 0x15, 0x26, 0x11];
+        assert_eq!(parse_service_data(&build_service_data_ad(&payload)), Err(ParseError::UnsupportedVersion(0x7E)));
+        // Flags だけの広告（PicoStreet 以外）
+        assert_eq!(parse_service_data(&[0x02, 0x01, 0x06]), Err(ParseError::NoServiceData));
     }
 
     #[test]
     fn parse_bounds_checks() {
         // Broken AD
-        assert!(parse_service_data(&[0x02, 0x16]).is_none());
+        assert_eq!(parse_service_data(&[0x02, 0x16]), Err(ParseError::MalformedAd));
         
         // Correct header but wrong payload size
         let payload = [0x01, 0x50, 0x28, 0xCD, 0xC1]; // Only 5 bytes instead of 8
         let mut ad = heapless::Vec::<u8,64>::new();
         ad.extend_from_slice(&[2 + 1 + payload.len() as u8, 0x16, (SERVICE_UUID_16 & 0xFF) as u8, (SERVICE_UUID_16 >> 8) as u8]).unwrap();
         ad.extend_from_slice(&payload).unwrap();
-        assert!(parse_service_data(&ad).is_none());
+        assert_eq!(parse_service_data(&ad), Err(ParseError::BadLength));
+    }
+
+    #[test]
+    fn scan_response_builds_and_parses() {
+        let mut buf = [0u8; MAX_SCAN_RESPONSE];
+        let n = build_scan_response(&mut buf, &TEST_BD_ADDR, "");
+        assert_eq!(&buf[2..n], b"PicoStreet-2611");
+        let r = parse_scan_response(&buf[..n]).unwrap();
+        assert_eq!(r.short_name.as_str(), "PicoStreet-2611");
+        assert_eq!(r.nickname, None);
+
+        // 入りきらないニックネームは文字単位で切り詰める（12バイト）
+        let n = build_scan_response(&mut buf, &TEST_BD_ADDR, "すれちがい太郎");
+        assert_eq!(n, MAX_SCAN_RESPONSE);
+        let r = parse_scan_response(&buf[..n]).unwrap();
+        assert_eq!(r.nickname.unwrap().as_str(), "すれちが");
+
+        // 他の機器の名前は無視する
+        let mut other = [0x07, AD_TYPE_SHORT_NAME, b'P', b'h', b'o', b'n', b'e', b'1'].to_vec();
+        assert_eq!(parse_scan_response(&other), None);
+        other.truncate(5);
+        assert_eq!(parse_scan_response(&other), None);
     }
 }
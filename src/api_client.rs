@@ -1,84 +1,137 @@
-//! 簡易APIクライアント（no_std, 手書きJSON, HTTP/1.1）
+//! 簡易APIクライアント（no_std, 手書きJSON をストリーム送信, HTTP/1.1 / HTTPS）
 use defmt::*;
 use heapless::String;
 
 use embassy_net::{Stack, dns::DnsQueryType, IpAddress, IpEndpoint};
 use embassy_net::tcp::TcpSocket;
-use embedded_io_async::Write;
+use embedded_io_async::{Read, Write};
+use embassy_rp::clocks::RoscRng;
 use embassy_time::{with_timeout, Duration};
-
-use crate::storage::EncounterLog;
+use embedded_tls::pki::CertVerifier;
+use embedded_tls::{
+    Aes128GcmSha256, Certificate, CryptoProvider, TlsClock, TlsConfig, TlsConnection, TlsContext, TlsError,
+    TlsVerifier,
+};
+use rand_core::CryptoRngCore;
+
+use crate::config;
 use crate::settings;
-use pico_w_id_beacon::format::fmt_bytes_colon;
+use pico_w_id_beacon::api_json::{append_u64, for_each_json_fragment, json_body_len, JsonFragments};
+pub use pico_w_id_beacon::api_json::ApiPayload;
+use pico_w_id_beacon::config_record::ApiTransport;
+use pico_w_id_beacon::http::{self, UploadReply};
+use pico_w_id_beacon::request_sig::{RequestSigner, SIGNATURE_HEADER, TIMESTAMP_HEADER};
 
 // 送信先設定は settings から取得
 
-/// ペイロード（送信直前に組み立て）
-pub struct ApiPayload<'a> {
-    pub device_id: [u8; 6],
-    pub encounters: &'a [EncounterLog],
-    pub reported_at: u64,
+/// 送信エラー
+#[derive(Clone, Copy, defmt::Format)]
+pub enum ApiError {
+    /// DNS/TCP/TLS/書き込みなど通信層の失敗
+    Transport(&'static str),
+    /// サーバが 2xx 以外を返した（Retry-After 秒数があれば保持）
+    Status { code: u16, retry_after: Option<u32> },
 }
 
-/// ペイロードをJSONへ（簡易フォーマット、heapless）
-pub fn serialize_to_json(payload: &ApiPayload<'_>) -> String<2048> {
-    let mut s: String<2048> = String::new();
-    let _ = s.push_str("{");
-
-    // device_id
-    let id = fmt_bytes_colon(&payload.device_id);
-    let _ = s.push_str("\"device_id\":\"");
-    let _ = s.push_str(id.as_str());
-    let _ = s.push_str("\",");
-
-    // reported_at
-    let _ = s.push_str("\"reported_at\":");
-    append_u64(&mut s, payload.reported_at);
-    let _ = s.push_str(",");
-
-    // encounters
-    let _ = s.push_str("\"encounters\":[");
-    for (i, e) in payload.encounters.iter().enumerate() {
-        if i > 0 { let _ = s.push_str(","); }
-        let mac = fmt_bytes_colon(&e.mac_addr);
-        let _ = s.push_str("{");
-        let _ = s.push_str("\"mac_addr\":\"");
-        let _ = s.push_str(mac.as_str());
-        let _ = s.push_str("\",");
-        let _ = s.push_str("\"timestamp\":");
-        append_u64(&mut s, e.timestamp);
-        let _ = s.push_str("}");
+impl ApiError {
+    /// 再試行で回復が見込めるか（通信層の失敗、429、5xx）
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ApiError::Transport(_) => true,
+            ApiError::Status { code, .. } => *code == 429 || *code >= 500,
+        }
     }
-    let _ = s.push_str("]}");
 
-    s
+    /// サーバ指定の再試行待ち（秒）
+    pub fn retry_after(&self) -> Option<u32> {
+        match self {
+            ApiError::Status { retry_after, .. } => *retry_after,
+            ApiError::Transport(_) => None,
+        }
+    }
 }
 
-fn append_u64<const N: usize>(s: &mut String<N>, mut v: u64) {
-    // 10進数を逆から詰めて反転
-    let mut buf = [0u8; 20];
-    let mut i = 0;
-    if v == 0 { let _ = s.push('0'); return; }
-    while v > 0 {
-        buf[i] = b'0' + (v % 10) as u8;
-        v /= 10;
-        i += 1;
+impl core::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ApiError::Transport(e) => f.write_str(e),
+            ApiError::Status { code, retry_after: Some(s) } => write!(f, "HTTP {} (retry-after {}s)", code, s),
+            ApiError::Status { code, retry_after: None } => write!(f, "HTTP {}", code),
+        }
     }
-    while i > 0 { i -= 1; let _ = s.push(buf[i] as char); }
 }
 
-// rssi は API に含めないポリシーに変更（2025-09）
+impl From<&'static str> for ApiError {
+    fn from(e: &'static str) -> Self {
+        ApiError::Transport(e)
+    }
+}
+
+/// 送信成功時のサーバ応答から取り出した情報
+#[derive(Clone, Copy, Default)]
+pub struct ApiResponse {
+    /// サーバが指示した次回送信時刻（Unix秒）
+    pub next_upload_at: Option<u64>,
+}
+
+/// JSON ボディを断片ごとに `w` へ書き込む（全体をRAMに持たない）。
+pub async fn write_json_body<W: Write>(w: &mut W, payload: &ApiPayload<'_>) -> Result<(), &'static str> {
+    for frag in JsonFragments::new(payload) {
+        w.write_all(frag?.as_bytes()).await.map_err(|_| "write body")?;
+    }
+    Ok(())
+}
+
+// 生の rssi（1回ごとの値）は API に含めない。相手ごとの集計値（min/max/mean）のみ送る。
+
+/// TLS レコード受信バッファ（最大レコード 16KB + ヘッダ/タグ）
+const TLS_READ_BUF: usize = 16_640;
+/// TLS レコード送信バッファ
+const TLS_WRITE_BUF: usize = 4096;
+/// サーバ証明書チェーン検証用の作業領域
+const TLS_CERT_BUF: usize = 4096;
+
+/// 証明書の有効期限チェックに使う時計（NTP 未同期なら期限チェックを省略）
+struct TimekeeperClock;
+
+impl TlsClock for TimekeeperClock {
+    fn now() -> Option<u64> {
+        crate::timekeeper::now_unix()
+    }
+}
+
+/// 設定で選んだ CA 証明書でサーバ証明書を検証する暗号プロバイダ
+struct PinnedCaProvider {
+    rng: RoscRng,
+    verifier: CertVerifier<Aes128GcmSha256, TimekeeperClock, TLS_CERT_BUF>,
+}
+
+impl CryptoProvider for PinnedCaProvider {
+    type CipherSuite = Aes128GcmSha256;
+    type Signature = &'static [u8];
 
-/// APIへ送信（HTTP/1.1）。成功時は Ok(())
-pub async fn send_encounters_to_server(stack: Stack<'static>, payload: &ApiPayload<'_>) -> Result<(), &'static str> {
+    fn rng(&mut self) -> impl CryptoRngCore {
+        &mut self.rng
+    }
+
+    fn verifier(&mut self) -> Result<&mut impl TlsVerifier<Self::CipherSuite>, TlsError> {
+        Ok(&mut self.verifier)
+    }
+}
+
+/// APIへ送信（HTTP/1.1、設定の api_tls=on なら HTTPS。接続先・CA は config）。成功時はサーバ応答の情報を返す
+pub async fn send_encounters_to_server(stack: Stack<'static>, payload: &ApiPayload<'_>) -> Result<ApiResponse, ApiError> {
     // DNS解決
-    let host = settings::API_HOST;
+    let cfg = config::get();
+    let host = cfg.api_host.as_str();
+    // 接続前に決める（HTTPS なのに CA が無ければ平文に落とさず失敗させる）
+    let transport = cfg.api_transport(settings::API_TLS_CAS)?;
     let addrs = with_timeout(Duration::from_secs(3), stack.dns_query(host, DnsQueryType::A))
         .await
         .map_err(|_| "DNS timeout")
         .and_then(|r| r.map_err(|_| "DNS error"))?;
-    let server_ip = match addrs.first() { Some(IpAddress::Ipv4(v4)) => *v4, _ => return Err("no ipv4") };
-    let ep = IpEndpoint::new(IpAddress::Ipv4(server_ip), settings::API_PORT);
+    let server_ip = match addrs.first() { Some(IpAddress::Ipv4(v4)) => *v4, _ => return Err(ApiError::Transport("no ipv4")) };
+    let ep = IpEndpoint::new(IpAddress::Ipv4(server_ip), cfg.api_port);
 
     // TCP
     let mut rx_buf = [0u8; 1024];
@@ -87,72 +140,112 @@ pub async fn send_encounters_to_server(stack: Stack<'static>, payload: &ApiPaylo
     info!("API: 接続 {:?}", defmt::Debug2Format(&ep));
     match with_timeout(Duration::from_secs(3), sock.connect(ep)).await {
         Ok(Ok(())) => {}
-        Ok(Err(_)) => return Err("connect fail"),
-        Err(_) => return Err("connect timeout"),
+        Ok(Err(_)) => return Err(ApiError::Transport("connect fail")),
+        Err(_) => return Err(ApiError::Transport("connect timeout")),
     }
 
-    // HTTPリクエスト
-    let body = serialize_to_json(payload);
+    let (ca, server_name) = match transport {
+        ApiTransport::Plain => return post_json(&mut sock, "http", payload).await,
+        ApiTransport::Tls { ca, server_name } => (ca, server_name),
+    };
+
+    // TLS ハンドシェイク（選択した CA 証明書で検証。SNI/ホスト名検証に API ホスト名を使用）
+    let mut tls_rx = [0u8; TLS_READ_BUF];
+    let mut tls_tx = [0u8; TLS_WRITE_BUF];
+    let config = TlsConfig::new()
+        .with_server_name(server_name)
+        .with_ca(Certificate::X509(ca));
+    let provider = PinnedCaProvider { rng: RoscRng, verifier: CertVerifier::new() };
+    let mut tls = TlsConnection::new(sock, &mut tls_rx, &mut tls_tx);
+    match with_timeout(Duration::from_secs(10), tls.open(TlsContext::new(&config, provider))).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            warn!("API: TLSハンドシェイク失敗: {}", defmt::Debug2Format(&e));
+            return Err(ApiError::Transport("tls handshake"));
+        }
+        Err(_) => return Err(ApiError::Transport("tls timeout")),
+    }
+    let res = post_json(&mut tls, "https", payload).await;
+    let _ = tls.close().await;
+    res
+}
+
+/// 確立済みの接続（平文 TCP / TLS）で HTTP POST を行い、ステータスを判定する。
+async fn post_json<C: Read + Write>(conn: &mut C, scheme: &str, payload: &ApiPayload<'_>) -> Result<ApiResponse, ApiError> {
+    let cfg = config::get();
+    let host = cfg.api_host.as_str();
+    let path = cfg.api_path.as_str();
+
+    // HTTPリクエスト（ボディは断片ごとに送信。長さは事前に算出）
+    let body_len = json_body_len(payload)?;
     info!(
-        "API: POST http://{}:{}{} (encounters={}, body={}B)",
+        "API: POST {}://{}:{}{} (encounters={}, body={}B)",
+        scheme,
         host,
-        settings::API_PORT,
-        settings::API_PATH,
+        cfg.api_port,
+        path,
         payload.encounters.len() as u32,
-        body.len() as u32
+        body_len as u32
     );
-    let mut req: String<256> = String::new();
+    let mut req: String<512> = String::new();
     let _ = req.push_str("POST ");
-    let _ = req.push_str(settings::API_PATH);
+    let _ = req.push_str(path);
     let _ = req.push_str(" HTTP/1.1\r\nHost: ");
     let _ = req.push_str(host);
     let _ = req.push_str("\r\nContent-Type: application/json\r\nConnection: close\r\nContent-Length: ");
-    append_u64(&mut req, body.len() as u64);
-    let _ = req.push_str("\r\n\r\n");
+    append_u64(&mut req, body_len as u64);
+    let _ = req.push_str("\r\n");
+    if !cfg.api_key.is_empty() {
+        // 署名ヘッダ（reported_at をタイムスタンプとしてリプレイを防ぐ）
+        if payload.reported_at == 0 {
+            return Err(ApiError::Transport("time not synced"));
+        }
+        let mut signer = RequestSigner::new(cfg.api_key.as_bytes(), "POST", path, payload.reported_at);
+        for_each_json_fragment(payload, |frag| signer.update(frag))?;
+        let _ = req.push_str(TIMESTAMP_HEADER);
+        let _ = req.push_str(": ");
+        append_u64(&mut req, payload.reported_at);
+        let _ = req.push_str("\r\n");
+        let _ = req.push_str(SIGNATURE_HEADER);
+        let _ = req.push_str(": ");
+        let _ = req.push_str(signer.finalize_hex().as_str());
+        let _ = req.push_str("\r\n");
+    }
+    let _ = req.push_str("\r\n");
 
-    match with_timeout(Duration::from_secs(3), sock.write_all(req.as_bytes())).await {
+    match with_timeout(Duration::from_secs(3), conn.write_all(req.as_bytes())).await {
+        Ok(Ok(())) => {}
+        _ => return Err(ApiError::Transport("write head")),
+    }
+    match with_timeout(Duration::from_secs(10), write_json_body(conn, payload)).await {
         Ok(Ok(())) => {}
-        _ => return Err("write head"),
+        Ok(Err(e)) => return Err(e.into()),
+        Err(_) => return Err(ApiError::Transport("write body")),
     }
-    match with_timeout(Duration::from_secs(3), sock.write_all(body.as_bytes())).await {
+    // TLS はここでレコードを送出する（平文 TCP では何もしない）
+    match with_timeout(Duration::from_secs(3), conn.flush()).await {
         Ok(Ok(())) => {}
-        _ => return Err("write body"),
+        _ => return Err(ApiError::Transport("write flush")),
     }
 
-    // レスポンス先頭を読み、HTTPステータスを表示
-    let mut tmp = [0u8; 256];
-    let n = match with_timeout(Duration::from_secs(2), sock.read(&mut tmp)).await {
+    // レスポンス先頭を読み、HTTPステータスを判定する。
+    // 応答が無い・解析できない場合はサーバが受理したか分からないため失敗扱い（記録を消さずに再送する）
+    let mut tmp = [0u8; 512];
+    let n = match with_timeout(Duration::from_secs(10), conn.read(&mut tmp)).await {
         Ok(Ok(n)) => n,
         Ok(Err(_)) => 0,
         Err(_) => 0,
     };
-    if let Some(code) = parse_http_status(&tmp[..n]) {
-        info!("API: レスポンス status={}", code as u32);
-        if (200..300).contains(&code) {
-            info!("API: 送信完了 ({}bytes)", body.len() as u32);
-            Ok(())
-        } else {
-            Err("http status")
+    match http::parse_upload_reply(&tmp[..n]) {
+        UploadReply::Accepted { next_upload_at } => {
+            info!("API: 送信完了 ({}bytes)", body_len as u32);
+            Ok(ApiResponse { next_upload_at })
         }
-    } else {
-        info!("API: レスポンス status=不明");
-        info!("API: 送信完了 ({}bytes)", body.len() as u32);
-        Ok(())
-    }
-}
-
-fn parse_http_status(buf: &[u8]) -> Option<u16> {
-    if buf.len() < 12 { return None; }
-    if !buf.starts_with(b"HTTP/1.") { return None; }
-    // Find first space
-    let mut i = 0;
-    while i < buf.len() && buf[i] != b' ' { i += 1; }
-    if i + 4 > buf.len() { return None; }
-    let d1 = buf[i+1]; let d2 = buf[i+2]; let d3 = buf[i+3];
-    if (d1 as char).is_ascii_digit() && (d2 as char).is_ascii_digit() && (d3 as char).is_ascii_digit() {
-        let code = ((d1 - b'0') as u16) * 100 + ((d2 - b'0') as u16) * 10 + ((d3 - b'0') as u16);
-        Some(code)
-    } else {
-        None
+        UploadReply::Rejected { code, retry_after } => {
+            info!("API: レスポンス status={}", code as u32);
+            Err(ApiError::Status { code, retry_after })
+        }
+        UploadReply::NoResponse => Err(ApiError::Transport("no response")),
+        UploadReply::Garbled => Err(ApiError::Transport("bad response")),
     }
 }
@@ -0,0 +1,279 @@
+//! API に送る JSON ボディ（手書き、断片ごとに生成して全体を RAM に持たない）
+//! - `{"device_id":"..","reported_at":N,"encounters":[{..},{..}]}`
+//! - 断片は先頭・1件ごと・末尾。Content-Length・署名・送信で同じ断片を順に生成する
+//! - 断片がバッファに収まらない場合は途中で切らずにエラーにする（不正な JSON を送らないため）
+
+use heapless::String;
+
+use crate::encounter_log::EncounterLog;
+use crate::format::fmt_bytes_colon;
+
+/// ペイロード（送信直前に組み立て）
+pub struct ApiPayload<'a> {
+    pub device_id: [u8; 6],
+    pub encounters: &'a [EncounterLog],
+    pub reported_at: u64,
+}
+
+/// JSON 断片（1件ぶんなど）の最大長。数値は最大桁で見積もって約360B + ニックネーム（エスケープ後最大96B）。
+pub const JSON_FRAG_MAX: usize = 480;
+
+/// JSON 断片
+pub type Fragment = String<JSON_FRAG_MAX>;
+
+/// JSON 末尾
+const JSON_TAIL: &str = "]}";
+
+/// 容量超過を記録する JSON 断片バッファ。
+/// 超過した断片は送らずにエラーにする（途中で切れた不正な JSON を送らないため）。
+struct Frag {
+    s: Fragment,
+    overflow: bool,
+}
+
+impl Frag {
+    fn new() -> Self {
+        Self { s: String::new(), overflow: false }
+    }
+
+    fn push_str(&mut self, v: &str) {
+        if self.s.push_str(v).is_err() {
+            self.overflow = true;
+        }
+    }
+
+    /// JSON 文字列として引用符付きで追加する（`"` `\` と制御文字をエスケープ）
+    fn push_json_str(&mut self, v: &str) {
+        self.push_str("\"");
+        for c in v.chars() {
+            match c {
+                '"' => self.push_str("\\\""),
+                '\\' => self.push_str("\\\\"),
+                c if (c as u32) < 0x20 => {
+                    const HEX: &[u8; 16] = b"0123456789abcdef";
+                    let b = c as u8;
+                    self.push_str("\\u00");
+                    let h = [HEX[(b >> 4) as usize], HEX[(b & 0x0F) as usize]];
+                    self.push_str(core::str::from_utf8(&h).unwrap_or("00"));
+                }
+                c => self.push_str(c.encode_utf8(&mut [0u8; 4])),
+            }
+        }
+        self.push_str("\"");
+    }
+
+    fn push_u64(&mut self, v: u64) {
+        let mut n: String<20> = String::new();
+        append_u64(&mut n, v);
+        self.push_str(n.as_str());
+    }
+
+    fn push_i32(&mut self, v: i32) {
+        if v < 0 { self.push_str("-"); }
+        self.push_u64(v.unsigned_abs() as u64);
+    }
+
+    fn finish(self) -> Result<Fragment, &'static str> {
+        if self.overflow { Err("json overflow") } else { Ok(self.s) }
+    }
+}
+
+/// JSON 先頭（device_id, reported_at, encounters 配列の開始まで）
+fn json_head(payload: &ApiPayload<'_>) -> Result<Fragment, &'static str> {
+    let mut f = Frag::new();
+    let id = fmt_bytes_colon(&payload.device_id);
+    f.push_str("{\"device_id\":\"");
+    f.push_str(id.as_str());
+    f.push_str("\",\"reported_at\":");
+    f.push_u64(payload.reported_at);
+    f.push_str(",\"encounters\":[");
+    f.finish()
+}
+
+/// encounters 配列の i 番目の要素（2件目以降は先頭にカンマを付ける）
+fn json_encounter(i: usize, e: &EncounterLog) -> Result<Fragment, &'static str> {
+    let mut f = Frag::new();
+    if i > 0 { f.push_str(","); }
+    let mac = fmt_bytes_colon(&e.mac_addr);
+    // seq はサーバ側で再送の重複排除に使う（同じ seq は同じ内容）
+    f.push_str("{\"seq\":");
+    f.push_u64(e.seq as u64);
+    f.push_str(",\"mac_addr\":\"");
+    f.push_str(mac.as_str());
+    // 一時IDの行は epoch ごとの集計（サーバが同じ端末の行をまとめる）
+    f.push_str("\",\"id_kind\":\"");
+    f.push_str(e.id_kind());
+    // timestamp は従来互換（最終検出時刻）
+    f.push_str("\",\"timestamp\":");
+    f.push_u64(e.stats.last_seen);
+    f.push_str(",\"first_seen\":");
+    f.push_u64(e.stats.first_seen);
+    f.push_str(",\"last_seen\":");
+    f.push_u64(e.stats.last_seen);
+    f.push_str(",\"count\":");
+    f.push_u64(e.stats.count as u64);
+    f.push_str(",\"rssi_min\":");
+    f.push_i32(e.stats.rssi_min as i32);
+    f.push_str(",\"rssi_max\":");
+    f.push_i32(e.stats.rssi_max as i32);
+    f.push_str(",\"rssi_mean\":");
+    f.push_i32(e.stats.rssi_mean() as i32);
+    f.push_str(",\"dwell_secs\":");
+    f.push_u64(e.stats.dwell_secs as u64);
+    // 距離推定（相手が TX 電力を載せていない場合は null / "unknown"）
+    f.push_str(",\"tx_power\":");
+    match e.tx_power {
+        Some(p) => f.push_i32(p as i32),
+        None => f.push_str("null"),
+    }
+    f.push_str(",\"distance_cm\":");
+    match e.distance_cm() {
+        Some(cm) => f.push_u64(cm as u64),
+        None => f.push_str("null"),
+    }
+    f.push_str(",\"proximity\":\"");
+    f.push_str(e.proximity().as_str());
+    f.push_str("\",\"mutual\":");
+    f.push_str(if e.mutual { "true" } else { "false" });
+    f.push_str(",\"nickname\":");
+    match &e.nickname {
+        Some(n) => f.push_json_str(n.as_str()),
+        None => f.push_str("null"),
+    }
+    f.push_str("}");
+    f.finish()
+}
+
+/// JSON ボディの断片を先頭から順に返す（先頭・1件ごと・末尾）
+pub struct JsonFragments<'p, 'a> {
+    payload: &'p ApiPayload<'a>,
+    /// 0 = 先頭、1..=件数 = encounters、件数+1 = 末尾
+    next: usize,
+}
+
+impl<'p, 'a> JsonFragments<'p, 'a> {
+    pub fn new(payload: &'p ApiPayload<'a>) -> Self {
+        Self { payload, next: 0 }
+    }
+}
+
+impl Iterator for JsonFragments<'_, '_> {
+    type Item = Result<Fragment, &'static str>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let n = self.payload.encounters.len();
+        let i = self.next;
+        if i > n + 1 {
+            return None;
+        }
+        self.next += 1;
+        Some(match i {
+            0 => json_head(self.payload),
+            i if i <= n => json_encounter(i - 1, &self.payload.encounters[i - 1]),
+            _ => Ok(String::try_from(JSON_TAIL).unwrap_or_default()),
+        })
+    }
+}
+
+/// JSON ボディの断片を順に `f` へ渡す（長さ計算・署名計算用。書き込み時と同じ断片を生成する）
+pub fn for_each_json_fragment(payload: &ApiPayload<'_>, mut f: impl FnMut(&[u8])) -> Result<(), &'static str> {
+    for frag in JsonFragments::new(payload) {
+        f(frag?.as_bytes());
+    }
+    Ok(())
+}
+
+/// JSON ボディの総バイト数（Content-Length 用）
+pub fn json_body_len(payload: &ApiPayload<'_>) -> Result<usize, &'static str> {
+    let mut len = 0;
+    for_each_json_fragment(payload, |frag| len += frag.len())?;
+    Ok(len)
+}
+
+/// 10進数を `s` の末尾に追加する（HTTP ヘッダの数値にも使う）
+pub fn append_u64<const N: usize>(s: &mut String<N>, mut v: u64) {
+    // 10進数を逆から詰めて反転
+    let mut buf = [0u8; 20];
+    let mut i = 0;
+    if v == 0 { let _ = s.push('0'); return; }
+    while v > 0 {
+        buf[i] = b'0' + (v % 10) as u8;
+        v /= 10;
+        i += 1;
+    }
+    while i > 0 { i -= 1; let _ = s.push(buf[i] as char); }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adv_payload::Nickname;
+    use crate::peer_stats::PeerStats;
+    use pretty_assertions::assert_eq;
+
+    fn record(seq: u32, nickname: Option<&str>) -> EncounterLog {
+        let mut stats = PeerStats::new(1_700_000_000, -70);
+        stats.observe(1_700_000_030, -55);
+        EncounterLog {
+            mac_addr: [0x28, 0xCD, 0xC1, 0x15, 0x26, seq as u8],
+            ephemeral: seq == 3,
+            seq,
+            stats,
+            tx_power: if seq % 2 == 1 { Some(-8) } else { None },
+            mutual: seq == 2,
+            nickname: nickname.and_then(|n| Nickname::new(n.as_bytes())),
+        }
+    }
+
+    fn serialize(payload: &ApiPayload<'_>) -> std::vec::Vec<u8> {
+        let mut out = std::vec::Vec::new();
+        for_each_json_fragment(payload, |frag| out.extend_from_slice(frag)).unwrap();
+        out
+    }
+
+    #[test]
+    fn body_is_valid_json_and_matches_content_length() {
+        let encounters = [record(1, Some("た\"な\\か\n")), record(2, None), record(3, Some("Taro"))];
+        let payload = ApiPayload { device_id: [1, 2, 3, 4, 5, 6], encounters: &encounters, reported_at: 1_700_000_100 };
+        let body = serialize(&payload);
+        assert_eq!(body.len(), json_body_len(&payload).unwrap());
+
+        let v: serde_json::Value = serde_json::from_slice(&body).expect("valid JSON");
+        assert_eq!(v["device_id"], "01:02:03:04:05:06");
+        assert_eq!(v["reported_at"], 1_700_000_100u64);
+        let list = v["encounters"].as_array().unwrap();
+        assert_eq!(list.len(), 3);
+        assert_eq!(list[0]["nickname"], "た\"な\\か\n");
+        assert_eq!(list[0]["tx_power"], -8);
+        assert_eq!(list[0]["rssi_min"], -70);
+        assert_eq!(list[1]["nickname"], serde_json::Value::Null);
+        assert_eq!(list[1]["mutual"], true);
+        assert_eq!(list[1]["id_kind"], "bd_addr");
+        assert_eq!(list[2]["id_kind"], "ephemeral");
+        assert_eq!(list[2]["seq"], 3);
+        assert_eq!(list[2]["first_seen"], 1_700_000_000u64);
+    }
+
+    #[test]
+    fn empty_and_full_buffers_are_valid_json() {
+        let payload = ApiPayload { device_id: [0; 6], encounters: &[], reported_at: 0 };
+        let v: serde_json::Value = serde_json::from_slice(&serialize(&payload)).expect("valid JSON");
+        assert_eq!(v["encounters"].as_array().map(|a| a.len()), Some(0));
+
+        // 最大件数・最大桁の数値・エスケープの多いニックネームでも断片に収まる
+        let mut worst = record(u32::MAX, Some("\u{1}\u{1}\u{1}\u{1}\u{1}\u{1}\u{1}\u{1}\u{1}\u{1}\u{1}\u{1}\u{1}\u{1}\u{1}\u{1}"));
+        worst.stats.first_seen = u64::MAX;
+        worst.stats.last_seen = u64::MAX;
+        worst.stats.count = u32::MAX;
+        worst.stats.dwell_secs = u32::MAX;
+        worst.stats.rssi_min = i8::MIN;
+        worst.tx_power = Some(i8::MIN);
+        worst.ephemeral = true;
+        let encounters = [worst; 100];
+        let payload = ApiPayload { device_id: [0xFF; 6], encounters: &encounters, reported_at: u64::MAX };
+        let body = serialize(&payload);
+        assert_eq!(body.len(), json_body_len(&payload).unwrap());
+        let v: serde_json::Value = serde_json::from_slice(&body).expect("valid JSON");
+        assert_eq!(v["encounters"].as_array().map(|a| a.len()), Some(100));
+    }
+}
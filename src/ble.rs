@@ -1,90 +1,629 @@
 //! BLE Host 初期化と広告/スキャンの時間多重ユーティリティ
+//! - MUTUAL_EXCHANGE=true なら接続可能な広告にし、見つけた相手と GATT で ID を交換して相互確認する（exchange）
+//! - GATT_INFO=true なら接続可能な広告にし、スマホから状態を読める情報サービスを公開する（制御は gatt_control）
+//! - GATT_CONFIG=true なら WiFi / API の設定を暗号化した書き込みで受け付ける（gatt_config）
+//! - ADV_SCAN_RESPONSE=true ならスキャン応答に短縮名とニックネームを返す。ACTIVE_SCAN=true なら
+//!   アクティブスキャンで相手のスキャン応答を受け取り、ニックネームをすれ違いの記録に付ける
+//! - ADV_EXTENDED=true なら拡張広告（Bluetooth 5）で大きな TLV を載せ、拡張スキャンで受信する。
+//!   ADV_CODED_PHY=true なら Coded PHY（長距離）。コントローラが受け付けなければ 1M PHY → 通常の広告に戻す
 
+use core::cell::{Cell, RefCell};
 use core::sync::atomic::{AtomicU8, Ordering};
 
-use defmt::info;
-use embassy_time::{Duration, Timer, Instant};
+use defmt::{debug, info, warn};
+use embassy_rp::clocks::RoscRng;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{with_timeout, Duration, Timer, Instant};
 use embassy_futures::join::join;
+use embassy_futures::select::{select, Either};
+use portable_atomic::AtomicU32;
+use rand_core::RngCore;
 
 
-use trouble_host::advertise::{AdStructure, Advertisement, AdvertisementParameters};
+use trouble_host::advertise::{AdStructure, Advertisement, AdvertisementParameters, AdvertisementSet};
 use trouble_host::prelude::*;
+use bt_hci::param::{AddrKind, BdAddr, LeAdvEventKind, PhyKind};
 
-use pico_w_id_beacon::adv_payload::{build_adv_payload, parse_service_data};
+use pico_w_id_beacon::adv_payload::{
+    build_adv_payload, build_adv_payload_v2, build_scan_response, ephemeral_id, epoch_for, parse_scan_response,
+    parse_service_data, unsynced_epoch, ParseError, Parsed, TlvBuilder, AD_TYPE_TX_POWER, MAX_EXTENDED_PAYLOAD,
+    MAX_LEGACY_PAYLOAD, MAX_SCAN_RESPONSE,
+};
+use pico_w_id_beacon::exchange::{
+    should_initiate, uuid_le, ExchangeCooldown, RecentPeers, EXCHANGE_OWN_ID_UUID, EXCHANGE_PEER_ID_UUID,
+    EXCHANGE_SERVICE_UUID,
+};
+use pico_w_id_beacon::console::{self, apply_config, Command, ConfigKey};
+use pico_w_id_beacon::gatt_config::{self, MAX_CONFIG_PLAINTEXT, MAX_CONFIG_WRITE};
+use pico_w_id_beacon::gatt_control::{verify_control, ControlCommand, CHALLENGE_LEN};
+use pico_w_id_beacon::ble_addr::{derive_irk, resolvable_private_address, static_random_address, use_rpa};
 use pico_w_id_beacon::format::fmt_bytes_colon;
 use pico_w_id_beacon::constants::SERVICE_UUID_16;
 
 static RX_PULSES: AtomicU8 = AtomicU8::new(0);
 
+/// 現在広告中の一時ID（自分の信号の判定用。一時IDを使わない場合は未使用）
+static ADV_ID: Mutex<CriticalSectionRawMutex, Cell<Option<[u8; 6]>>> = Mutex::new(Cell::new(None));
+
+/// 自分の BLE アドレス（ID 交換でどちらが接続するかの判定用）
+static OWN_ADDR: Mutex<CriticalSectionRawMutex, Cell<[u8; 6]>> = Mutex::new(Cell::new([0; 6]));
+
+/// ID 交換の接続先
+struct ExchangeTarget {
+    kind: AddrKind,
+    addr: BdAddr,
+    /// 相手が広告している ID（読み出した ID と一致すれば相互確認済みにする）
+    id: [u8; 6],
+}
+
+/// ID 交換の接続待ち（受信コールバックから積み、広告/スキャンのループで接続する）
+static EXCHANGE_QUEUE: Channel<CriticalSectionRawMutex, ExchangeTarget, 4> = Channel::new();
+
+/// 最近接続を試みた相手
+static EXCHANGE_COOLDOWN: Mutex<CriticalSectionRawMutex, RefCell<ExchangeCooldown>> =
+    Mutex::new(RefCell::new(ExchangeCooldown::new()));
+
+/// ID 交換の接続・読み書き全体のタイムアウト
+const EXCHANGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 接続された側で接続を保つ最大時間（スマホからの読み出し・設定を含む）
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// 電池残量が分からないときの値（電池電圧の測定は未対応）
+const BATTERY_UNKNOWN: u8 = 0xFF;
+
+/// PicoStreet の GATT サーバ（ID 交換と情報サービス）
+#[gatt_server]
+struct Server {
+    exchange: ExchangeService,
+    info: InfoService,
+    config: ConfigService,
+}
+
+#[gatt_service(uuid = "8c3f0001-1d2b-4f5e-9a3c-50696f537472")]
+struct ExchangeService {
+    // 自分の ID（広告中の ID と同じ）
+    #[characteristic(uuid = "8c3f0002-1d2b-4f5e-9a3c-50696f537472", read)]
+    own_id: [u8; 6],
+    // 接続してきた相手が自分の ID を書き込む
+    #[characteristic(uuid = "8c3f0003-1d2b-4f5e-9a3c-50696f537472", write)]
+    peer_id: [u8; 6],
+}
+
+/// 設定サービス（チャレンジは情報サービスと共通、鍵は GATT_CONFIG_KEY）
+#[gatt_service(uuid = "8c3f0201-1d2b-4f5e-9a3c-50696f537472")]
+struct ConfigService {
+    // 暗号化したコンソールの1行（`config set <key> <value>`）+ タグ
+    #[characteristic(uuid = "8c3f0202-1d2b-4f5e-9a3c-50696f537472", write)]
+    command: heapless::Vec<u8, MAX_CONFIG_WRITE>,
+    // 直前の書き込みの結果（UTF-8）
+    #[characteristic(uuid = "8c3f0203-1d2b-4f5e-9a3c-50696f537472", read)]
+    status: heapless::String<64>,
+}
+
+/// 情報サービス（値は接続ごとに `refresh_info` で更新する）
+#[gatt_service(uuid = "8c3f0101-1d2b-4f5e-9a3c-50696f537472")]
+struct InfoService {
+    // 広告中の ID（一時ID を使う場合は BD_ADDR ではなく一時ID。認証なしで読めるため）
+    #[characteristic(uuid = "8c3f0102-1d2b-4f5e-9a3c-50696f537472", read)]
+    device_id: [u8; 6],
+    // ファームウェアのバージョン（UTF-8）
+    #[characteristic(uuid = "8c3f0103-1d2b-4f5e-9a3c-50696f537472", read)]
+    firmware: heapless::String<16>,
+    // 起動後に記録した相手の数（u32 LE）
+    #[characteristic(uuid = "8c3f0104-1d2b-4f5e-9a3c-50696f537472", read)]
+    encounter_count: u32,
+    // 最後に送信に成功した時刻（Unix秒 u64 LE、未送信なら0）
+    #[characteristic(uuid = "8c3f0105-1d2b-4f5e-9a3c-50696f537472", read)]
+    last_upload: u64,
+    // 電池残量（%、不明なら 255）
+    #[characteristic(uuid = "8c3f0106-1d2b-4f5e-9a3c-50696f537472", read)]
+    battery: u8,
+    // 制御コマンドのチャレンジ（接続ごと・書き込みごとに変わる）
+    #[characteristic(uuid = "8c3f0107-1d2b-4f5e-9a3c-50696f537472", read)]
+    challenge: [u8; CHALLENGE_LEN],
+    // 制御コマンド（[コマンド][タグ]、GATT_CONTROL_KEY で認証）
+    #[characteristic(uuid = "8c3f0108-1d2b-4f5e-9a3c-50696f537472", write)]
+    control: [u8; 9],
+}
+
+/// 自分が広告している ID かどうか
+fn is_self_id(id: &[u8; 6], self_bd_addr: &[u8; 6]) -> bool {
+    id == self_bd_addr || ADV_ID.lock(|c| c.get()) == Some(*id)
+}
+
+/// 最近受信した PicoStreet の広告のアドレスと ID
+/// （スキャン応答にはアドレスしか無いため。ID の書き込みも接続元のアドレスで照合する）
+static RECENT_PEERS: Mutex<CriticalSectionRawMutex, RefCell<RecentPeers>> = Mutex::new(RefCell::new(RecentPeers::new()));
+
+/// 広告のアドレスと ID を覚える
+fn remember_peer(addr: [u8; 6], id: [u8; 6]) {
+    RECENT_PEERS.lock(|p| p.borrow_mut().remember(&addr, &id, Instant::now().as_millis()));
+}
+
+/// `addr` から最近受信した広告の ID
+fn recent_peer_id(addr: &[u8; 6]) -> Option<[u8; 6]> {
+    RECENT_PEERS.lock(|p| p.borrow().id_of(addr, Instant::now().as_millis()))
+}
+
+/// 受信した広告の分類ごとの件数（診断用）
+#[derive(Clone, Copy, Default)]
+pub struct RxStats {
+    /// PicoStreet として解析できた
+    pub ok: u32,
+    pub no_service_data: u32,
+    pub wrong_uuid: u32,
+    pub malformed_ad: u32,
+    pub wrong_device_type: u32,
+    pub unsupported_version: u32,
+    pub bad_length: u32,
+}
+
+/// 分類ごとの件数（添字は `rx_slot`）
+static RX_COUNTS: [AtomicU32; 7] = [const { AtomicU32::new(0) }; 7];
+
+fn rx_slot(res: &Result<Parsed, ParseError>) -> usize {
+    match res {
+        Ok(_) => 0,
+        Err(ParseError::NoServiceData) => 1,
+        Err(ParseError::WrongUuid) => 2,
+        Err(ParseError::MalformedAd) => 3,
+        Err(ParseError::WrongDeviceType) => 4,
+        Err(ParseError::UnsupportedVersion(_)) => 5,
+        Err(ParseError::BadLength) => 6,
+    }
+}
+
+/// 起動後に受信した広告の分類ごとの件数
+pub fn rx_stats() -> RxStats {
+    let c = |i: usize| RX_COUNTS[i].load(Ordering::Relaxed);
+    RxStats {
+        ok: c(0),
+        no_service_data: c(1),
+        wrong_uuid: c(2),
+        malformed_ad: c(3),
+        wrong_device_type: c(4),
+        unsupported_version: c(5),
+        bad_length: c(6),
+    }
+}
+
 struct RxHandler {
     self_bd_addr: [u8; 6],
 }
 
+/// 受信した広告1件（レガシー・拡張共通）
+struct Report<'a> {
+    data: &'a [u8],
+    rssi: i8,
+    ext: bool,
+    addr_kind: AddrKind,
+    addr: BdAddr,
+    /// 接続可能な広告か（ID 交換の対象）
+    connectable: bool,
+    /// アクティブスキャンへのスキャン応答か
+    scan_response: bool,
+}
+
+impl RxHandler {
+    /// 受信した広告1件を処理する（分類を数え、PicoStreet なら保存）。
+    fn on_report(&self, r: Report<'_>) {
+        if r.scan_response {
+            on_scan_response(&r);
+            return;
+        }
+        let res = parse_service_data(r.data);
+        RX_COUNTS[rx_slot(&res)].fetch_add(1, Ordering::Relaxed);
+        let rssi = r.rssi;
+        let parsed = match res {
+            Ok(p) => p,
+            Err(ParseError::UnsupportedVersion(v)) => {
+                debug!("未対応バージョンの PicoStreet 広告 v{} rssi={}", v, rssi);
+                return;
+            }
+            Err(_) => return,
+        };
+        let kind = if r.ext { "(拡張)" } else { "" };
+        // 自分自身のIDの場合は「SELF RX」としてログする（LEDは点滅させない）
+        if is_self_id(&parsed.id, &self.self_bd_addr) {
+            let s = fmt_bytes_colon(&parsed.id);
+            info!("自分の信号受信{} id={} rssi={}", kind, s.as_str(), rssi);
+            return;
+        }
+
+        let s = fmt_bytes_colon(&parsed.id);
+        info!("他デバイス検出{} id={} v{} rssi={}", kind, s.as_str(), parsed.version, rssi);
+        // 現在時刻（NTP未同期時は0）
+        let now = crate::timekeeper::now_unix().unwrap_or(0);
+        let _ = crate::storage::save_encounter(parsed.id, parsed.ephemeral, now, rssi, parsed.tx_power);
+        if let Some(n) = parsed.nickname {
+            crate::storage::set_nickname(parsed.id, n);
+        }
+        if crate::settings::ACTIVE_SCAN || crate::settings::MUTUAL_EXCHANGE {
+            if let Ok(addr) = <[u8; 6]>::try_from(r.addr.raw()) {
+                remember_peer(addr, parsed.id);
+            }
+        }
+        if r.connectable {
+            queue_exchange(r.addr_kind, r.addr, parsed.id);
+        }
+        let v = RX_PULSES.load(Ordering::Relaxed);
+        RX_PULSES.store(v.saturating_add(1), Ordering::Relaxed);
+    }
+}
+
+/// スキャン応答1件を処理する（直前に広告を受信した PicoStreet のニックネームを記録に付ける）。
+/// 広告の分類の件数には数えない。
+fn on_scan_response(r: &Report<'_>) {
+    let Some(resp) = parse_scan_response(r.data) else { return };
+    let Ok(addr) = <[u8; 6]>::try_from(r.addr.raw()) else { return };
+    let Some(id) = recent_peer_id(&addr) else { return };
+    debug!("スキャン応答 {} rssi={}", resp.short_name.as_str(), r.rssi);
+    if let Some(n) = resp.nickname {
+        crate::storage::set_nickname(id, n);
+    }
+}
+
 impl EventHandler for RxHandler {
     fn on_adv_reports(&self, mut it: trouble_host::scan::LeAdvReportsIter<'_>) {
         while let Some(Ok(report)) = it.next() {
-            let data = report.data;
-            if let Some(parsed) = parse_service_data(data) {
-                // 自分自身のBD_ADDRの場合は「SELF RX」としてログする（LEDは点滅させない）
-                if parsed.bd_addr == self.self_bd_addr {
-                    let s = fmt_bytes_colon(&parsed.bd_addr);
-                    info!("自分の信号受信 bd_addr={} rssi={}", s.as_str(), report.rssi);
-                    continue;
-                }
-                
-                let s = fmt_bytes_colon(&parsed.bd_addr);
-                info!("他デバイス検出 bd_addr={} rssi={}", s.as_str(), report.rssi);
-                // 現在時刻（NTP未同期時は0）
-                let now = crate::timekeeper::now_unix().unwrap_or(0);
-                let _ = crate::storage::save_encounter(parsed.bd_addr, now, report.rssi);
-                let v = RX_PULSES.load(Ordering::Relaxed);
-                RX_PULSES.store(v.saturating_add(1), Ordering::Relaxed);
-            }
+            self.on_report(Report {
+                data: report.data,
+                rssi: report.rssi,
+                ext: false,
+                addr_kind: report.addr_kind,
+                addr: report.addr,
+                connectable: matches!(report.event_kind, LeAdvEventKind::AdvInd),
+                scan_response: matches!(report.event_kind, LeAdvEventKind::ScanRsp),
+            });
         }
     }
 
     fn on_ext_adv_reports(&self, mut it: trouble_host::scan::LeExtAdvReportsIter<'_>) {
         while let Some(Ok(report)) = it.next() {
-            let data = report.data;
-            if let Some(parsed) = parse_service_data(data) {
-                // 自分自身のBD_ADDRの場合は「SELF RX」としてログする（LEDは点滅させない）
-                if parsed.bd_addr == self.self_bd_addr {
-                    let s = fmt_bytes_colon(&parsed.bd_addr);
-                    info!("自分の信号受信(拡張) bd_addr={} rssi={}", s.as_str(), report.rssi);
-                    continue;
+            self.on_report(Report {
+                data: report.data,
+                rssi: report.rssi,
+                ext: true,
+                addr_kind: report.addr_kind,
+                addr: report.addr,
+                connectable: report.event_kind.connectable(),
+                scan_response: report.event_kind.scan_response(),
+            });
+        }
+    }
+}
+
+/// 記録済みでまだ相互確認していない相手なら ID 交換の接続待ちに積む（アドレスの小さい方だけが接続する）
+fn queue_exchange(kind: AddrKind, addr: BdAddr, id: [u8; 6]) {
+    if !crate::settings::MUTUAL_EXCHANGE {
+        return;
+    }
+    let Ok(peer_addr) = <[u8; 6]>::try_from(addr.raw()) else { return };
+    if !should_initiate(&OWN_ADDR.lock(|c| c.get()), &peer_addr) || !crate::storage::needs_exchange(&id) {
+        return;
+    }
+    if EXCHANGE_COOLDOWN.lock(|c| c.borrow_mut().try_start(&id, Instant::now().as_millis())) {
+        let _ = EXCHANGE_QUEUE.try_send(ExchangeTarget { kind, addr, id });
+    }
+}
+
+/// 相手に接続して ID を交換する（相手の ID を読んで確かめ、自分の ID を書き込む）。
+async fn initiate_exchange<C>(
+    stack: &Stack<'_, C, DefaultPacketPool>,
+    central: &mut Central<'_, C, DefaultPacketPool>,
+    target: &ExchangeTarget,
+    own_id: [u8; 6],
+) -> Result<(), &'static str>
+where
+    C: Controller
+        + bt_hci::controller::ControllerCmdSync<bt_hci::cmd::le::LeClearFilterAcceptList>
+        + bt_hci::controller::ControllerCmdSync<bt_hci::cmd::le::LeAddDeviceToFilterAcceptList>
+        + bt_hci::controller::ControllerCmdAsync<bt_hci::cmd::le::LeCreateConn>
+        + bt_hci::controller::ControllerCmdSync<bt_hci::cmd::le::LeCreateConnCancel>
+        + bt_hci::controller::ControllerCmdSync<bt_hci::cmd::le::LeSetExtScanParams>
+        + bt_hci::controller::ControllerCmdSync<bt_hci::cmd::le::LeSetExtScanEnable>
+        + bt_hci::controller::ControllerCmdSync<bt_hci::cmd::le::LeClearAdvSets>
+        + bt_hci::controller::ControllerCmdSync<bt_hci::cmd::le::LeSetExtAdvParams>
+        + bt_hci::controller::ControllerCmdSync<bt_hci::cmd::le::LeSetAdvSetRandomAddr>
+        + bt_hci::controller::ControllerCmdSync<bt_hci::cmd::le::LeReadNumberOfSupportedAdvSets>
+        + for<'t> bt_hci::controller::ControllerCmdSync<bt_hci::cmd::le::LeSetExtAdvData<'t>>
+        + for<'t> bt_hci::controller::ControllerCmdSync<bt_hci::cmd::le::LeSetExtScanResponseData<'t>>
+        + for<'t> bt_hci::controller::ControllerCmdSync<bt_hci::cmd::le::LeSetExtAdvEnable<'t>>,
+{
+    let config = ConnectConfig {
+        connect_params: Default::default(),
+        scan_config: ScanConfig { filter_accept_list: &[(target.kind, &target.addr)], ..Default::default() },
+    };
+    let conn = match with_timeout(EXCHANGE_TIMEOUT, central.connect(&config)).await {
+        Ok(Ok(conn)) => conn,
+        Ok(Err(_)) => return Err("接続失敗"),
+        Err(_) => return Err("接続タイムアウト"),
+    };
+    let client = match GattClient::<C, DefaultPacketPool, 4>::new(stack, &conn).await {
+        Ok(c) => c,
+        Err(_) => {
+            conn.disconnect();
+            return Err("GATT クライアント初期化失敗");
+        }
+    };
+    let exchange = async {
+        let services = client
+            .services_by_uuid(&Uuid::new_long(uuid_le(&EXCHANGE_SERVICE_UUID)))
+            .await
+            .map_err(|_| "サービス検索失敗")?;
+        let service = services.first().ok_or("PicoStreet サービスなし")?.clone();
+        let own: Characteristic<[u8; 6]> = client
+            .characteristic_by_uuid(&service, &Uuid::new_long(uuid_le(&EXCHANGE_OWN_ID_UUID)))
+            .await
+            .map_err(|_| "特性検索失敗")?;
+        let peer: Characteristic<[u8; 6]> = client
+            .characteristic_by_uuid(&service, &Uuid::new_long(uuid_le(&EXCHANGE_PEER_ID_UUID)))
+            .await
+            .map_err(|_| "特性検索失敗")?;
+        let mut their_id = [0u8; 6];
+        let n = client.read_characteristic(&own, &mut their_id).await.map_err(|_| "ID 読み出し失敗")?;
+        // 広告を受信した相手と接続先が同じ端末か（一時IDの切り替わり直後などは一致しない）
+        if n != their_id.len() || their_id != target.id {
+            return Err("ID 不一致");
+        }
+        client.write_characteristic(&peer, &own_id).await.map_err(|_| "ID 書き込み失敗")?;
+        Ok(())
+    };
+    let res = match select(client.task(), with_timeout(EXCHANGE_TIMEOUT, exchange)).await {
+        Either::First(_) => Err("切断"),
+        Either::Second(Ok(res)) => res,
+        Either::Second(Err(_)) => Err("交換タイムアウト"),
+    };
+    conn.disconnect();
+    if res.is_ok() {
+        crate::storage::mark_mutual(target.id).await;
+    }
+    res
+}
+
+/// 情報サービスの値を現在の状態にする（チャレンジも作り直す）
+fn refresh_info(server: &Server<'_>) -> [u8; CHALLENGE_LEN] {
+    let info = &server.info;
+    let mut challenge = [0u8; CHALLENGE_LEN];
+    RoscRng.fill_bytes(&mut challenge);
+    let _ = server.set(&info.encounter_count, &crate::storage::total_saved());
+    let _ = server.set(&info.last_upload, &crate::scheduler::last_upload().unwrap_or(0));
+    let _ = server.set(&info.battery, &BATTERY_UNKNOWN);
+    let _ = server.set(&info.challenge, &challenge);
+    challenge
+}
+
+/// 制御コマンドを実行する
+async fn run_control(cmd: ControlCommand) {
+    info!("制御コマンド: {}", defmt::Debug2Format(&cmd));
+    match cmd {
+        ControlCommand::UploadNow => crate::scheduler::request_upload(),
+        ControlCommand::ClearLog => {
+            crate::storage::clear().await;
+        }
+        // 応答を返してから再起動する（`serve_connection` の最後）
+        ControlCommand::Reboot => {}
+    }
+}
+
+/// 設定サービスへの書き込みを復号し、`config set` なら設定を保存する（反映は再起動後）。
+async fn apply_config_write(challenge: &[u8; CHALLENGE_LEN], data: &[u8]) -> Result<&'static str, &'static str> {
+    if !crate::settings::GATT_CONFIG {
+        return Err("設定サービスは無効");
+    }
+    let key = crate::settings::GATT_CONFIG_KEY;
+    // 制御コマンドと同じ鍵では、制御用に鍵を渡した相手も設定を変えられてしまう
+    if !key.is_empty() && key == crate::settings::GATT_CONTROL_KEY {
+        return Err("GATT_CONFIG_KEY が GATT_CONTROL_KEY と同じ");
+    }
+    let mut buf = [0u8; MAX_CONFIG_PLAINTEXT];
+    let line = gatt_config::open(key, challenge, data, &mut buf).map_err(|e| e.as_str())?;
+    let Ok(Command::ConfigSet { key, value }) = console::parse(line) else {
+        return Err("config set <key> <value> のみ受け付けます");
+    };
+    // 署名鍵は USB コンソールでのみ書き込む（無線では受け付けない）
+    if key == ConfigKey::Key {
+        return Err("key は USB コンソールで設定してください");
+    }
+    let mut rec = crate::config::latest();
+    apply_config(&mut rec, key, value)?;
+    crate::config::save(rec).await?;
+    info!("BLE から設定を保存: {}", defmt::Debug2Format(&key));
+    Ok("保存しました（再起動後に反映）")
+}
+
+/// 接続してきた相手（ID 交換の相手やスマホ）に GATT サーバを提供する。
+/// ID の書き込みは接続元が直前に広告していた ID と一致すれば相互確認済みとして記録し、
+/// 制御コマンドと設定の書き込みは認証してから実行する。
+async fn serve_connection(server: &Server<'_>, conn: Connection<'_, DefaultPacketPool>) {
+    let conn = match conn.with_attribute_server(server) {
+        Ok(c) => c,
+        Err(_) => {
+            info!("GATT サーバの割り当て失敗");
+            return;
+        }
+    };
+    let peer_handle = server.exchange.peer_id.handle;
+    let control_handle = server.info.control.handle;
+    let config_handle = server.config.command.handle;
+    let mut challenge = refresh_info(server);
+    let mut reboot = false;
+    let res = with_timeout(CONNECTION_TIMEOUT, async {
+        loop {
+            match conn.next().await {
+                GattConnectionEvent::Disconnected { .. } => break,
+                GattConnectionEvent::Gatt { event } => {
+                    if let GattEvent::Write(w) = &event {
+                        if w.handle() == peer_handle && crate::settings::MUTUAL_EXCHANGE {
+                            if let Ok(id) = <[u8; 6]>::try_from(w.data()) {
+                                let peer_addr = <[u8; 6]>::try_from(conn.raw().peer_address().raw()).ok();
+                                if peer_addr.and_then(|a| recent_peer_id(&a)) == Some(id) {
+                                    crate::storage::mark_mutual(id).await;
+                                } else {
+                                    info!("ID の書き込みを拒否（接続元の広告と一致しない）");
+                                }
+                            }
+                        } else if w.handle() == control_handle {
+                            match verify_control(crate::settings::GATT_CONTROL_KEY, &challenge, w.data()) {
+                                Ok(cmd) => {
+                                    run_control(cmd).await;
+                                    reboot |= cmd == ControlCommand::Reboot;
+                                }
+                                Err(e) => info!("制御コマンドを拒否: {}", e.as_str()),
+                            }
+                            // 同じ書き込みを再送されても通らないよう、毎回チャレンジを変える
+                            challenge = refresh_info(server);
+                        } else if w.handle() == config_handle {
+                            let result = apply_config_write(&challenge, w.data()).await;
+                            if let Err(e) = result {
+                                info!("設定の書き込みを拒否: {}", e);
+                            }
+                            let mut status = heapless::String::new();
+                            let _ = status.push_str(match result {
+                                Ok(s) | Err(s) => s,
+                            });
+                            let _ = server.set(&server.config.status, &status);
+                            challenge = refresh_info(server);
+                        }
+                    }
+                    if let Ok(reply) = event.accept() {
+                        reply.send().await;
+                    }
+                    if reboot {
+                        break;
+                    }
                 }
-                
-                let s = fmt_bytes_colon(&parsed.bd_addr);
-                info!("他デバイス検出(拡張) bd_addr={} rssi={}", s.as_str(), report.rssi);
-                let now = crate::timekeeper::now_unix().unwrap_or(0);
-                let _ = crate::storage::save_encounter(parsed.bd_addr, now, report.rssi);
-                let v = RX_PULSES.load(Ordering::Relaxed);
-                RX_PULSES.store(v.saturating_add(1), Ordering::Relaxed);
+                _ => {}
             }
         }
+    })
+    .await;
+    if res.is_err() {
+        info!("接続タイムアウト（切断）");
+    }
+    conn.raw().disconnect();
+    if reboot {
+        Timer::after(Duration::from_millis(200)).await;
+        cortex_m::peripheral::SCB::sys_reset();
     }
 }
 
 /// 広告用の AD を構築（Flags, Complete 16-bit UUIDs, Service Data）
-fn build_advertisement_data<'a>(buf: &'a mut [u8], payload: &'a [u8]) -> &'a [u8] {
+fn build_advertisement_data<'a>(buf: &'a mut [u8], payload: &'a [u8], tx_power: i8) -> &'a [u8] {
     // SERVICE_UUID_16 を LE エンディアンで
     let uuid16 = [(SERVICE_UUID_16 & 0xff) as u8, (SERVICE_UUID_16 >> 8) as u8];
     let mut used = 0usize;
     // Flags (0x01) 0x06
     used += AdStructure::encode_slice(&[AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED)], &mut buf[used..]).unwrap();
     // NOTE: Service Data に UUID を含めるため、重複する Complete 16-bit UUIDs は省略
-    // 省略により全体 31B 制約内に収める（Flags 3B + ServiceData(4B+payload)）
+    // 省略により全体 31B 制約内に収める（Flags 3B + TX Power 3B + ServiceData(4B+payload)）
+    // TX Power Level (0x0A): 受信側の距離推定用
+    used += AdStructure::encode_slice(&[AdStructure::Unknown { ty: AD_TYPE_TX_POWER, data: &[tx_power as u8] }], &mut buf[used..]).unwrap();
     // Service Data (0x16)
     used += AdStructure::encode_slice(&[AdStructure::ServiceData16 { uuid: uuid16, data: payload }], &mut buf[used..]).unwrap();
     &buf[..used]
 }
 
+/// 広告・スキャンの方式（コントローラが受け付けなければ順に下げる）
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum AdvMode {
+    /// 拡張広告・拡張スキャン（Coded PHY、長距離）
+    ExtendedCoded,
+    /// 拡張広告・拡張スキャン（1M PHY）
+    Extended,
+    /// 通常の広告（31バイト）・スキャン
+    Legacy,
+}
+
+impl AdvMode {
+    /// 設定から決める。RPA はアドレスを切り替えられないため拡張広告にしない
+    /// （拡張広告のアドレスは広告セットごとで、起動時のアドレスのままになる）
+    fn from_settings(use_rpa: bool) -> Self {
+        if !crate::settings::ADV_EXTENDED || use_rpa {
+            Self::Legacy
+        } else if crate::settings::ADV_CODED_PHY {
+            Self::ExtendedCoded
+        } else {
+            Self::Extended
+        }
+    }
+
+    /// 受け付けられなかったときの次の方式
+    fn fallback(self) -> Self {
+        match self {
+            Self::ExtendedCoded => Self::Extended,
+            Self::Extended | Self::Legacy => Self::Legacy,
+        }
+    }
+
+    fn is_extended(self) -> bool {
+        self != Self::Legacy
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::ExtendedCoded => "拡張広告（Coded PHY）",
+            Self::Extended => "拡張広告（1M PHY）",
+            Self::Legacy => "通常の広告",
+        }
+    }
+}
+
+/// 現在のエポック番号（時刻未同期なら起動ごとの乱数から始まる稼働時間ベース）
+fn current_epoch(unsynced_nonce: u32) -> u32 {
+    match crate::timekeeper::now_unix() {
+        Some(unix) => epoch_for(unix),
+        None => unsynced_epoch(unsynced_nonce, Instant::now().as_secs()),
+    }
+}
+
+/// 自分の広告ペイロードを構築し、(長さ, 載せたID) を返す。
+/// PRIVACY_ID_KEY が設定されていれば一時ID（v2）、空なら BD_ADDR（v1）。ADV_TLV=true なら TLV 形式（v3）。
+/// 拡張広告では常に TLV 形式で、TX 電力と切り詰めないニックネームも載せる（受信できるのは新しいファームだけのため）。
+fn build_self_payload(
+    buf: &mut [u8; MAX_EXTENDED_PAYLOAD],
+    self_bd_addr: &[u8; 6],
+    epoch: u32,
+    mode: AdvMode,
+    tx_power: i8,
+) -> (usize, [u8; 6]) {
+    let key = crate::settings::PRIVACY_ID_KEY;
+    let ephemeral = !key.is_empty();
+    let id = if ephemeral { ephemeral_id(key, self_bd_addr, epoch) } else { *self_bd_addr };
+    if ephemeral {
+        ADV_ID.lock(|c| c.set(Some(id)));
+    }
+    if mode.is_extended() {
+        let Some(mut b) = TlvBuilder::with_limit(buf, MAX_EXTENDED_PAYLOAD) else { return (0, id) };
+        b.id(&id, ephemeral);
+        b.tx_power(tx_power);
+        if !crate::settings::ADV_NICKNAME.is_empty() {
+            b.nickname(crate::settings::ADV_NICKNAME);
+        }
+        return (b.finish(), id);
+    }
+    let buf = &mut buf[..MAX_LEGACY_PAYLOAD];
+    if crate::settings::ADV_TLV {
+        let Some(mut b) = TlvBuilder::new(buf) else { return (0, id) };
+        b.id(&id, ephemeral);
+        if !crate::settings::ADV_NICKNAME.is_empty() {
+            b.nickname(crate::settings::ADV_NICKNAME);
+        }
+        return (b.finish(), id);
+    }
+    let len = if ephemeral { build_adv_payload_v2(buf, &id) } else { build_adv_payload(buf, &id) };
+    (len, id)
+}
+
 /// BLE Host を生成し、TXフェーズ → RXフェーズを繰り返す。
-/// - TXフェーズ: Service Data に簡素化PicoStreetペイロードを格納して広告
+/// - TXフェーズ: Service Data に簡素化PicoStreetペイロードを格納して広告（一時IDはエポックごとに切り替え）
 /// - RXフェーズ: スキャンして見つかったら RX LED を点滅
 pub async fn advertise_and_scan_loop<C>(
     controller: C,
-    control: &mut cyw43::Control<'_>,
+    control: &'static crate::SharedControl,
     self_bd_addr: [u8; 6],
 ) -> !
 where
@@ -92,11 +631,32 @@ where
         + bt_hci::controller::ControllerCmdSync<bt_hci::cmd::le::LeSetScanParams>
         + bt_hci::controller::ControllerCmdSync<bt_hci::cmd::le::LeSetScanEnable>
         + bt_hci::controller::ControllerCmdSync<bt_hci::cmd::le::LeClearFilterAcceptList>
-        + bt_hci::controller::ControllerCmdSync<bt_hci::cmd::le::LeAddDeviceToFilterAcceptList>,
+        + bt_hci::controller::ControllerCmdSync<bt_hci::cmd::le::LeAddDeviceToFilterAcceptList>
+        + bt_hci::controller::ControllerCmdSync<bt_hci::cmd::le::LeSetRandomAddr>
+        + bt_hci::controller::ControllerCmdSync<bt_hci::cmd::le::LeReadAdvPhysicalChannelTxPower>
+        + bt_hci::controller::ControllerCmdAsync<bt_hci::cmd::le::LeCreateConn>
+        + bt_hci::controller::ControllerCmdSync<bt_hci::cmd::le::LeCreateConnCancel>,
 {
-    // ランダムアドレス（PoC固定値）
-    let address: Address = Address::random([0xff, 0x8f, 0x1a, 0x05, 0xe4, 0xff]);
-    info!("BLEアドレス = {:?}", address);
+    // ランダムアドレス: 端末ごとの静的ランダムアドレス、BLE_RPA=true または一時ID を使うなら RPA（エポックごとに切り替え）
+    let irk = derive_irk(
+        match crate::settings::PRIVACY_ID_KEY {
+            [] => &self_bd_addr[..],
+            key => key,
+        },
+        &self_bd_addr,
+    );
+    let use_rpa = use_rpa(crate::settings::BLE_RPA, crate::settings::PRIVACY_ID_KEY);
+    if use_rpa && !crate::settings::BLE_RPA {
+        info!("一時ID を使うため BLE アドレスを RPA にします（BLE_RPA=false を上書き）");
+    }
+    let addr_bytes = if use_rpa {
+        resolvable_private_address(&irk, RoscRng.next_u32())
+    } else {
+        static_random_address(&self_bd_addr)
+    };
+    OWN_ADDR.lock(|c| c.set(addr_bytes));
+    let address: Address = Address::random(addr_bytes);
+    info!("BLEアドレス = {:?} ({})", address, if use_rpa { "RPA" } else { "静的ランダム" });
 
     // Host 準備
     let mut resources: HostResources<DefaultPacketPool, 1, 1> = HostResources::new();
@@ -105,76 +665,244 @@ where
     let Host { mut peripheral, central, mut runner, .. } = stack.build();
     let mut scanner = Scanner::new(central);
     let handler = RxHandler { self_bd_addr };
+    let mutual = crate::settings::MUTUAL_EXCHANGE;
+    // ID 交換か情報・設定サービスを使う場合は接続を受け付ける
+    let connectable = mutual || crate::settings::GATT_INFO || crate::settings::GATT_CONFIG;
+    let server = match Server::new_with_config(GapConfig::Peripheral(PeripheralConfig {
+        name: "PicoStreet",
+        appearance: &appearance::keyring::GENERIC_KEYRING,
+    })) {
+        Ok(s) => s,
+        Err(e) => {
+            info!("GATT サーバ初期化失敗: {}", e);
+            crate::leds::error_blink_loop(&mut *control.lock().await).await;
+        }
+    };
+    let mut firmware = heapless::String::new();
+    let _ = firmware.push_str(env!("CARGO_PKG_VERSION"));
+    let _ = server.set(&server.info.firmware, &firmware);
+    info!("相互確認（GATT での ID 交換）: {}", if mutual { "有効" } else { "無効" });
+    info!("GATT 情報サービス: {}", if crate::settings::GATT_INFO { "有効" } else { "無効" });
+    info!("GATT 設定サービス: {}", if crate::settings::GATT_CONFIG { "有効" } else { "無効" });
+    let config_key = crate::settings::GATT_CONFIG_KEY;
+    if crate::settings::GATT_CONFIG && (config_key.is_empty() || config_key == crate::settings::GATT_CONTROL_KEY) {
+        warn!("GATT_CONFIG_KEY が未設定または GATT_CONTROL_KEY と同じため、設定の書き込みは受け付けません");
+    }
 
-    // バッファ
-    let mut adv_payload = [0u8; 8];
-    let payload_len = build_adv_payload(&mut adv_payload, &self_bd_addr);
-    let payload = &adv_payload[..payload_len];
-    let bd_str = fmt_bytes_colon(&self_bd_addr);
-    info!("送信ペイロード構築 len={} bd_addr={}", payload_len, bd_str.as_str());
+    // 時刻未同期のときの一時ID用（起動ごとに変える）
+    let unsynced_nonce = RoscRng.next_u32();
+    // 広告する ID が切り替わるか（一時ID）と、アドレスが切り替わるか（RPA）は別。一時ID なら必ず RPA になる
+    let id_rotates = !crate::settings::PRIVACY_ID_KEY.is_empty();
+    info!(
+        "送信ID: {} / アドレス: {}",
+        if id_rotates { "一時ID（v2、エポックごとに切り替え）" } else { "BD_ADDR（v1、固定）" },
+        if use_rpa { "RPA（エポックごとに切り替え）" } else { "静的ランダム（固定）" }
+    );
+    if use_rpa && !id_rotates {
+        warn!("BLE_RPA=true でも PRIVACY_ID_KEY が空のため、広告のペイロードに BD_ADDR が載ります（アドレスを変えても端末を追跡できます）");
+    }
     let mut ad_buf = [0u8; 31];
+    // 拡張広告: Flags 3B + TX Power 3B + Service Data (4B + payload) + 名前（スキャン応答と同じ AD）
+    let mut ext_ad_buf = [0u8; 10 + MAX_EXTENDED_PAYLOAD + MAX_SCAN_RESPONSE];
+    let mut scan_buf = [0u8; MAX_SCAN_RESPONSE];
+    let scannable = crate::settings::ADV_SCAN_RESPONSE;
+    info!("スキャン応答: {} / アクティブスキャン: {}",
+        if scannable { "有効" } else { "無効" },
+        if crate::settings::ACTIVE_SCAN { "有効" } else { "無効" });
+    let adv_mode = Cell::new(AdvMode::from_settings(use_rpa));
+    if crate::settings::ADV_EXTENDED && use_rpa {
+        info!("拡張広告は RPA の切り替えに対応しないため通常の広告にします");
+    }
+    info!("広告方式: {}", adv_mode.get().as_str());
 
     let _ = join(runner.run_with_handler(&handler), async {
-        // 広告をEnable維持
-        let ad = build_advertisement_data(&mut ad_buf, payload);
-        info!("BLE送信開始 len={}", ad.len());
         let mut params = AdvertisementParameters::default();
         // 送信頻度: 3秒に1回（min/maxともに3秒）
         params.interval_min = Duration::from_millis(3000);
         params.interval_max = Duration::from_millis(3000);
-        let _advertiser = match peripheral
-            .advertise(
-                &params,
-                Advertisement::NonconnectableNonscannableUndirected { adv_data: ad },
-            )
-            .await
-        {
-            Ok(h) => h,
+        let mut cfg = ScanConfig::default();
+        // アクティブスキャンなら相手のスキャン応答（短縮名・ニックネーム）も受け取る
+        cfg.active = crate::settings::ACTIVE_SCAN;
+        cfg.interval = Duration::from_millis(200);
+        cfg.window = Duration::from_millis(150);
+        cfg.timeout = Duration::from_millis(0);
+        let mut last_pulse = Instant::now();
+
+        // 広告の TX 電力をコントローラから読み、筐体などの補正値を足して広告に載せる
+        let tx_power = match stack.command(bt_hci::cmd::le::LeReadAdvPhysicalChannelTxPower::new()).await {
+            Ok(dbm) => dbm.saturating_add(crate::settings::TX_POWER_OFFSET_DB),
             Err(_) => {
-                info!("advertise() failed; entering error blink loop");
-                crate::leds::error_blink_loop(control).await;
+                info!("TX電力の取得失敗（0dBm として広告）");
+                crate::settings::TX_POWER_OFFSET_DB
             }
         };
+        info!("TX電力 = {}dBm", tx_power);
 
-        // スキャン再始動ポンプと送信インジケータのパルスを並列実行
-        let scan_pump = async {
-            let mut cfg = ScanConfig::default();
-            cfg.active = false; // passive
-            cfg.interval = Duration::from_millis(200);
-            cfg.window = Duration::from_millis(150);
-            cfg.timeout = Duration::from_millis(0);
-            let mut last_pulse = Instant::now();
-            loop {
-                // 保存件数が 1000 件を超えたらエラーブリンク
-                if crate::storage::total_saved() > 1000 {
-                    crate::leds::error_blink_loop(control).await;
+        // エポックごとにペイロード（と RPA）を作り直して広告し直す（どちらも使わない場合は1回だけ）。
+        // ID 交換で接続した・された後も広告し直す（接続で広告が止まるため）
+        let mut adv_epoch: Option<u32> = None;
+        loop {
+            let epoch = current_epoch(unsynced_nonce);
+            // 一時IDとアドレスを同時に切り替える（片方だけ変わると前後を結び付けられるため）
+            if use_rpa && adv_epoch.is_some_and(|e| e != epoch) {
+                // 広告・スキャン停止後にアドレスを変更する
+                Timer::after(Duration::from_millis(50)).await;
+                let rpa = resolvable_private_address(&irk, RoscRng.next_u32());
+                match stack.command(bt_hci::cmd::le::LeSetRandomAddr::new(bt_hci::param::BdAddr::new(rpa))).await {
+                    Ok(_) => {
+                        OWN_ADDR.lock(|c| c.set(rpa));
+                        info!("BLEアドレス変更 = {:?}", Address::random(rpa));
+                    }
+                    Err(_) => info!("BLEアドレス変更失敗（前のアドレスのまま）"),
                 }
-                let session = match scanner.scan(&cfg).await {
-                    Ok(s) => s,
-                    Err(_) => {
+            }
+            adv_epoch = Some(epoch);
+            let mode = adv_mode.get();
+            let mut adv_payload = [0u8; MAX_EXTENDED_PAYLOAD];
+            let (payload_len, id) = build_self_payload(&mut adv_payload, &self_bd_addr, epoch, mode, tx_power);
+            let _ = server.set(&server.exchange.own_id, &id);
+            let _ = server.set(&server.info.device_id, &id);
+            let id_str = fmt_bytes_colon(&id);
+            // スキャン応答の短縮名は広告中の ID から作る（一時IDと一緒に変わる）
+            let scan_data: &[u8] = if scannable {
+                let n = build_scan_response(&mut scan_buf, &id, crate::settings::ADV_NICKNAME);
+                &scan_buf[..n]
+            } else {
+                &[]
+            };
+            let advertised = if mode.is_extended() {
+                // 拡張広告はスキャン応答と接続を両立できないため、名前は広告データに含める
+                let mut len = build_advertisement_data(&mut ext_ad_buf, &adv_payload[..payload_len], tx_power).len();
+                ext_ad_buf[len..len + scan_data.len()].copy_from_slice(scan_data);
+                len += scan_data.len();
+                let ad = &ext_ad_buf[..len];
+                info!("BLE送信開始（{}） len={} id={} epoch={}", mode.as_str(), ad.len(), id_str.as_str(), epoch);
+                let phy = if mode == AdvMode::ExtendedCoded { PhyKind::LeCoded } else { PhyKind::Le1M };
+                let sets = [AdvertisementSet {
+                    params: AdvertisementParameters { primary_phy: phy, secondary_phy: phy, ..params },
+                    data: if connectable {
+                        Advertisement::ExtConnectableNonscannableUndirected { adv_data: ad }
+                    } else {
+                        Advertisement::ExtNonconnectableNonscannableUndirected { anonymous: false, adv_data: ad }
+                    },
+                }];
+                let mut handles = AdvertisementSet::handles(&sets);
+                peripheral.advertise_ext(&sets, &mut handles).await
+            } else {
+                let ad = build_advertisement_data(&mut ad_buf, &adv_payload[..payload_len], tx_power);
+                info!("BLE送信開始 len={} id={} epoch={}", ad.len(), id_str.as_str(), epoch);
+                let adv = if connectable {
+                    Advertisement::ConnectableScannableUndirected { adv_data: ad, scan_data }
+                } else if scannable {
+                    Advertisement::NonconnectableScannableUndirected { adv_data: ad, scan_data }
+                } else {
+                    Advertisement::NonconnectableNonscannableUndirected { adv_data: ad }
+                };
+                peripheral.advertise(&params, adv).await
+            };
+            // 広告をEnable維持（次のエポックまで、または接続されるまで）
+            let advertiser = match advertised {
+                Ok(h) => h,
+                Err(_) if mode.is_extended() => {
+                    // コントローラが拡張広告（または Coded PHY）を受け付けない
+                    adv_mode.set(mode.fallback());
+                    info!("{}を開始できません。{}にします", mode.as_str(), adv_mode.get().as_str());
+                    continue;
+                }
+                Err(_) => {
+                    info!("advertise() failed; entering error blink loop");
+                    crate::leds::error_blink_loop(&mut *control.lock().await).await;
+                }
+            };
+            cfg.phys = if mode == AdvMode::ExtendedCoded { PhySet::M1Coded } else { PhySet::M1 };
+            // 接続の受け付け（ID 交換か情報・設定サービスを使う場合のみ）
+            let accept = async {
+                if connectable {
+                    advertiser.accept().await.ok()
+                } else {
+                    core::future::pending().await
+                }
+            };
+
+            // スキャン再始動ポンプと送信インジケータのパルスを並列実行
+            let pump = async {
+                loop {
+                    // 保存件数が 1000 件を超えたらエラーブリンク
+                    if crate::storage::total_saved() > 1000 {
+                        crate::leds::error_blink_loop(&mut *control.lock().await).await;
+                    }
+                    // イベント処理に譲る（拡張広告の方式では拡張スキャン。通常の広告も受信できる）
+                    let scanned = if mode.is_extended() {
+                        match scanner.scan_ext(&cfg).await {
+                            Ok(session) => {
+                                Timer::after(Duration::from_millis(20)).await;
+                                core::mem::drop(session);
+                                true
+                            }
+                            Err(_) => false,
+                        }
+                    } else {
+                        match scanner.scan(&cfg).await {
+                            Ok(session) => {
+                                Timer::after(Duration::from_millis(20)).await;
+                                core::mem::drop(session);
+                                true
+                            }
+                            Err(_) => false,
+                        }
+                    };
+                    if !scanned && mode.is_extended() {
+                        // コントローラが拡張スキャン（または Coded PHY）を受け付けない
+                        adv_mode.set(mode.fallback());
+                        info!("{}の拡張スキャンを開始できません。{}にします", mode.as_str(), adv_mode.get().as_str());
+                        break None;
+                    }
+                    if !scanned {
                         info!("scan() failed; entering error blink loop");
-                        crate::leds::error_blink_loop(control).await;
+                        crate::leds::error_blink_loop(&mut *control.lock().await).await;
                     }
-                };
-                // イベント処理に譲る
-                Timer::after(Duration::from_millis(20)).await;
-                core::mem::drop(session);
-                // 過剰なHCIを避けるため小休止
-                Timer::after(Duration::from_millis(5)).await;
-                // 受信インジケータ（高速点滅）
-                if RX_PULSES.load(Ordering::Relaxed) > 0 {
-                    let v = RX_PULSES.load(Ordering::Relaxed);
-                    if v > 0 { RX_PULSES.store(v - 1, Ordering::Relaxed); }
-                    crate::leds::blink_rx_fast(control).await;
+                    // 過剰なHCIを避けるため小休止
+                    Timer::after(Duration::from_millis(5)).await;
+                    // 受信インジケータ（高速点滅）
+                    if RX_PULSES.load(Ordering::Relaxed) > 0 {
+                        let v = RX_PULSES.load(Ordering::Relaxed);
+                        if v > 0 { RX_PULSES.store(v - 1, Ordering::Relaxed); }
+                        crate::leds::blink_rx_fast(&mut *control.lock().await).await;
+                    }
+                    // 送信インジケータ（100ms点灯を1秒周期）
+                    if Instant::now() - last_pulse >= Duration::from_millis(1000) {
+                        crate::leds::blink_tx(&mut *control.lock().await, 100).await;
+                        last_pulse = Instant::now();
+                    }
+                    // ID 交換の相手が見つかったら（スキャン停止中に）接続する
+                    if let Ok(target) = EXCHANGE_QUEUE.try_receive() {
+                        break Some(target);
+                    }
+                    // エポックが変わったら（時刻同期を含む）RPA と一時ID（使う場合）を切り替える。どちらも固定なら広告し直さない
+                    if (use_rpa || id_rotates) && current_epoch(unsynced_nonce) != epoch {
+                        break None;
+                    }
+                }
+            };
+
+            match select(accept, pump).await {
+                Either::First(Some(conn)) => {
+                    info!("接続されました");
+                    serve_connection(&server, conn).await;
                 }
-                // 送信インジケータ（100ms点灯を1秒周期）
-                if Instant::now() - last_pulse >= Duration::from_millis(1000) {
-                    crate::leds::blink_tx(control, 100).await;
-                    last_pulse = Instant::now();
+                Either::First(None) => info!("接続の受け付け失敗"),
+                Either::Second(Some(target)) => {
+                    let s = fmt_bytes_colon(&target.id);
+                    info!("ID 交換: 接続します id={}", s.as_str());
+                    let mut central = scanner.into_inner();
+                    if let Err(e) = initiate_exchange(&stack, &mut central, &target, id).await {
+                        info!("ID 交換失敗 id={}: {}", s.as_str(), e);
+                    }
+                    scanner = Scanner::new(central);
                 }
+                Either::Second(None) => {}
             }
-        };
-        scan_pump.await;
+        }
     }).await;
 
     // 終了しない
@@ -0,0 +1,126 @@
+//! BLE ランダムアドレスの生成（Core Spec Vol 6 Part B 1.3.2）
+//! - 静的ランダムアドレス: BD_ADDR から端末ごとに決まる（最上位2ビット=11）
+//! - RPA（Resolvable Private Address）: IRK と乱数 prand から作る（最上位2ビット=01）。IRK を知る相手だけが解決できる
+//! - バイト順は trouble-host の `Address::random` と同じリトルエンディアン（[5] が最上位）
+
+use aes::cipher::{BlockEncrypt, KeyInit};
+use aes::Aes128;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+/// 静的ランダムアドレスの導出に使うラベル
+const STATIC_ADDR_LABEL: &[u8] = b"PicoStreet-SRA";
+/// IRK の導出に使うラベル
+const IRK_LABEL: &[u8] = b"PicoStreet-IRK";
+
+/// BD_ADDR から端末ごとの静的ランダムアドレスを導出する。
+/// SHA-256("PicoStreet-SRA" || bd_addr) の先頭6バイトの最上位2ビットを 11 にしたもの。
+pub fn static_random_address(bd_addr: &[u8; 6]) -> [u8; 6] {
+    let digest = Sha256::new().chain_update(STATIC_ADDR_LABEL).chain_update(bd_addr).finalize();
+    let mut addr = [0u8; 6];
+    addr.copy_from_slice(&digest[..6]);
+    addr[5] |= 0xC0;
+    // ランダム部（46ビット）が全0・全1のアドレスは使えない
+    if addr[..5].iter().all(|&b| b == 0x00) && addr[5] == 0xC0 {
+        addr[0] = 0x01;
+    }
+    if addr.iter().all(|&b| b == 0xFF) {
+        addr[0] = 0xFE;
+    }
+    addr
+}
+
+/// RPA を使うか。一時ID（`privacy_key` が空でない）を広告する場合は設定によらず RPA にする
+/// （静的アドレスのままだと、切り替わる一時IDがアドレスで結び付けられてしまうため）。
+pub fn use_rpa(ble_rpa: bool, privacy_key: &[u8]) -> bool {
+    ble_rpa || !privacy_key.is_empty()
+}
+
+/// 秘密鍵と BD_ADDR から端末ごとの IRK（16バイト、最上位バイトが先頭）を導出する。
+/// HMAC-SHA256(secret, "PicoStreet-IRK" || bd_addr) の先頭16バイト（全端末で同じ鍵でも IRK は端末ごとに変わる）。
+pub fn derive_irk(secret: &[u8], bd_addr: &[u8; 6]) -> [u8; 16] {
+    // HMAC は任意長の鍵を受け付けるため失敗しない
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(secret).expect("HMAC key");
+    mac.update(IRK_LABEL);
+    mac.update(bd_addr);
+    let tag = mac.finalize().into_bytes();
+    let mut irk = [0u8; 16];
+    irk.copy_from_slice(&tag[..16]);
+    irk
+}
+
+/// RPA を作る。`random` の下位22ビットを prand の乱数部に使う。
+pub fn resolvable_private_address(irk: &[u8; 16], random: u32) -> [u8; 6] {
+    let mut prand = (random & 0x3F_FFFF) | 0x40_0000;
+    // 乱数部（22ビット）が全0・全1の prand は使えない
+    if prand == 0x40_0000 || prand == 0x7F_FFFF {
+        prand ^= 0x01;
+    }
+    let hash = ah(irk, prand);
+    let h = hash.to_le_bytes();
+    let p = prand.to_le_bytes();
+    [h[0], h[1], h[2], p[0], p[1], p[2]]
+}
+
+/// RPA が `irk` で解決できるか（この端末のアドレスか）を判定する。
+pub fn resolve(irk: &[u8; 16], addr: &[u8; 6]) -> bool {
+    if addr[5] & 0xC0 != 0x40 {
+        return false;
+    }
+    let hash = u32::from_le_bytes([addr[0], addr[1], addr[2], 0]);
+    let prand = u32::from_le_bytes([addr[3], addr[4], addr[5], 0]);
+    ah(irk, prand) == hash
+}
+
+/// ランダムアドレスのハッシュ関数 ah(k, r) = e(k, 0^104 || r) mod 2^24
+fn ah(irk: &[u8; 16], prand: u32) -> u32 {
+    let cipher = Aes128::new(irk.into());
+    let mut block = [0u8; 16];
+    block[13..16].copy_from_slice(&prand.to_be_bytes()[1..4]);
+    let mut block = block.into();
+    cipher.encrypt_block(&mut block);
+    u32::from_be_bytes([0, block[13], block[14], block[15]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn ephemeral_ids_force_rpa() {
+        assert!(!use_rpa(false, b""));
+        assert!(use_rpa(true, b""));
+        assert!(use_rpa(false, b"device-secret"));
+    }
+
+    #[test]
+    fn ah_matches_core_spec_sample() {
+        // Core Spec Vol 3 Part H D.7
+        let irk = [
+            0xEC, 0x02, 0x34, 0xA3, 0x57, 0xC8, 0xAD, 0x05, 0x34, 0x10, 0x10, 0xA6, 0x0A, 0x39, 0x7D, 0x9B,
+        ];
+        assert_eq!(ah(&irk, 0x70_8194), 0x0D_FBAA);
+    }
+
+    #[test]
+    fn static_address_is_per_device_and_marked() {
+        let a = static_random_address(&[0x28, 0xCD, 0xC1, 0x15, 0x26, 0x11]);
+        let b = static_random_address(&[0x28, 0xCD, 0xC1, 0x15, 0x26, 0x12]);
+        assert_ne!(a, b);
+        assert_eq!(a[5] & 0xC0, 0xC0);
+        assert_eq!(a, static_random_address(&[0x28, 0xCD, 0xC1, 0x15, 0x26, 0x11]));
+    }
+
+    #[test]
+    fn rpa_resolves_only_with_own_irk() {
+        // 同じ鍵でも端末（BD_ADDR）が違えば IRK は別
+        let irk = derive_irk(b"fleet-secret", &[0x28, 0xCD, 0xC1, 0x15, 0x26, 0x01]);
+        let other = derive_irk(b"fleet-secret", &[0x28, 0xCD, 0xC1, 0x15, 0x26, 0x02]);
+        let addr = resolvable_private_address(&irk, 0x1234_5678);
+        assert_eq!(addr[5] & 0xC0, 0x40);
+        assert!(resolve(&irk, &addr));
+        assert!(!resolve(&other, &addr));
+        assert_ne!(addr, resolvable_private_address(&irk, 0x1234_5679));
+    }
+}
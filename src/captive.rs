@@ -0,0 +1,271 @@
+//! 設定ポータル（ソフトAP）用の最小 DHCP サーバ / DNS 応答
+//! - DHCP: DISCOVER に OFFER、REQUEST に ACK を返す。端末 MAC ごとに固定のアドレスを貸し出す
+//! - DNS: どの名前の A 問い合わせにも AP 自身のアドレスを返す（OS のキャプティブポータル検出をフォームへ誘導）
+//! - AP のパスワードは端末ごとに BD_ADDR と秘密値から導出する（全台共通のパスワードにしない）
+
+use core::fmt::Write as _;
+
+use heapless::String;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// 同時に貸し出せるアドレス数（AP アドレス +1 から連番）
+pub const MAX_LEASES: usize = 8;
+/// リース時間（秒）
+const LEASE_SECS: u32 = 3600;
+
+const DHCP_MAGIC: [u8; 4] = [99, 130, 83, 99];
+const BOOTP_LEN: usize = 236;
+const DHCP_DISCOVER: u8 = 1;
+const DHCP_OFFER: u8 = 2;
+const DHCP_REQUEST: u8 = 3;
+const DHCP_ACK: u8 = 5;
+const DHCP_NAK: u8 = 6;
+
+/// 応答バッファに必要な最小サイズ
+pub const DHCP_REPLY_MAX: usize = BOOTP_LEN + 4 + 40;
+
+/// AP 用の最小 DHCP サーバ（/24 固定）
+pub struct DhcpServer {
+    server_ip: [u8; 4],
+    leases: [Option<[u8; 6]>; MAX_LEASES],
+}
+
+impl DhcpServer {
+    pub const fn new(server_ip: [u8; 4]) -> Self {
+        Self { server_ip, leases: [None; MAX_LEASES] }
+    }
+
+    /// クライアントからのパケットを処理し、応答を `out` に書いて長さを返す。
+    /// 応答不要（対象外のメッセージ・満杯）なら None。応答はブロードキャスト（ポート68）で送る。
+    pub fn handle(&mut self, req: &[u8], out: &mut [u8]) -> Option<usize> {
+        if req.len() < BOOTP_LEN + 4 || req[0] != 1 || req[1] != 1 || req[2] != 6 || req[236..240] != DHCP_MAGIC {
+            return None;
+        }
+        if out.len() < DHCP_REPLY_MAX {
+            return None;
+        }
+        let mut chaddr = [0u8; 6];
+        chaddr.copy_from_slice(&req[28..34]);
+        let options = &req[240..];
+        let msg_type = find_option(options, 53)?.first().copied()?;
+
+        let reply_type = match msg_type {
+            DHCP_DISCOVER => DHCP_OFFER,
+            DHCP_REQUEST => {
+                // 他のサーバ宛て、または貸していないアドレスの要求は NAK
+                if find_option(options, 54).is_some_and(|id| id != self.server_ip) {
+                    return None;
+                }
+                match find_option(options, 50) {
+                    Some(ip) if Some(ip) != self.lease_ip(&chaddr).as_ref().map(|a| &a[..]) => DHCP_NAK,
+                    _ => DHCP_ACK,
+                }
+            }
+            _ => return None,
+        };
+        let yiaddr = if reply_type == DHCP_NAK { [0; 4] } else { self.lease_ip(&chaddr)? };
+
+        out[..BOOTP_LEN].fill(0);
+        out[0] = 2; // BOOTREPLY
+        out[1] = 1;
+        out[2] = 6;
+        out[4..8].copy_from_slice(&req[4..8]); // xid
+        out[10..12].copy_from_slice(&req[10..12]); // flags
+        out[16..20].copy_from_slice(&yiaddr);
+        out[20..24].copy_from_slice(&self.server_ip);
+        out[28..44].copy_from_slice(&req[28..44]); // chaddr
+        out[236..240].copy_from_slice(&DHCP_MAGIC);
+
+        let ip = self.server_ip;
+        let mut n = 240;
+        let mut opt = |code: u8, val: &[u8]| {
+            out[n] = code;
+            out[n + 1] = val.len() as u8;
+            out[n + 2..n + 2 + val.len()].copy_from_slice(val);
+            n += 2 + val.len();
+        };
+        opt(53, &[reply_type]);
+        opt(54, &ip);
+        if reply_type != DHCP_NAK {
+            opt(51, &LEASE_SECS.to_be_bytes());
+            opt(1, &[255, 255, 255, 0]);
+            opt(3, &ip);
+            opt(6, &ip);
+        }
+        out[n] = 255;
+        Some(n + 1)
+    }
+
+    /// 端末に割り当てるアドレス（未登録なら空きを割り当てる。満杯なら None）
+    fn lease_ip(&mut self, chaddr: &[u8; 6]) -> Option<[u8; 4]> {
+        let idx = match self.leases.iter().position(|l| l.as_ref() == Some(chaddr)) {
+            Some(i) => i,
+            None => {
+                let i = self.leases.iter().position(|l| l.is_none())?;
+                self.leases[i] = Some(*chaddr);
+                i
+            }
+        };
+        let mut ip = self.server_ip;
+        ip[3] = ip[3].wrapping_add(1 + idx as u8);
+        Some(ip)
+    }
+}
+
+/// DHCP オプション領域から `code` の値を探す
+fn find_option(mut opts: &[u8], code: u8) -> Option<&[u8]> {
+    while let [c, rest @ ..] = opts {
+        match *c {
+            0 => opts = rest,
+            255 => return None,
+            _ => {
+                let (&len, rest) = rest.split_first()?;
+                let val = rest.get(..len as usize)?;
+                if *c == code {
+                    return Some(val);
+                }
+                opts = &rest[len as usize..];
+            }
+        }
+    }
+    None
+}
+
+/// DNS 問い合わせに対する応答を `out` に書いて長さを返す。
+/// A レコードの問い合わせには `ip` を返し、それ以外は回答なしで応答する。
+pub fn dns_reply(query: &[u8], ip: [u8; 4], out: &mut [u8]) -> Option<usize> {
+    if query.len() < 12 || query[2] & 0x80 != 0 || u16::from_be_bytes([query[4], query[5]]) != 1 {
+        return None;
+    }
+    // 問い合わせ名（ラベル列）の終わりを探す
+    let mut i = 12;
+    loop {
+        let len = *query.get(i)? as usize;
+        if len == 0 {
+            break;
+        }
+        if len & 0xC0 != 0 {
+            return None;
+        }
+        i += 1 + len;
+    }
+    let q_end = i + 5; // 終端0 + QTYPE(2) + QCLASS(2)
+    let question = query.get(12..q_end)?;
+    let is_a = question[question.len() - 4..] == [0, 1, 0, 1];
+    let len = q_end + if is_a { 16 } else { 0 };
+    if out.len() < len {
+        return None;
+    }
+
+    out[0..2].copy_from_slice(&query[0..2]); // ID
+    out[2] = 0x84 | (query[2] & 0x01); // QR, AA, RD をそのまま
+    out[3] = 0x80; // RA, RCODE=0
+    out[4..6].copy_from_slice(&[0, 1]);
+    out[6..8].copy_from_slice(&[0, is_a as u8]);
+    out[8..12].fill(0);
+    out[12..q_end].copy_from_slice(question);
+    if is_a {
+        let a = &mut out[q_end..len];
+        a[0..2].copy_from_slice(&[0xC0, 0x0C]); // 問い合わせ名へのポインタ
+        a[2..6].copy_from_slice(&[0, 1, 0, 1]);
+        a[6..10].copy_from_slice(&60u32.to_be_bytes());
+        a[10..12].copy_from_slice(&[0, 4]);
+        a[12..16].copy_from_slice(&ip);
+    }
+    Some(len)
+}
+
+/// ポータル AP のパスワード長（16進16文字 = 64ビット）
+pub const PORTAL_PSK_LEN: usize = 16;
+
+/// 端末ごとのポータル AP パスワード。`HMAC-SHA256(secret, "PicoStreet-AP" || BD_ADDR)` の先頭8バイトを16進にしたもの。
+/// 同じファームを書いた端末でも BD_ADDR ごとに異なり、secret を知っていれば手元で計算できる。
+pub fn portal_psk(secret: &[u8], bd_addr: &[u8; 6]) -> String<PORTAL_PSK_LEN> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(b"PicoStreet-AP");
+    mac.update(bd_addr);
+    let digest = mac.finalize().into_bytes();
+    let mut psk = String::new();
+    for b in &digest[..PORTAL_PSK_LEN / 2] {
+        let _ = write!(psk, "{:02x}", b);
+    }
+    psk
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const AP: [u8; 4] = [192, 168, 4, 1];
+
+    fn dhcp_msg(mac: u8, msg_type: u8, extra: &[u8]) -> std::vec::Vec<u8> {
+        let mut m = vec![0u8; BOOTP_LEN];
+        m[0] = 1;
+        m[1] = 1;
+        m[2] = 6;
+        m[4..8].copy_from_slice(&[1, 2, 3, 4]);
+        m[28..34].copy_from_slice(&[2, 0, 0, 0, 0, mac]);
+        m.extend_from_slice(&DHCP_MAGIC);
+        m.extend_from_slice(&[53, 1, msg_type]);
+        m.extend_from_slice(extra);
+        m.push(255);
+        m
+    }
+
+    #[test]
+    fn offers_then_acks_same_address() {
+        let mut server = DhcpServer::new(AP);
+        let mut out = [0u8; DHCP_REPLY_MAX];
+
+        let n = server.handle(&dhcp_msg(7, DHCP_DISCOVER, &[]), &mut out).unwrap();
+        assert_eq!(&out[4..8], &[1, 2, 3, 4]);
+        assert_eq!(&out[16..20], &[192, 168, 4, 2]);
+        assert_eq!(find_option(&out[240..n], 53), Some(&[DHCP_OFFER][..]));
+        assert_eq!(find_option(&out[240..n], 6), Some(&AP[..]));
+
+        let req = dhcp_msg(7, DHCP_REQUEST, &[50, 4, 192, 168, 4, 2, 54, 4, 192, 168, 4, 1]);
+        let n = server.handle(&req, &mut out).unwrap();
+        assert_eq!(find_option(&out[240..n], 53), Some(&[DHCP_ACK][..]));
+        assert_eq!(&out[16..20], &[192, 168, 4, 2]);
+
+        // 別の端末には次のアドレス、知らないアドレスの要求には NAK
+        server.handle(&dhcp_msg(8, DHCP_DISCOVER, &[]), &mut out).unwrap();
+        assert_eq!(&out[16..20], &[192, 168, 4, 3]);
+        let n = server.handle(&dhcp_msg(9, DHCP_REQUEST, &[50, 4, 10, 0, 0, 5]), &mut out).unwrap();
+        assert_eq!(find_option(&out[240..n], 53), Some(&[DHCP_NAK][..]));
+    }
+
+    #[test]
+    fn answers_any_a_query_with_ap_address() {
+        // ID=0x1234, RD, QDCOUNT=1, "captive.apple.com" A IN
+        let mut q = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in ["captive", "apple", "com"] {
+            q.push(label.len() as u8);
+            q.extend_from_slice(label.as_bytes());
+        }
+        q.extend_from_slice(&[0, 0, 1, 0, 1]);
+        let mut out = [0u8; 128];
+        let n = dns_reply(&q, AP, &mut out).unwrap();
+        assert_eq!(n, q.len() + 16);
+        assert_eq!(&out[0..4], &[0x12, 0x34, 0x85, 0x80]);
+        assert_eq!(&out[6..8], &[0, 1]);
+        assert_eq!(&out[n - 4..n], &AP);
+
+        // AAAA には回答なし
+        let len = q.len();
+        q[len - 3] = 28;
+        let n = dns_reply(&q, AP, &mut out).unwrap();
+        assert_eq!(n, q.len());
+        assert_eq!(&out[6..8], &[0, 0]);
+    }
+
+    #[test]
+    fn portal_psk_is_per_device() {
+        let a = portal_psk(b"fleet-secret", &[0x28, 0xCD, 0xC1, 0x15, 0x26, 0x01]);
+        assert_eq!(a.as_str(), "b8f9d7195fae1f3e");
+        assert_ne!(portal_psk(b"fleet-secret", &[0x28, 0xCD, 0xC1, 0x15, 0x26, 0x02]), a);
+        assert_ne!(portal_psk(b"other-secret", &[0x28, 0xCD, 0xC1, 0x15, 0x26, 0x01]), a);
+    }
+}
@@ -0,0 +1,156 @@
+//! 実行時の設定（動作モード・WiFi / API 接続先）
+//! - 起動時に `load` で1回だけフラッシュから読み込み、以降は `get()` で参照する
+//! - 保存されていなければ settings.rs の値を既定値として使う
+//! - 古い形式で保存されていた場合は現在の形式へ移行して書き戻す
+//! - 変更は再起動後に反映される（設定ポータルは `store`、通常動作中は `save` でフラッシュ書き込みタスク経由）
+use core::cell::RefCell;
+
+use defmt::*;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::once_lock::OnceLock;
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration};
+use heapless::String;
+use pico_w_id_beacon::config_record::{self, ConfigRecord, CONFIG_VERSION};
+
+use crate::settings;
+use crate::storage::FlashDev;
+
+/// 設定領域（フラッシュ先頭からのオフセット）。ログ領域（storage）の直後、STORAGE の末尾 16KB。
+pub const CONFIG_REGION_OFFSET: u32 = 0x1F_C000;
+
+static CONFIG: OnceLock<ConfigRecord> = OnceLock::new();
+
+/// 書き込みタスクへ渡す保存待ちの設定
+static PENDING: Mutex<CriticalSectionRawMutex, RefCell<Option<ConfigRecord>>> = Mutex::new(RefCell::new(None));
+/// 保存結果（true=成功）
+static SAVED: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+/// 通常動作中に保存した最新の設定（再起動後に反映。USB コンソールと BLE で共有）
+static STAGED: Mutex<CriticalSectionRawMutex, RefCell<Option<ConfigRecord>>> = Mutex::new(RefCell::new(None));
+
+/// settings_example.rs の SSID の例（書き換えずにビルドした場合は未設定として扱う）
+const EXAMPLE_WIFI_SSID: &str = "AP_NAME";
+
+/// settings.rs の値から作る既定の設定
+fn defaults() -> ConfigRecord {
+    ConfigRecord {
+        developer_mode: settings::DEVELOPER_MODE,
+        wifi_ssid: if settings::WIFI_SSID == EXAMPLE_WIFI_SSID { String::new() } else { truncated(settings::WIFI_SSID) },
+        wifi_psk: truncated(settings::WIFI_PSK),
+        api_host: truncated(settings::API_HOST),
+        api_port: settings::API_PORT,
+        api_path: truncated(settings::API_PATH),
+        api_tls: settings::API_TLS,
+        api_ca: 0,
+        // 署名鍵は端末ごとに USB コンソールで書き込む（ファームウェアには持たせない）
+        api_key: Default::default(),
+    }
+}
+
+/// フラッシュから設定を読み込む。起動時に1回だけ、他のモジュールより先に呼ぶ（ログ領域のマウント前）。
+pub fn load(flash: &mut FlashDev) {
+    let defaults = defaults();
+    let rec = match config_record::load(flash, CONFIG_REGION_OFFSET, &defaults) {
+        Ok(Some(stored)) => {
+            if stored.version < CONFIG_VERSION {
+                info!("設定を v{} から v{} へ移行します", stored.version, CONFIG_VERSION);
+                if let Err(e) = store(flash, &stored.record) {
+                    warn!("移行した設定の保存に失敗: {}", defmt::Debug2Format(&e));
+                }
+            }
+            info!("保存済み設定を使用");
+            stored.record
+        }
+        Ok(None) => {
+            info!("保存済み設定なし（settings.rs の値を使用）");
+            defaults
+        }
+        Err(e) => {
+            warn!("設定の読み込み失敗（settings.rs の値を使用）: {}", defmt::Debug2Format(&e));
+            defaults
+        }
+    };
+    info!(
+        "設定: dev={} SSID='{}' API={}://{}:{}{} (ca={}) 署名鍵={}",
+        rec.developer_mode, rec.wifi_ssid.as_str(), if rec.api_tls { "https" } else { "http" },
+        rec.api_host.as_str(), rec.api_port, rec.api_path.as_str(), rec.api_ca,
+        if rec.api_key.is_empty() { "未設定" } else { "設定済み" }
+    );
+    let _ = CONFIG.init(rec);
+}
+
+/// 設定を保存する（反映は再起動後）。
+pub fn store(flash: &mut FlashDev, rec: &ConfigRecord) -> Result<(), embassy_rp::flash::Error> {
+    config_record::store(flash, CONFIG_REGION_OFFSET, rec)
+}
+
+/// 通常動作中に設定を保存する（フラッシュ書き込みタスク経由。反映は再起動後）。
+pub async fn save(rec: ConfigRecord) -> Result<(), &'static str> {
+    PENDING.lock(|p| *p.borrow_mut() = Some(rec.clone()));
+    SAVED.reset();
+    if !crate::storage::request_config_save() {
+        return Err("フラッシュ書き込みキュー満杯");
+    }
+    match with_timeout(Duration::from_secs(5), SAVED.wait()).await {
+        Ok(true) => {
+            STAGED.lock(|s| *s.borrow_mut() = Some(rec));
+            Ok(())
+        }
+        Ok(false) => Err("フラッシュ書き込み失敗"),
+        Err(_) => Err("フラッシュ書き込みタスク応答なし"),
+    }
+}
+
+/// 保存待ちの設定を書き込む（flash_writer_task から呼ばれる）
+pub fn write_pending(flash: &mut FlashDev) {
+    let Some(rec) = PENDING.lock(|p| p.borrow_mut().take()) else { return };
+    let ok = match store(flash, &rec) {
+        Ok(()) => {
+            info!("設定を保存しました（再起動後に反映）");
+            true
+        }
+        Err(e) => {
+            warn!("設定の保存に失敗: {}", defmt::Debug2Format(&e));
+            false
+        }
+    };
+    SAVED.signal(ok);
+}
+
+/// 現在の設定（`load` 済みであること）
+pub fn get() -> &'static ConfigRecord {
+    CONFIG.try_get().expect("config::load 未実行")
+}
+
+/// 通常動作中に保存した設定（再起動後に反映）。保存していなければ None。
+pub fn staged() -> Option<ConfigRecord> {
+    STAGED.lock(|s| s.borrow().clone())
+}
+
+/// 変更の起点にする設定（保存済みで未反映の設定があればそれ、無ければ現在の設定）
+pub fn latest() -> ConfigRecord {
+    staged().unwrap_or_else(|| get().clone())
+}
+
+/// デベロッパーモードかどうか
+#[inline]
+pub fn is_developer_mode() -> bool {
+    get().developer_mode
+}
+
+/// 接続先の WiFi が設定済みかどうか（未設定なら設定ポータルを起動する）
+pub fn is_configured() -> bool {
+    !get().wifi_ssid.is_empty() || !settings::WIFI_NETWORKS.is_empty()
+}
+
+/// 長すぎる値は切り詰める（settings.rs の定数は通常収まる）
+fn truncated<const N: usize>(s: &str) -> String<N> {
+    let mut out = String::new();
+    for c in s.chars() {
+        if out.push(c).is_err() {
+            break;
+        }
+    }
+    out
+}
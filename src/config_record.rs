@@ -0,0 +1,456 @@
+//! フラッシュ上の設定レコード（動作モード・WiFi / API 接続先）
+//! - 2セクタを交互に使い、世代番号の大きい有効な方を採用（書き込み途中の電源断でも旧設定が残る）
+//! - スロット: [magic(4)][version(2)][body_len(2)][gen(4)][body][crc32(4)]（crc は先頭から body 末尾まで）
+//! - body: 長さ1バイト前置の文字列と LE の数値を並べたもの（version ごとに定義）
+//!   - v1: ssid, psk, host, port, path（設定ポータルの初版）
+//!   - v2: developer_mode(1) + v1 と同じ並び
+//!   - v3: v2 + api_tls(1) + api_ca(1)（HTTPS の有無と、組み込みの CA 一覧から使う証明書の番号）
+//!   - v4: v3 + api_key（長さ1バイト前置、API 署名用の端末ごとの鍵。USB コンソールで書き込む）
+//! - 古い version は読み込み時に現在の形式へ移行する（増えた項目は既定値で埋める）
+
+use embedded_storage::nor_flash::NorFlash;
+use heapless::{String, Vec};
+
+use crate::crc::crc32;
+
+const CONFIG_MAGIC: u32 = 0x4746_4350; // "PCFG"
+/// 現在のレコード形式
+pub const CONFIG_VERSION: u16 = 4;
+const HEADER_LEN: usize = 12;
+/// 1スロットの書き込みサイズ（WRITE_SIZE の倍数になるよう固定長）
+const SLOT_LEN: usize = 512;
+/// 使用するセクタ数（A/B の2スロット）
+pub const CONFIG_SECTORS: u32 = 2;
+
+/// 保存される設定
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConfigRecord {
+    /// デベロッパーモード（30秒毎送信）
+    pub developer_mode: bool,
+    pub wifi_ssid: String<32>,
+    pub wifi_psk: String<64>,
+    pub api_host: String<64>,
+    pub api_port: u16,
+    pub api_path: String<64>,
+    /// HTTPS で送信するか
+    pub api_tls: bool,
+    /// サーバ証明書の検証に使う CA（ファームウェアに組み込んだ CA 一覧の番号）
+    pub api_ca: u8,
+    /// API 署名用の端末ごとの鍵（空なら署名しない）
+    pub api_key: ApiKey,
+}
+
+/// API 署名用の鍵（HMAC-SHA256、最大32バイト）。ログに出さないよう Debug では中身を表示しない。
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct ApiKey(Vec<u8, 32>);
+
+impl ApiKey {
+    /// 16進文字列（64文字 = 32バイト）から作る
+    pub fn from_hex(s: &str) -> Option<Self> {
+        if s.len() != 64 {
+            return None;
+        }
+        let mut key = Vec::new();
+        for pair in s.as_bytes().chunks(2) {
+            let hi = (pair[0] as char).to_digit(16)?;
+            let lo = (pair[1] as char).to_digit(16)?;
+            key.push((hi << 4 | lo) as u8).ok()?;
+        }
+        Some(Self(key))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl core::fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(if self.is_empty() { "ApiKey(未設定)" } else { "ApiKey(設定済み)" })
+    }
+}
+
+/// API への接続方法（設定と組み込みの CA 一覧から決める）
+#[derive(Debug, PartialEq, Eq)]
+pub enum ApiTransport<'a> {
+    /// 平文 HTTP
+    Plain,
+    /// HTTPS。`ca`（DER）でサーバ証明書を検証し、`server_name` を SNI とホスト名検証に使う
+    Tls { ca: &'a [u8], server_name: &'a str },
+}
+
+/// フラッシュから読み込んだ設定
+#[derive(Debug, PartialEq, Eq)]
+pub struct Stored {
+    pub record: ConfigRecord,
+    /// 保存されていた形式（CONFIG_VERSION 未満なら移行済みの内容を書き戻すとよい）
+    pub version: u16,
+}
+
+impl ConfigRecord {
+    /// body（現在の形式）をエンコードして長さを返す
+    fn encode_body(&self, out: &mut [u8]) -> usize {
+        let mut w = Writer { buf: out, pos: 0 };
+        w.bytes(&[self.developer_mode as u8]);
+        w.str(&self.wifi_ssid);
+        w.str(&self.wifi_psk);
+        w.str(&self.api_host);
+        w.bytes(&self.api_port.to_le_bytes());
+        w.str(&self.api_path);
+        w.bytes(&[self.api_tls as u8, self.api_ca]);
+        w.bytes(&[self.api_key.0.len() as u8]);
+        w.bytes(&self.api_key.0);
+        w.pos
+    }
+
+    /// body をデコードする。古い形式に無い項目は `defaults` で埋める。未知の形式は None。
+    fn decode_body(version: u16, body: &[u8], defaults: &ConfigRecord) -> Option<Self> {
+        let mut r = Reader { buf: body, pos: 0 };
+        let developer_mode = match version {
+            1 => defaults.developer_mode,
+            2..=4 => r.array::<1>()?[0] != 0,
+            _ => return None,
+        };
+        let mut rec = Self {
+            developer_mode,
+            wifi_ssid: r.str()?,
+            wifi_psk: r.str()?,
+            api_host: r.str()?,
+            api_port: u16::from_le_bytes(r.array()?),
+            api_path: r.str()?,
+            api_tls: defaults.api_tls,
+            api_ca: defaults.api_ca,
+            api_key: defaults.api_key.clone(),
+        };
+        if version >= 3 {
+            let [tls, ca] = r.array::<2>()?;
+            rec.api_tls = tls != 0;
+            rec.api_ca = ca;
+        }
+        if version >= 4 {
+            let len = *r.take(1)?.first()? as usize;
+            rec.api_key = ApiKey(Vec::from_slice(r.take(len)?).ok()?);
+        }
+        if version == 1 {
+            // v1 の空欄は「settings.rs の値を使う」の意味だった
+            if rec.api_host.is_empty() {
+                rec.api_host = defaults.api_host.clone();
+                rec.api_port = defaults.api_port;
+            }
+            if rec.api_path.is_empty() {
+                rec.api_path = defaults.api_path.clone();
+            }
+        }
+        Some(rec)
+    }
+}
+
+impl ConfigRecord {
+    /// API への接続方法を決める。`cas` はファームウェアに組み込んだ CA 証明書（DER）の一覧。
+    /// HTTPS なのに CA が選べない場合は平文に落とさずエラーにする。
+    pub fn api_transport<'a>(&'a self, cas: &[&'a [u8]]) -> Result<ApiTransport<'a>, &'static str> {
+        if !self.api_tls {
+            return Ok(ApiTransport::Plain);
+        }
+        let ca = cas.get(self.api_ca as usize).copied().filter(|ca| !ca.is_empty()).ok_or("tls ca missing")?;
+        if self.api_host.is_empty() {
+            return Err("tls host missing");
+        }
+        Ok(ApiTransport::Tls { ca, server_name: self.api_host.as_str() })
+    }
+}
+
+/// 設定領域 `[base, base + CONFIG_SECTORS * ERASE_SIZE)` から最新の有効な設定を読む。
+/// 保存されていない（または未知の形式の）場合は None。
+pub fn load<F: NorFlash>(flash: &mut F, base: u32, defaults: &ConfigRecord) -> Result<Option<Stored>, F::Error> {
+    let Some(slot) = latest_slot(flash, base)? else { return Ok(None) };
+    let body = &slot.buf[HEADER_LEN..HEADER_LEN + slot.body_len];
+    Ok(ConfigRecord::decode_body(slot.version, body, defaults).map(|record| Stored { record, version: slot.version }))
+}
+
+/// 設定を保存する（古い方のスロットを消去して書き込む）。
+pub fn store<F: NorFlash>(flash: &mut F, base: u32, rec: &ConfigRecord) -> Result<(), F::Error> {
+    let (slot, gen) = match latest_slot(flash, base)? {
+        Some(latest) => ((latest.index + 1) % CONFIG_SECTORS, latest.gen.wrapping_add(1)),
+        None => (0, 1),
+    };
+    let mut buf = [0xFFu8; SLOT_LEN];
+    let body_len = rec.encode_body(&mut buf[HEADER_LEN..SLOT_LEN - 4]);
+    buf[0..4].copy_from_slice(&CONFIG_MAGIC.to_le_bytes());
+    buf[4..6].copy_from_slice(&CONFIG_VERSION.to_le_bytes());
+    buf[6..8].copy_from_slice(&(body_len as u16).to_le_bytes());
+    buf[8..12].copy_from_slice(&gen.to_le_bytes());
+    let end = HEADER_LEN + body_len;
+    let crc = crc32(&buf[..end]);
+    buf[end..end + 4].copy_from_slice(&crc.to_le_bytes());
+
+    let addr = base + slot * F::ERASE_SIZE as u32;
+    flash.erase(addr, addr + F::ERASE_SIZE as u32)?;
+    flash.write(addr, &buf)
+}
+
+/// CRC が一致したスロット
+struct Slot {
+    index: u32,
+    gen: u32,
+    version: u16,
+    body_len: usize,
+    buf: [u8; SLOT_LEN],
+}
+
+/// 有効なスロットのうち世代番号が最大のもの
+fn latest_slot<F: NorFlash>(flash: &mut F, base: u32) -> Result<Option<Slot>, F::Error> {
+    let mut best: Option<Slot> = None;
+    for index in 0..CONFIG_SECTORS {
+        let mut buf = [0u8; SLOT_LEN];
+        flash.read(base + index * F::ERASE_SIZE as u32, &mut buf)?;
+        if u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) != CONFIG_MAGIC {
+            continue;
+        }
+        let version = u16::from_le_bytes([buf[4], buf[5]]);
+        let body_len = u16::from_le_bytes([buf[6], buf[7]]) as usize;
+        let gen = u32::from_le_bytes([buf[8], buf[9], buf[10], buf[11]]);
+        let end = HEADER_LEN + body_len;
+        if end + 4 > SLOT_LEN {
+            continue;
+        }
+        let crc = u32::from_le_bytes([buf[end], buf[end + 1], buf[end + 2], buf[end + 3]]);
+        if crc != crc32(&buf[..end]) {
+            continue;
+        }
+        // 世代番号は 1 始まりの連番なので、2スロット間の比較は差で判定する
+        if best.as_ref().is_none_or(|b| (gen.wrapping_sub(b.gen) as i32) > 0) {
+            best = Some(Slot { index, gen, version, body_len, buf });
+        }
+    }
+    Ok(best)
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, b: &[u8]) {
+        self.buf[self.pos..self.pos + b.len()].copy_from_slice(b);
+        self.pos += b.len();
+    }
+
+    fn str(&mut self, s: &str) {
+        self.bytes(&[s.len() as u8]);
+        self.bytes(s.as_bytes());
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> Option<&[u8]> {
+        let b = self.buf.get(self.pos..self.pos + n)?;
+        self.pos += n;
+        Some(b)
+    }
+
+    fn array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        self.take(N)?.try_into().ok()
+    }
+
+    fn str<const N: usize>(&mut self) -> Option<String<N>> {
+        let len = *self.take(1)?.first()? as usize;
+        let s = core::str::from_utf8(self.take(len)?).ok()?;
+        String::try_from(s).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_storage::nor_flash::{ErrorType, NorFlashError, NorFlashErrorKind, ReadNorFlash};
+    use pretty_assertions::assert_eq;
+
+    const SECTOR: usize = 4096;
+
+    #[derive(Debug)]
+    struct RamError;
+    impl NorFlashError for RamError {
+        fn kind(&self) -> NorFlashErrorKind {
+            NorFlashErrorKind::Other
+        }
+    }
+
+    struct RamFlash {
+        mem: std::vec::Vec<u8>,
+    }
+    impl ErrorType for RamFlash {
+        type Error = RamError;
+    }
+    impl ReadNorFlash for RamFlash {
+        const READ_SIZE: usize = 1;
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), RamError> {
+            let o = offset as usize;
+            bytes.copy_from_slice(&self.mem[o..o + bytes.len()]);
+            Ok(())
+        }
+        fn capacity(&self) -> usize {
+            self.mem.len()
+        }
+    }
+    impl NorFlash for RamFlash {
+        const WRITE_SIZE: usize = 1;
+        const ERASE_SIZE: usize = SECTOR;
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), RamError> {
+            self.mem[from as usize..to as usize].fill(0xFF);
+            Ok(())
+        }
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), RamError> {
+            let o = offset as usize;
+            for (d, s) in self.mem[o..o + bytes.len()].iter_mut().zip(bytes) {
+                *d &= *s;
+            }
+            Ok(())
+        }
+    }
+
+    fn sample(ssid: &str) -> ConfigRecord {
+        ConfigRecord {
+            developer_mode: false,
+            wifi_ssid: String::try_from(ssid).unwrap(),
+            wifi_psk: String::try_from("password").unwrap(),
+            api_host: String::try_from("example.com").unwrap(),
+            api_port: 443,
+            api_path: String::try_from("/api/encounters").unwrap(),
+            api_tls: true,
+            api_ca: 1,
+            api_key: ApiKey::from_hex(&"a5".repeat(32)).unwrap(),
+        }
+    }
+
+    #[test]
+    fn empty_region_has_no_config() {
+        let mut flash = RamFlash { mem: vec![0xFF; SECTOR * 2] };
+        assert_eq!(load(&mut flash, 0, &ConfigRecord::default()).unwrap(), None);
+    }
+
+    #[test]
+    fn latest_store_wins_and_alternates_slots() {
+        let mut flash = RamFlash { mem: vec![0xFF; SECTOR * 2] };
+        store(&mut flash, 0, &sample("home")).unwrap();
+        store(&mut flash, 0, &sample("school")).unwrap();
+        assert_eq!(load(&mut flash, 0, &ConfigRecord::default()).unwrap().unwrap().record, sample("school"));
+        // 2回目は別スロットに書かれている（1回目が残っている）
+        assert_eq!(&flash.mem[0..4], &CONFIG_MAGIC.to_le_bytes());
+        assert_eq!(&flash.mem[SECTOR..SECTOR + 4], &CONFIG_MAGIC.to_le_bytes());
+    }
+
+    #[test]
+    fn torn_write_falls_back_to_previous() {
+        let mut flash = RamFlash { mem: vec![0xFF; SECTOR * 2] };
+        store(&mut flash, 0, &sample("home")).unwrap();
+        store(&mut flash, 0, &sample("school")).unwrap();
+        // 2回目の書き込み途中で電源断したことにする
+        flash.mem[SECTOR + 20] ^= 0xFF;
+        assert_eq!(load(&mut flash, 0, &ConfigRecord::default()).unwrap().unwrap().record, sample("home"));
+    }
+
+    #[test]
+    fn migrates_v1_record_with_defaults() {
+        let mut flash = RamFlash { mem: vec![0xFF; SECTOR * 2] };
+        // v1 形式（developer_mode なし、API 項目は空欄）を直接書く
+        let mut body = std::vec::Vec::new();
+        for s in ["home", "password", ""] {
+            body.push(s.len() as u8);
+            body.extend_from_slice(s.as_bytes());
+        }
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.push(0);
+        let mut slot = std::vec::Vec::new();
+        slot.extend_from_slice(&CONFIG_MAGIC.to_le_bytes());
+        slot.extend_from_slice(&1u16.to_le_bytes());
+        slot.extend_from_slice(&(body.len() as u16).to_le_bytes());
+        slot.extend_from_slice(&1u32.to_le_bytes());
+        slot.extend_from_slice(&body);
+        let crc = crc32(&slot);
+        slot.extend_from_slice(&crc.to_le_bytes());
+        flash.mem[..slot.len()].copy_from_slice(&slot);
+
+        let defaults = ConfigRecord { developer_mode: true, api_tls: false, api_ca: 0, api_key: ApiKey::default(), ..sample("default") };
+        let stored = load(&mut flash, 0, &defaults).unwrap().unwrap();
+        assert_eq!(stored.version, 1);
+        assert_eq!(
+            stored.record,
+            ConfigRecord { developer_mode: true, api_tls: false, api_ca: 0, api_key: ApiKey::default(), ..sample("home") }
+        );
+
+        // 書き戻すと現在の形式で読める
+        store(&mut flash, 0, &stored.record).unwrap();
+        let again = load(&mut flash, 0, &ConfigRecord::default()).unwrap().unwrap();
+        assert_eq!(again.version, CONFIG_VERSION);
+        assert_eq!(again.record, stored.record);
+    }
+
+    #[test]
+    fn tls_settings_round_trip_and_migrate_from_v2() {
+        let mut flash = RamFlash { mem: vec![0xFF; SECTOR * 2] };
+        store(&mut flash, 0, &sample("home")).unwrap();
+        let stored = load(&mut flash, 0, &ConfigRecord::default()).unwrap().unwrap();
+        assert_eq!((stored.record.api_tls, stored.record.api_ca), (true, 1));
+
+        // v2（TLS 項目なし）は既定値で埋める
+        let mut v2 = sample("home");
+        v2.api_tls = false;
+        let mut body = [0u8; SLOT_LEN];
+        let len = v2.encode_body(&mut body) - 2 - 33;
+        let defaults = ConfigRecord { api_tls: true, api_ca: 2, ..ConfigRecord::default() };
+        let rec = ConfigRecord::decode_body(2, &body[..len], &defaults).unwrap();
+        assert_eq!((rec.api_tls, rec.api_ca), (true, 2));
+        assert_eq!(rec.wifi_ssid.as_str(), "home");
+    }
+
+    #[test]
+    fn api_key_round_trips_and_migrates_from_v3_as_unset() {
+        let mut flash = RamFlash { mem: vec![0xFF; SECTOR * 2] };
+        store(&mut flash, 0, &sample("home")).unwrap();
+        let stored = load(&mut flash, 0, &ConfigRecord::default()).unwrap().unwrap();
+        assert_eq!(stored.record.api_key.as_bytes(), &[0xA5; 32]);
+
+        // v3（鍵なし）は未設定のまま（全台共通の既定値は持たない）
+        let mut body = [0u8; SLOT_LEN];
+        let len = sample("home").encode_body(&mut body) - 33;
+        let rec = ConfigRecord::decode_body(3, &body[..len], &ConfigRecord::default()).unwrap();
+        assert!(rec.api_key.is_empty());
+        assert_eq!((rec.api_tls, rec.api_ca), (true, 1));
+    }
+
+    #[test]
+    fn api_key_is_hex_and_never_printed() {
+        assert_eq!(ApiKey::from_hex(&"0F".repeat(32)).unwrap().as_bytes(), &[0x0F; 32]);
+        assert_eq!(ApiKey::from_hex(&"0f".repeat(31)), None);
+        assert_eq!(ApiKey::from_hex(&"zz".repeat(32)), None);
+        let shown = format!("{:?}", sample("home"));
+        assert!(shown.contains("ApiKey(設定済み)"));
+        assert!(!shown.contains("165"), "{shown}");
+    }
+
+    #[test]
+    fn api_transport_requires_a_ca_for_https() {
+        const CA0: &[u8] = &[0x30, 0x82, 0x01];
+        const CA1: &[u8] = &[0x30, 0x82, 0x02];
+        let cas: &[&[u8]] = &[CA0, CA1];
+        let rec = sample("home");
+        assert_eq!(rec.api_transport(cas), Ok(ApiTransport::Tls { ca: CA1, server_name: "example.com" }));
+        assert_eq!(ConfigRecord { api_tls: false, ..sample("home") }.api_transport(&[]), Ok(ApiTransport::Plain));
+        // 範囲外・空の CA は平文に落とさずエラー
+        assert_eq!(ConfigRecord { api_ca: 2, ..sample("home") }.api_transport(cas), Err("tls ca missing"));
+        assert_eq!(ConfigRecord { api_ca: 0, ..sample("home") }.api_transport(&[&[]]), Err("tls ca missing"));
+        let no_host = ConfigRecord { api_host: String::new(), ..sample("home") };
+        assert_eq!(no_host.api_transport(cas), Err("tls host missing"));
+    }
+}
@@ -0,0 +1,307 @@
+//! USB シリアルコンソールのコマンド解析（1行 = 1コマンド）
+//! - 入力は `LineBuffer` で行にまとめる（BS/DEL で1文字削除、CR/LF で確定）
+//! - `parse` で `Command` に変換し、`apply_config` で `config set` を設定レコードへ反映する
+
+use heapless::Vec;
+
+use crate::config_record::{ApiKey, ConfigRecord};
+
+/// コマンド一覧（help の表示用）
+pub const HELP: &str = "\
+status                  状態（時刻・WiFi・保存件数）
+log dump                すれ違いログを表示
+log clear               すれ違いログを全消去
+export                  すれ違いログをバイナリで出力（tools/export 用）
+config get              設定を表示
+config set <key> <val>  設定を保存（再起動後に反映）
+                        key: dev ssid psk host port path tls ca key
+                        （key は API 署名鍵の16進64文字、clear で消去。表示はしない）
+time                    現在時刻
+wifi scan               周囲のWiFiを表示
+upload now              すぐにAPIへ送信
+reboot                  再起動
+";
+
+/// コンソールコマンド
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command<'a> {
+    Help,
+    Status,
+    LogDump,
+    LogClear,
+    /// ホスト側ツール向けのバイナリ出力（export_frame）
+    Export,
+    ConfigGet,
+    ConfigSet { key: ConfigKey, value: &'a str },
+    Time,
+    WifiScan,
+    UploadNow,
+    Reboot,
+}
+
+/// `config set` で変更できる項目
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigKey {
+    Dev,
+    Ssid,
+    Psk,
+    Host,
+    Port,
+    Path,
+    /// HTTPS の有無（on / off）
+    Tls,
+    /// 組み込みの CA 一覧の番号
+    Ca,
+    /// API 署名用の端末ごとの鍵（16進）。USB コンソールでのみ設定できる
+    Key,
+}
+
+impl ConfigKey {
+    fn from_str(s: &str) -> Option<Self> {
+        Some(match s {
+            "dev" => Self::Dev,
+            "ssid" => Self::Ssid,
+            "psk" => Self::Psk,
+            "host" => Self::Host,
+            "port" => Self::Port,
+            "path" => Self::Path,
+            "tls" => Self::Tls,
+            "ca" => Self::Ca,
+            "key" => Self::Key,
+            _ => return None,
+        })
+    }
+}
+
+/// 解析エラー
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// 空行（何もしない）
+    Empty,
+    /// 未知のコマンド
+    Unknown,
+    /// 引数が足りない
+    MissingArg,
+    /// config set の key が不正
+    BadKey,
+}
+
+/// 1行をコマンドに変換する。`config set` の値は key 以降の残り全体（空白を含んでよい）。
+pub fn parse(line: &str) -> Result<Command<'_>, ParseError> {
+    let line = line.trim();
+    let (cmd, rest) = split_word(line);
+    let (sub, arg) = split_word(rest);
+    match (cmd, sub) {
+        ("", _) => Err(ParseError::Empty),
+        ("help" | "?", _) => Ok(Command::Help),
+        ("status", _) => Ok(Command::Status),
+        ("log", "dump") => Ok(Command::LogDump),
+        ("log", "clear") => Ok(Command::LogClear),
+        ("export", _) => Ok(Command::Export),
+        ("config", "get") => Ok(Command::ConfigGet),
+        ("config", "set") => {
+            let (key, value) = split_word(arg);
+            if key.is_empty() {
+                return Err(ParseError::MissingArg);
+            }
+            let key = ConfigKey::from_str(key).ok_or(ParseError::BadKey)?;
+            Ok(Command::ConfigSet { key, value })
+        }
+        ("time", _) => Ok(Command::Time),
+        ("wifi", "scan") => Ok(Command::WifiScan),
+        ("upload", "now") => Ok(Command::UploadNow),
+        ("reboot", _) => Ok(Command::Reboot),
+        ("log" | "config" | "wifi" | "upload", "") => Err(ParseError::MissingArg),
+        _ => Err(ParseError::Unknown),
+    }
+}
+
+/// `config set` の値を検証して設定レコードに反映する。
+pub fn apply_config(rec: &mut ConfigRecord, key: ConfigKey, value: &str) -> Result<(), &'static str> {
+    match key {
+        ConfigKey::Dev => {
+            rec.developer_mode = match value {
+                "on" | "true" | "1" => true,
+                "off" | "false" | "0" => false,
+                _ => return Err("dev は on / off"),
+            }
+        }
+        ConfigKey::Ssid => {
+            if value.is_empty() {
+                return Err("ssid が空");
+            }
+            rec.wifi_ssid = value.try_into().map_err(|_| "ssid は32バイトまで")?;
+        }
+        ConfigKey::Psk => {
+            if !(8..=63).contains(&value.len()) {
+                return Err("psk は8〜63文字");
+            }
+            rec.wifi_psk = value.try_into().map_err(|_| "psk が長すぎます")?;
+        }
+        ConfigKey::Host => {
+            if value.is_empty() {
+                return Err("host が空");
+            }
+            rec.api_host = value.try_into().map_err(|_| "host は64バイトまで")?;
+        }
+        ConfigKey::Port => {
+            rec.api_port = value.parse::<u16>().ok().filter(|&p| p != 0).ok_or("port は1〜65535")?;
+        }
+        ConfigKey::Path => {
+            if !value.starts_with('/') {
+                return Err("path は / で始める");
+            }
+            rec.api_path = value.try_into().map_err(|_| "path は64バイトまで")?;
+        }
+        ConfigKey::Tls => {
+            rec.api_tls = match value {
+                "on" | "true" | "1" => true,
+                "off" | "false" | "0" => false,
+                _ => return Err("tls は on / off"),
+            }
+        }
+        ConfigKey::Ca => {
+            rec.api_ca = value.parse::<u8>().map_err(|_| "ca は0〜255の番号")?;
+        }
+        ConfigKey::Key => {
+            rec.api_key = match value {
+                "clear" => ApiKey::default(),
+                _ => ApiKey::from_hex(value).ok_or("key は16進64文字（32バイト）")?,
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 受信バイト列を行にまとめるバッファ
+pub struct LineBuffer<const N: usize> {
+    buf: Vec<u8, N>,
+}
+
+/// `LineBuffer::feed` の結果
+#[derive(Debug, PartialEq, Eq)]
+pub enum LineEvent {
+    /// 何もしない（CR LF の LF など）
+    None,
+    /// 1文字追加された（エコーする）
+    Echo(u8),
+    /// 1文字削除された
+    Erase,
+    /// 行が確定した（`line()` で取り出し、`clear()` する）
+    Line,
+}
+
+impl<const N: usize> Default for LineBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> LineBuffer<N> {
+    pub const fn new() -> Self {
+        Self { buf: Vec::new() }
+    }
+
+    /// 1バイト受け取る。行が長すぎる場合は超えた分を捨てる。
+    pub fn feed(&mut self, b: u8) -> LineEvent {
+        match b {
+            b'\r' | b'\n' => {
+                if b == b'\n' && self.buf.is_empty() {
+                    LineEvent::None
+                } else {
+                    LineEvent::Line
+                }
+            }
+            0x08 | 0x7F => {
+                if self.buf.pop().is_some() {
+                    LineEvent::Erase
+                } else {
+                    LineEvent::None
+                }
+            }
+            0x20..=0x7E | 0x80..=0xFF => match self.buf.push(b) {
+                Ok(()) => LineEvent::Echo(b),
+                Err(_) => LineEvent::None,
+            },
+            _ => LineEvent::None,
+        }
+    }
+
+    /// 確定した行（UTF-8 でなければ空）
+    pub fn line(&self) -> &str {
+        core::str::from_utf8(&self.buf).unwrap_or("")
+    }
+
+    pub fn clear(&mut self) {
+        self.buf.clear();
+    }
+}
+
+/// 先頭の1語と残り（先頭の空白を除く）に分ける
+fn split_word(s: &str) -> (&str, &str) {
+    match s.find(' ') {
+        Some(i) => (&s[..i], s[i + 1..].trim_start()),
+        None => (s, ""),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse("  status "), Ok(Command::Status));
+        assert_eq!(parse("log dump"), Ok(Command::LogDump));
+        assert_eq!(parse("export"), Ok(Command::Export));
+        assert_eq!(parse("config set ssid My Home AP"), Ok(Command::ConfigSet { key: ConfigKey::Ssid, value: "My Home AP" }));
+        assert_eq!(parse("config set"), Err(ParseError::MissingArg));
+        assert_eq!(parse("config set color red"), Err(ParseError::BadKey));
+        assert_eq!(parse("log"), Err(ParseError::MissingArg));
+        assert_eq!(parse("format c:"), Err(ParseError::Unknown));
+        assert_eq!(parse(""), Err(ParseError::Empty));
+    }
+
+    #[test]
+    fn applies_and_validates_config() {
+        let mut rec = ConfigRecord::default();
+        apply_config(&mut rec, ConfigKey::Dev, "on").unwrap();
+        apply_config(&mut rec, ConfigKey::Port, "8443").unwrap();
+        apply_config(&mut rec, ConfigKey::Path, "/api/encounters").unwrap();
+        assert!(rec.developer_mode);
+        assert_eq!(rec.api_port, 8443);
+        assert_eq!(rec.api_path.as_str(), "/api/encounters");
+        assert!(apply_config(&mut rec, ConfigKey::Port, "0").is_err());
+        assert!(apply_config(&mut rec, ConfigKey::Psk, "short").is_err());
+        assert!(apply_config(&mut rec, ConfigKey::Path, "api").is_err());
+        assert_eq!(rec.api_port, 8443);
+        apply_config(&mut rec, ConfigKey::Tls, "on").unwrap();
+        apply_config(&mut rec, ConfigKey::Ca, "1").unwrap();
+        assert_eq!((rec.api_tls, rec.api_ca), (true, 1));
+        assert!(apply_config(&mut rec, ConfigKey::Tls, "maybe").is_err());
+        assert!(apply_config(&mut rec, ConfigKey::Ca, "-1").is_err());
+
+        let hex = "00112233445566778899aabbccddeeff00112233445566778899AABBCCDDEEFF";
+        assert_eq!(parse(&["config set key ", hex].concat()), Ok(Command::ConfigSet { key: ConfigKey::Key, value: hex }));
+        apply_config(&mut rec, ConfigKey::Key, hex).unwrap();
+        assert_eq!(rec.api_key.as_bytes()[..3], [0x00, 0x11, 0x22]);
+        assert!(apply_config(&mut rec, ConfigKey::Key, &hex[..62]).is_err());
+        assert!(apply_config(&mut rec, ConfigKey::Key, "secret").is_err());
+        assert_eq!(rec.api_key.as_bytes().len(), 32);
+        apply_config(&mut rec, ConfigKey::Key, "clear").unwrap();
+        assert!(rec.api_key.is_empty());
+    }
+
+    #[test]
+    fn line_buffer_handles_backspace_and_crlf() {
+        let mut lb: LineBuffer<16> = LineBuffer::new();
+        for &b in b"timx\x7fe" {
+            lb.feed(b);
+        }
+        assert_eq!(lb.feed(b'\r'), LineEvent::Line);
+        assert_eq!(lb.line(), "time");
+        lb.clear();
+        assert_eq!(lb.feed(b'\n'), LineEvent::None);
+    }
+}
@@ -0,0 +1,38 @@
+//! CRC-32（IEEE 802.3, 反転多項式 0xEDB88320）
+//! テーブルを持たないビット単位実装（フラッシュ/RAM節約のため）
+
+/// `data` の CRC-32 を返す。
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+/// 既存の CRC 値 `crc` に `data` を追加した CRC-32 を返す（分割計算用）。
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut c = !crc;
+    for &b in data {
+        c ^= b as u32;
+        for _ in 0..8 {
+            let mask = (c & 1).wrapping_neg();
+            c = (c >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !c
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_vector() {
+        // "123456789" の CRC-32 は 0xCBF43926
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn incremental_matches_oneshot() {
+        let c = crc32_update(crc32(b"1234"), b"56789");
+        assert_eq!(c, crc32(b"123456789"));
+    }
+}
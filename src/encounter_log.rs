@@ -0,0 +1,47 @@
+//! すれ違いログ1件（相手ごとの集計行）。storage が保持し、API 送信・コンソール・エクスポートで使う
+//! - 集計は広告中の ID ごと。相手が一時ID（v2 / TLV 0x02）なら ID が epoch ごとに変わるため、
+//!   同じ相手でも epoch（10分）ごとに別の行になる（端末単位にまとめるのはサーバ側）
+
+use crate::adv_payload::Nickname;
+use crate::peer_stats::PeerStats;
+use crate::proximity::{estimate_distance_cm, Proximity};
+
+/// すれ違いログ1件（相手の ID ごとの集計）
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EncounterLog {
+    /// 相手の ID（`ephemeral` なら一時ID、そうでなければ BD_ADDR）
+    pub mac_addr: [u8; 6],
+    /// `mac_addr` が一時ID か（この行は1つの epoch 内の集計）
+    pub ephemeral: bool,
+    /// 最後に更新した検出の通し番号（送信確認カーソル・サーバ側の重複排除に使用）
+    pub seq: u32,
+    pub stats: PeerStats,
+    /// 相手の TX 電力（広告に載っていた場合）
+    pub tx_power: Option<i8>,
+    /// GATT 接続で ID を交換し、相手も自分を記録したことを確認済みか
+    pub mutual: bool,
+    /// 相手のニックネーム（TLV 広告またはスキャン応答に載っていた場合）
+    pub nickname: Option<Nickname>,
+}
+
+impl EncounterLog {
+    /// 最接近時（RSSI 最大）の推定距離（cm）。TX 電力が分からなければ None。
+    pub fn distance_cm(&self) -> Option<u32> {
+        estimate_distance_cm(self.tx_power, self.stats.rssi_max)
+    }
+
+    /// 最接近時の近さの区分
+    pub fn proximity(&self) -> Proximity {
+        Proximity::from_distance_cm(self.distance_cm())
+    }
+
+    /// API・エクスポートに載せる ID の種類（"bd_addr" / "ephemeral"）
+    pub fn id_kind(&self) -> &'static str {
+        id_kind(self.ephemeral)
+    }
+}
+
+/// ID の種類の表記（"bd_addr" / "ephemeral"）
+pub fn id_kind(ephemeral: bool) -> &'static str {
+    if ephemeral { "ephemeral" } else { "bd_addr" }
+}
@@ -0,0 +1,238 @@
+//! すれ違いとみなす条件（壁越しの弱い電波や一瞬の通過を除く）
+//! - 相手ごとに RSSI を指数移動平均で平滑化し、平滑化後の値で判定する（1パケットの揺れで結果が変わらない）
+//! - 確定条件: 平滑化 RSSI が `min_rssi` 以上の検出が直近 `window_secs` 秒に `min_sightings` 回以上あり、
+//!   かつ `min_rssi` 以上が `min_dwell_secs` 秒以上続いている
+//! - 閾値を下回ると連続はやり直し。`window_secs` 秒以上途切れた相手は平滑化からやり直す
+//! - 時刻は稼働時間（ミリ秒）で扱う（NTP 未同期でも判定できる）
+//! - 確定後も平滑化は続け、閾値以上の検出だけを記録する
+//! - 記録側が行を消したら（送信確認・全消去）`forget` / `reset` で確定済みを解除する（再び条件を満たしてから記録する）
+
+use heapless::{Deque, Vec};
+
+/// 追跡する相手の最大数（満杯時は最終検出が最も古い相手を追い出す）
+pub const MAX_TRACKED: usize = 32;
+
+/// `min_sightings` の上限（直近の検出時刻をこの件数だけ覚えておく）
+pub const MAX_MIN_SIGHTINGS: u8 = 8;
+
+/// 新しい RSSI を 1/2^SMOOTHING_SHIFT の割合で取り込む（1/4）
+const SMOOTHING_SHIFT: u32 = 2;
+/// 平滑化 RSSI の固定小数点の倍率
+const RSSI_SCALE: i32 = 16;
+
+/// すれ違いの判定条件
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EncounterRules {
+    /// 平滑化 RSSI の下限（dBm）
+    pub min_rssi: i8,
+    /// `window_secs` 内に必要な検出回数（1〜MAX_MIN_SIGHTINGS）
+    pub min_sightings: u8,
+    /// 検出回数を数える窓（秒）。これより長く途切れたら候補をやり直す
+    pub window_secs: u32,
+    /// 閾値以上が続く必要のある時間（秒）
+    pub min_dwell_secs: u32,
+}
+
+impl EncounterRules {
+    /// 条件なし（受信した検出をすべて記録する）
+    pub const ANY: Self = Self { min_rssi: i8::MIN, min_sightings: 1, window_secs: 60, min_dwell_secs: 0 };
+}
+
+/// `EncounterGate::observe` の判定結果
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// 記録する。`rssi` は平滑化後の値、`committed` は今回の検出ですれ違いが確定したか、
+    /// `since_ms` は閾値以上が続いている区間の開始時刻（確定した行の初回検出時刻に使う）
+    Record { rssi: i8, committed: bool, since_ms: u64 },
+    /// 条件を満たすまで候補のまま（記録しない）
+    Pending,
+    /// 平滑化 RSSI が閾値未満（記録しない）
+    Weak,
+}
+
+/// 追跡中の相手1台
+#[derive(Clone, Debug)]
+struct Tracked {
+    id: [u8; 6],
+    /// 平滑化 RSSI × RSSI_SCALE
+    smoothed: i32,
+    last_ms: u64,
+    /// 閾値以上が始まった時刻（下回っている間は None）
+    above_since: Option<u64>,
+    /// 閾値以上だった直近の検出時刻
+    recent: Deque<u64, { MAX_MIN_SIGHTINGS as usize }>,
+    /// すれ違いとして確定済みか
+    committed: bool,
+}
+
+impl Tracked {
+    fn new(id: [u8; 6], now_ms: u64, rssi: i8) -> Self {
+        Self {
+            id,
+            smoothed: rssi as i32 * RSSI_SCALE,
+            last_ms: now_ms,
+            above_since: None,
+            recent: Deque::new(),
+            committed: false,
+        }
+    }
+}
+
+/// 相手ごとの候補を追跡し、検出を記録するかどうかを判定する
+pub struct EncounterGate {
+    rules: EncounterRules,
+    tracked: Vec<Tracked, MAX_TRACKED>,
+}
+
+impl EncounterGate {
+    pub const fn new(rules: EncounterRules) -> Self {
+        Self { rules, tracked: Vec::new() }
+    }
+
+    pub fn rules(&self) -> &EncounterRules {
+        &self.rules
+    }
+
+    /// 確定待ちの相手の数
+    pub fn pending(&self) -> usize {
+        self.tracked.iter().filter(|t| !t.committed).count()
+    }
+
+    /// 相手の追跡をやめる（記録側で行を消した相手。次の検出から候補としてやり直す）
+    pub fn forget(&mut self, id: &[u8; 6]) {
+        self.tracked.retain(|t| t.id != *id);
+    }
+
+    /// すべての相手の追跡をやめる
+    pub fn reset(&mut self) {
+        self.tracked.clear();
+    }
+
+    /// 検出1回を判定する。`known` は記録済みの相手か（追跡表から追い出された後でも確定済みとして扱う）。
+    pub fn observe(&mut self, id: &[u8; 6], now_ms: u64, rssi: i8, known: bool) -> Verdict {
+        let rules = self.rules;
+        let window_ms = rules.window_secs as u64 * 1000;
+        let t = match self.tracked.iter().position(|t| t.id == *id) {
+            Some(i) => {
+                let t = &mut self.tracked[i];
+                if now_ms.saturating_sub(t.last_ms) > window_ms {
+                    // 長く途切れた相手は平滑化からやり直す（確定済みかどうかは残す）
+                    let committed = t.committed;
+                    *t = Tracked::new(*id, now_ms, rssi);
+                    t.committed = committed;
+                } else {
+                    t.smoothed += (rssi as i32 * RSSI_SCALE - t.smoothed) >> SMOOTHING_SHIFT;
+                }
+                t
+            }
+            None => {
+                if self.tracked.is_full() {
+                    if let Some((i, _)) = self.tracked.iter().enumerate().min_by_key(|(_, t)| t.last_ms) {
+                        let _ = self.tracked.swap_remove(i);
+                    }
+                }
+                let _ = self.tracked.push(Tracked::new(*id, now_ms, rssi));
+                let Some(t) = self.tracked.last_mut() else { return Verdict::Pending };
+                t
+            }
+        };
+        t.last_ms = now_ms;
+        t.committed |= known;
+
+        if t.smoothed < rules.min_rssi as i32 * RSSI_SCALE {
+            t.above_since = None;
+            t.recent.clear();
+            return Verdict::Weak;
+        }
+        let since = *t.above_since.get_or_insert(now_ms);
+        if t.recent.is_full() {
+            let _ = t.recent.pop_front();
+        }
+        let _ = t.recent.push_back(now_ms);
+        let rssi = (t.smoothed / RSSI_SCALE) as i8;
+        if t.committed {
+            return Verdict::Record { rssi, committed: false, since_ms: since };
+        }
+
+        let in_window = t.recent.iter().filter(|&&ms| now_ms - ms <= window_ms).count();
+        let needed = rules.min_sightings.clamp(1, MAX_MIN_SIGHTINGS) as usize;
+        if in_window >= needed && now_ms - since >= rules.min_dwell_secs as u64 * 1000 {
+            t.committed = true;
+            return Verdict::Record { rssi, committed: true, since_ms: since };
+        }
+        Verdict::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const RULES: EncounterRules = EncounterRules { min_rssi: -80, min_sightings: 3, window_secs: 30, min_dwell_secs: 10 };
+    const PEER: [u8; 6] = [0x28, 0xCD, 0xC1, 0x15, 0x26, 0x01];
+
+    #[test]
+    fn commits_after_sightings_and_dwell() {
+        let mut gate = EncounterGate::new(RULES);
+        // 回数は足りても滞在が短いうちは候補のまま
+        assert_eq!(gate.observe(&PEER, 0, -60, false), Verdict::Pending);
+        assert_eq!(gate.observe(&PEER, 2_000, -60, false), Verdict::Pending);
+        assert_eq!(gate.observe(&PEER, 4_000, -60, false), Verdict::Pending);
+        assert_eq!(gate.pending(), 1);
+        // 確定時は最初の検出からの区間を返す
+        assert_eq!(gate.observe(&PEER, 10_000, -60, false), Verdict::Record { rssi: -60, committed: true, since_ms: 0 });
+        // 確定後は毎回記録する
+        assert_eq!(gate.observe(&PEER, 11_000, -64, false), Verdict::Record { rssi: -61, committed: false, since_ms: 0 });
+        assert_eq!(gate.pending(), 0);
+    }
+
+    #[test]
+    fn weak_peer_is_rejected_and_spikes_are_smoothed() {
+        let mut gate = EncounterGate::new(RULES);
+        // 壁越し（-95dBm）に1回だけ強いパケットが混ざっても閾値を超えない
+        for (i, rssi) in [-95, -95, -60, -95, -95].into_iter().enumerate() {
+            assert_eq!(gate.observe(&PEER, i as u64 * 5_000, rssi, false), Verdict::Weak);
+        }
+        // 閾値を下回ると連続はやり直し（既知の相手でも弱い検出は記録しない）
+        assert_eq!(gate.observe(&PEER, 30_000, -95, true), Verdict::Weak);
+    }
+
+    #[test]
+    fn known_peer_and_gaps() {
+        let mut gate = EncounterGate::new(RULES);
+        // 記録済みの相手（再起動後など）は最初の検出から記録する
+        assert_eq!(gate.observe(&PEER, 0, -70, true), Verdict::Record { rssi: -70, committed: false, since_ms: 0 });
+
+        let other = [0x28, 0xCD, 0xC1, 0x15, 0x26, 0x02];
+        for ms in [0, 5_000, 8_000] {
+            assert_eq!(gate.observe(&other, ms, -70, false), Verdict::Pending);
+        }
+        // 窓より長く途切れると数え直し
+        assert_eq!(gate.observe(&other, 40_000, -70, false), Verdict::Pending);
+        assert_eq!(gate.observe(&other, 45_000, -70, false), Verdict::Pending);
+        assert_eq!(gate.observe(&other, 50_000, -70, false), Verdict::Record { rssi: -70, committed: true, since_ms: 40_000 });
+
+        let mut any = EncounterGate::new(EncounterRules::ANY);
+        assert_eq!(any.observe(&other, 0, -100, false), Verdict::Record { rssi: -100, committed: true, since_ms: 0 });
+    }
+
+    #[test]
+    fn forgotten_peer_must_qualify_again() {
+        let mut gate = EncounterGate::new(RULES);
+        for ms in [0, 5_000, 10_000] {
+            gate.observe(&PEER, ms, -60, false);
+        }
+        assert_eq!(gate.observe(&PEER, 12_000, -60, false), Verdict::Record { rssi: -60, committed: false, since_ms: 0 });
+        // 記録側で行を消したら確定済みを解除し、新しい区間で数え直す
+        gate.forget(&PEER);
+        assert_eq!(gate.observe(&PEER, 14_000, -60, false), Verdict::Pending);
+        assert_eq!(gate.pending(), 1);
+        for ms in [16_000, 20_000] {
+            assert_eq!(gate.observe(&PEER, ms, -60, false), Verdict::Pending);
+        }
+        assert_eq!(gate.observe(&PEER, 24_000, -60, false), Verdict::Record { rssi: -60, committed: true, since_ms: 14_000 });
+        gate.reset();
+        assert_eq!(gate.pending(), 0);
+    }
+}
@@ -0,0 +1,170 @@
+//! すれ違いの相互確認（GATT 接続での ID 交換）
+//! - 広告は一方向なので、A が B を記録していても B が A を記録しているとは限らない
+//! - 互いに見つけたら BLE アドレスの小さい方が接続（セントラル）し、相手の ID を読んで自分の ID を書き込む
+//! - 接続した側は読んだ ID、接続された側（ペリフェラル）は書き込まれた ID を「相互確認済み」として記録する
+//! - 同じ相手への接続は `RETRY_SECS` 秒に1回まで（失敗しても、相手が非対応でも繰り返し過ぎない）
+//! - 書き込まれた ID は、接続元のアドレスから直近 `PEER_FRESH_SECS` 秒以内に受信した広告の ID と一致するときだけ受け付ける
+//!   （任意の端末が任意の ID を書き込んで相互確認済みにできないようにする）
+
+use heapless::Vec;
+
+/// PicoStreet 交換サービス（表記順。GATT 上はリトルエンディアン）
+/// 8c3f0001-1d2b-4f5e-9a3c-50696f537472
+pub const EXCHANGE_SERVICE_UUID: [u8; 16] = [
+    0x8C, 0x3F, 0x00, 0x01, 0x1D, 0x2B, 0x4F, 0x5E, 0x9A, 0x3C, 0x50, 0x69, 0x6F, 0x53, 0x74, 0x72,
+];
+/// 自分の ID（読み出し専用）: 8c3f0002-…
+pub const EXCHANGE_OWN_ID_UUID: [u8; 16] = [
+    0x8C, 0x3F, 0x00, 0x02, 0x1D, 0x2B, 0x4F, 0x5E, 0x9A, 0x3C, 0x50, 0x69, 0x6F, 0x53, 0x74, 0x72,
+];
+/// 相手の ID（書き込み専用）: 8c3f0003-…
+pub const EXCHANGE_PEER_ID_UUID: [u8; 16] = [
+    0x8C, 0x3F, 0x00, 0x03, 0x1D, 0x2B, 0x4F, 0x5E, 0x9A, 0x3C, 0x50, 0x69, 0x6F, 0x53, 0x74, 0x72,
+];
+
+/// 同じ相手へ再び接続を試みるまでの間隔（秒）
+pub const RETRY_SECS: u64 = 600;
+
+/// 接続を試みた相手を覚えておく数
+pub const MAX_RECENT: usize = 16;
+
+/// 広告のアドレスと ID の対応を有効とみなす時間（秒）
+pub const PEER_FRESH_SECS: u64 = 60;
+
+/// 広告のアドレスと ID を覚えておく数
+pub const MAX_RECENT_PEERS: usize = 16;
+
+/// 表記順の UUID を GATT 上のバイト順（リトルエンディアン）にする
+pub const fn uuid_le(uuid: &[u8; 16]) -> [u8; 16] {
+    let mut out = [0u8; 16];
+    let mut i = 0;
+    while i < 16 {
+        out[i] = uuid[15 - i];
+        i += 1;
+    }
+    out
+}
+
+/// 自分が接続する側か（BLE アドレスの小さい方）。アドレスはリトルエンディアン（[5] が最上位）。
+pub fn should_initiate(own_addr: &[u8; 6], peer_addr: &[u8; 6]) -> bool {
+    own_addr.iter().rev().lt(peer_addr.iter().rev())
+}
+
+/// 最近接続を試みた相手（ID ごと）
+pub struct ExchangeCooldown {
+    recent: Vec<([u8; 6], u64), MAX_RECENT>,
+}
+
+impl ExchangeCooldown {
+    pub const fn new() -> Self {
+        Self { recent: Vec::new() }
+    }
+
+    /// 接続を試みてよければ記録して true。`RETRY_SECS` 以内に試みた相手なら false。
+    /// 満杯時は最も古い記録を追い出す。
+    pub fn try_start(&mut self, id: &[u8; 6], now_ms: u64) -> bool {
+        let retry_ms = RETRY_SECS * 1000;
+        if let Some(r) = self.recent.iter_mut().find(|(r, _)| r == id) {
+            if now_ms.saturating_sub(r.1) < retry_ms {
+                return false;
+            }
+            r.1 = now_ms;
+            return true;
+        }
+        if self.recent.is_full() {
+            if let Some((i, _)) = self.recent.iter().enumerate().min_by_key(|(_, (_, t))| *t) {
+                let _ = self.recent.swap_remove(i);
+            }
+        }
+        let _ = self.recent.push((*id, now_ms));
+        true
+    }
+}
+
+impl Default for ExchangeCooldown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 最近受信した PicoStreet の広告のアドレスと ID（スキャン応答・ID の書き込みの照合用）
+pub struct RecentPeers {
+    /// (アドレス, ID, 受信時刻 ms)
+    peers: Vec<([u8; 6], [u8; 6], u64), MAX_RECENT_PEERS>,
+}
+
+impl RecentPeers {
+    pub const fn new() -> Self {
+        Self { peers: Vec::new() }
+    }
+
+    /// 広告のアドレスと ID を覚える（満杯なら最も古いものを捨てる）
+    pub fn remember(&mut self, addr: &[u8; 6], id: &[u8; 6], now_ms: u64) {
+        if let Some(p) = self.peers.iter_mut().find(|(a, _, _)| a == addr) {
+            p.1 = *id;
+            p.2 = now_ms;
+            return;
+        }
+        if self.peers.is_full() {
+            if let Some((i, _)) = self.peers.iter().enumerate().min_by_key(|(_, (_, _, t))| *t) {
+                let _ = self.peers.swap_remove(i);
+            }
+        }
+        let _ = self.peers.push((*addr, *id, now_ms));
+    }
+
+    /// `addr` から直近 `PEER_FRESH_SECS` 秒以内に受信した広告の ID
+    pub fn id_of(&self, addr: &[u8; 6], now_ms: u64) -> Option<[u8; 6]> {
+        self.peers
+            .iter()
+            .find(|(a, _, t)| a == addr && now_ms.saturating_sub(*t) <= PEER_FRESH_SECS * 1000)
+            .map(|(_, id, _)| *id)
+    }
+}
+
+impl Default for RecentPeers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn lower_address_initiates() {
+        let a = [0xFF, 0x00, 0x00, 0x00, 0x00, 0xC1];
+        let b = [0x00, 0x00, 0x00, 0x00, 0x00, 0xC2];
+        // 最上位バイト（[5]）から比べる
+        assert!(should_initiate(&a, &b));
+        assert!(!should_initiate(&b, &a));
+        assert!(!should_initiate(&a, &a));
+        assert_eq!(uuid_le(&EXCHANGE_SERVICE_UUID)[15], 0x8C);
+    }
+
+    #[test]
+    fn cooldown_limits_retries() {
+        let mut c = ExchangeCooldown::new();
+        let id = [1, 2, 3, 4, 5, 6];
+        assert!(c.try_start(&id, 0));
+        assert!(!c.try_start(&id, 60_000));
+        assert!(c.try_start(&[9; 6], 60_000));
+        assert!(c.try_start(&id, RETRY_SECS * 1000));
+    }
+
+    #[test]
+    fn recent_peers_match_address_and_expire() {
+        let mut p = RecentPeers::new();
+        let (addr, id) = ([0xAA; 6], [1, 2, 3, 4, 5, 6]);
+        p.remember(&addr, &id, 1_000);
+        assert_eq!(p.id_of(&addr, 1_000 + PEER_FRESH_SECS * 1000), Some(id));
+        // 別のアドレスからの書き込みや古い広告とは照合しない
+        assert_eq!(p.id_of(&[0xBB; 6], 2_000), None);
+        assert_eq!(p.id_of(&addr, 1_001 + PEER_FRESH_SECS * 1000), None);
+        // 一時IDが切り替わったら新しい ID だけを受け付ける
+        p.remember(&addr, &[9; 6], 5_000);
+        assert_eq!(p.id_of(&addr, 5_000), Some([9; 6]));
+    }
+}
@@ -0,0 +1,266 @@
+//! すれ違いログのエクスポート用フレーム（USB シリアル上のバイナリ転送）
+//! - フレーム: [magic "PX"(2)][type(1)][len(2, LE)][payload(len)][crc32(4, LE)]（crc は type から payload 末尾まで）
+//! - 転送: Header（件数）→ Record × 件数 → End
+//! - 受信側は `FrameDecoder` に1バイトずつ渡す（エコーなどのテキストは読み捨てる）
+//! - ホスト側ツール（tools/export）も同じソースを `#[path]` で取り込む
+
+use heapless::Vec;
+
+use crate::crc::crc32;
+use crate::peer_stats::PeerStats;
+
+const FRAME_MAGIC: [u8; 2] = *b"PX";
+const HEADER_LEN: usize = 5;
+const CRC_LEN: usize = 4;
+
+/// payload の最大長
+pub const MAX_FRAME_PAYLOAD: usize = 64;
+/// フレームの最大長
+pub const MAX_FRAME_LEN: usize = HEADER_LEN + MAX_FRAME_PAYLOAD + CRC_LEN;
+
+/// エクスポート形式のバージョン（Header に入れる）
+pub const EXPORT_VERSION: u8 = 4;
+
+/// フレーム種別
+pub const FRAME_HEADER: u8 = 0x01;
+pub const FRAME_RECORD: u8 = 0x02;
+pub const FRAME_END: u8 = 0x03;
+
+/// 転送開始（Header の payload）
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExportHeader {
+    pub version: u8,
+    pub device_id: [u8; 6],
+    /// 続く Record の件数
+    pub count: u16,
+    /// 転送時刻（Unix秒、未同期なら0）
+    pub exported_at: u64,
+}
+
+impl ExportHeader {
+    const LEN: usize = 17;
+
+    pub fn encode(&self) -> [u8; Self::LEN] {
+        let mut b = [0u8; Self::LEN];
+        b[0] = self.version;
+        b[1..7].copy_from_slice(&self.device_id);
+        b[7..9].copy_from_slice(&self.count.to_le_bytes());
+        b[9..17].copy_from_slice(&self.exported_at.to_le_bytes());
+        b
+    }
+
+    pub fn decode(b: &[u8]) -> Option<Self> {
+        if b.len() < Self::LEN {
+            return None;
+        }
+        Some(Self {
+            version: b[0],
+            device_id: b[1..7].try_into().ok()?,
+            count: u16::from_le_bytes([b[7], b[8]]),
+            exported_at: u64::from_le_bytes(b[9..17].try_into().ok()?),
+        })
+    }
+}
+
+/// すれ違い相手1件（Record の payload）
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExportRecord {
+    pub seq: u32,
+    pub mac_addr: [u8; 6],
+    pub stats: PeerStats,
+    /// 相手の TX 電力（v2 から。不明なら None）
+    pub tx_power: Option<i8>,
+    /// 相互確認済み（v3 から）
+    pub mutual: bool,
+    /// `mac_addr` が一時ID（epoch ごとの集計）か（v4 から）
+    pub ephemeral: bool,
+}
+
+impl ExportRecord {
+    const LEN: usize = 42;
+    /// v1（TX 電力なし）の長さ
+    const LEN_V1: usize = 40;
+    /// フラグ（v3 から）
+    const FLAG_MUTUAL: u8 = 0x01;
+    /// フラグ: 一時ID（v4 から）
+    const FLAG_EPHEMERAL: u8 = 0x02;
+    /// TX 電力なし（HCI と同じ 127 = 不明）
+    const TX_POWER_UNKNOWN: u8 = 0x7F;
+
+    pub fn encode(&self) -> [u8; Self::LEN] {
+        let s = &self.stats;
+        let mut b = [0u8; Self::LEN];
+        b[0..4].copy_from_slice(&self.seq.to_le_bytes());
+        b[4..10].copy_from_slice(&self.mac_addr);
+        b[10..18].copy_from_slice(&s.first_seen.to_le_bytes());
+        b[18..26].copy_from_slice(&s.last_seen.to_le_bytes());
+        b[26..30].copy_from_slice(&s.count.to_le_bytes());
+        b[30] = s.rssi_min as u8;
+        b[31] = s.rssi_max as u8;
+        b[32..36].copy_from_slice(&s.rssi_sum.to_le_bytes());
+        b[36..40].copy_from_slice(&s.dwell_secs.to_le_bytes());
+        b[40] = self.tx_power.map_or(Self::TX_POWER_UNKNOWN, |p| p as u8);
+        b[41] = (if self.mutual { Self::FLAG_MUTUAL } else { 0 }) | (if self.ephemeral { Self::FLAG_EPHEMERAL } else { 0 });
+        b
+    }
+
+    pub fn decode(b: &[u8]) -> Option<Self> {
+        if b.len() < Self::LEN_V1 {
+            return None;
+        }
+        let u32_at = |i: usize| u32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]]);
+        let u64_at = |i: usize| u64::from_le_bytes(b[i..i + 8].try_into().unwrap_or([0; 8]));
+        Some(Self {
+            seq: u32_at(0),
+            mac_addr: b[4..10].try_into().ok()?,
+            stats: PeerStats {
+                first_seen: u64_at(10),
+                last_seen: u64_at(18),
+                count: u32_at(26),
+                rssi_min: b[30] as i8,
+                rssi_max: b[31] as i8,
+                rssi_sum: u32_at(32) as i32,
+                dwell_secs: u32_at(36),
+            },
+            tx_power: b.get(40).filter(|&&p| p != Self::TX_POWER_UNKNOWN).map(|&p| p as i8),
+            mutual: b.get(41).is_some_and(|&f| f & Self::FLAG_MUTUAL != 0),
+            ephemeral: b.get(41).is_some_and(|&f| f & Self::FLAG_EPHEMERAL != 0),
+        })
+    }
+}
+
+/// フレームを `out` に書いて長さを返す（payload が長すぎる・`out` が足りない場合は None）
+pub fn encode_frame(frame_type: u8, payload: &[u8], out: &mut [u8]) -> Option<usize> {
+    let len = HEADER_LEN + payload.len() + CRC_LEN;
+    if payload.len() > MAX_FRAME_PAYLOAD || out.len() < len {
+        return None;
+    }
+    out[0..2].copy_from_slice(&FRAME_MAGIC);
+    out[2] = frame_type;
+    out[3..5].copy_from_slice(&(payload.len() as u16).to_le_bytes());
+    out[5..5 + payload.len()].copy_from_slice(payload);
+    let crc = crc32(&out[2..5 + payload.len()]);
+    out[5 + payload.len()..len].copy_from_slice(&crc.to_le_bytes());
+    Some(len)
+}
+
+/// 受信したフレーム
+#[derive(Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub frame_type: u8,
+    pub payload: Vec<u8, MAX_FRAME_PAYLOAD>,
+}
+
+/// バイト列からフレームを取り出す（magic で同期し、CRC 不一致は1バイトずらして探し直す）
+#[derive(Default)]
+pub struct FrameDecoder {
+    buf: Vec<u8, MAX_FRAME_LEN>,
+}
+
+impl FrameDecoder {
+    pub const fn new() -> Self {
+        Self { buf: Vec::new() }
+    }
+
+    /// 1バイト渡し、フレームが揃えば返す
+    pub fn push(&mut self, b: u8) -> Option<Frame> {
+        if self.buf.push(b).is_err() {
+            self.shift();
+            let _ = self.buf.push(b);
+        }
+        loop {
+            let buf = &self.buf;
+            if buf.is_empty() {
+                return None;
+            }
+            // magic の途中までしか無い間は待つ
+            let magic_len = buf.len().min(2);
+            if buf[..magic_len] != FRAME_MAGIC[..magic_len] {
+                self.shift();
+                continue;
+            }
+            if buf.len() < HEADER_LEN {
+                return None;
+            }
+            let len = u16::from_le_bytes([buf[3], buf[4]]) as usize;
+            if len > MAX_FRAME_PAYLOAD {
+                self.shift();
+                continue;
+            }
+            let total = HEADER_LEN + len + CRC_LEN;
+            if buf.len() < total {
+                return None;
+            }
+            let crc = u32::from_le_bytes(buf[total - 4..total].try_into().unwrap_or([0; 4]));
+            if crc != crc32(&buf[2..HEADER_LEN + len]) {
+                self.shift();
+                continue;
+            }
+            let frame = Frame {
+                frame_type: buf[2],
+                payload: Vec::from_slice(&buf[HEADER_LEN..HEADER_LEN + len]).ok()?,
+            };
+            self.buf.clear();
+            return Some(frame);
+        }
+    }
+
+    /// 先頭1バイトを捨てる
+    fn shift(&mut self) {
+        if !self.buf.is_empty() {
+            self.buf.remove(0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn record(seq: u32) -> ExportRecord {
+        let mut stats = PeerStats::new(1_700_000_000, -60);
+        stats.observe(1_700_000_030, -72);
+        ExportRecord { seq, mac_addr: [0x28, 0xCD, 0xC1, 0x15, 0x26, seq as u8], stats, tx_power: Some(-4), mutual: seq >= 8, ephemeral: seq % 2 == 1 }
+    }
+
+    #[test]
+    fn record_and_header_roundtrip() {
+        let r = record(7);
+        assert_eq!(ExportRecord::decode(&r.encode()), Some(r));
+        // v1 のレコード（TX 電力なし）も読める
+        assert_eq!(ExportRecord::decode(&r.encode()[..40]).map(|v1| v1.tx_power), Some(None));
+        let r = record(8);
+        assert_eq!(ExportRecord::decode(&r.encode()), Some(r));
+        // v2 のレコード（フラグなし）は相互確認なし
+        assert_eq!(ExportRecord::decode(&r.encode()[..41]).map(|v2| v2.mutual), Some(false));
+        // 相互確認と一時ID のフラグは独立
+        let r = record(9);
+        assert_eq!(ExportRecord::decode(&r.encode()).map(|v| (v.mutual, v.ephemeral)), Some((true, true)));
+        let h = ExportHeader { version: EXPORT_VERSION, device_id: [1, 2, 3, 4, 5, 6], count: 2, exported_at: 1_700_000_100 };
+        assert_eq!(ExportHeader::decode(&h.encode()), Some(h));
+    }
+
+    #[test]
+    fn decoder_skips_text_and_corrupted_frames() {
+        let mut stream = std::vec::Vec::new();
+        stream.extend_from_slice(b"export\r\nPX garbage ");
+        let mut out = [0u8; MAX_FRAME_LEN];
+        let n = encode_frame(FRAME_RECORD, &record(1).encode(), &mut out).unwrap();
+        // 1バイト壊したフレームは捨てられる
+        let mut bad = out[..n].to_vec();
+        bad[10] ^= 0x01;
+        stream.extend_from_slice(&bad);
+        let n = encode_frame(FRAME_RECORD, &record(2).encode(), &mut out).unwrap();
+        stream.extend_from_slice(&out[..n]);
+        let n = encode_frame(FRAME_END, &[], &mut out).unwrap();
+        stream.extend_from_slice(&out[..n]);
+
+        let mut dec = FrameDecoder::new();
+        let frames: std::vec::Vec<Frame> = stream.iter().filter_map(|&b| dec.push(b)).collect();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].frame_type, FRAME_RECORD);
+        assert_eq!(ExportRecord::decode(&frames[0].payload), Some(record(2)));
+        assert_eq!(frames[1].frame_type, FRAME_END);
+    }
+}
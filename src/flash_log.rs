@@ -0,0 +1,410 @@
+//! フラッシュ上の追記専用リングログ（電源断耐性つき）
+//! - 予約領域を消去単位（セクタ）のリングとして使い、最古セクタから順に再利用（ウェアレベリング）
+//! - セクタ先頭: [seq(4)][magic(4)]。magic を最後に書くことでヘッダ書き込み途中の電源断を検出
+//! - レコード: [MARK(1)][len(1)][payload(len)][crc32(4)]（WRITE_SIZE 境界へ切り上げ）
+//! - 起動時に seq 順で走査し、CRC 不一致（書き込み途中の電源断）を見つけたらそのセクタを閉じる
+//! - `protect_from` で指定したセクタ（以降）は消去しない。上位が最新状態を書き直すまで古い記録を守る
+
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::crc::crc32;
+
+/// 1レコードに格納できるペイロード最大長
+pub const MAX_PAYLOAD: usize = 64;
+
+const SECTOR_MAGIC: u32 = 0x474C_5350; // "PSLG"
+const HEADER_LEN: usize = 8;
+const RECORD_MARK: u8 = 0xA5;
+const ERASED: u8 = 0xFF;
+const RECORD_OVERHEAD: usize = 2 + 4; // MARK + len + crc32
+const RECORD_BUF: usize = 128; // MAX_PAYLOAD + オーバーヘッド（WRITE_SIZE 切り上げ分を含む）
+
+/// ログ操作のエラー
+#[derive(Debug, PartialEq, Eq)]
+pub enum LogError<E> {
+    /// 下位フラッシュドライバのエラー
+    Flash(E),
+    /// ペイロードが MAX_PAYLOAD を超えた
+    TooLarge,
+    /// 領域指定が不正（セクタ境界でない、セクタ数が2未満など）
+    Region,
+    /// 空きセクタがなく、最古セクタは保護中のため消去できない
+    Full,
+}
+
+/// 追記専用リングログ
+pub struct FlashLog<F: NorFlash> {
+    flash: F,
+    base: u32,
+    sectors: u32,
+    /// 書き込み中セクタ（index）
+    head: u32,
+    /// 書き込み中セクタ内の次の書き込み位置
+    head_offset: u32,
+    head_seq: u32,
+    /// 最古セクタ（index）
+    tail: u32,
+    /// ヘッダが有効なセクタ数
+    used: u32,
+    /// 消去しないセクタ（index）。ここから head までの記録は失われない
+    keep: Option<u32>,
+}
+
+impl<F: NorFlash> FlashLog<F> {
+    /// 領域 `[base, base + sectors * ERASE_SIZE)` を走査してログを復元する。
+    /// 空の領域（全消去状態）でもそのまま使用できる。
+    pub fn mount(flash: F, base: u32, sectors: u32) -> Result<Self, LogError<F::Error>> {
+        if sectors < 2 || !(base as usize).is_multiple_of(F::ERASE_SIZE) || HEADER_LEN > F::ERASE_SIZE {
+            return Err(LogError::Region);
+        }
+        let mut log = Self { flash, base, sectors, head: 0, head_offset: 0, head_seq: 0, tail: 0, used: 0, keep: None };
+
+        // 有効ヘッダを持つセクタのうち seq 最小=tail, 最大=head
+        let mut min: Option<(u32, u32)> = None;
+        let mut max: Option<(u32, u32)> = None;
+        for i in 0..sectors {
+            if let Some(seq) = log.read_header(i)? {
+                log.used += 1;
+                if min.is_none_or(|(_, s)| seq < s) { min = Some((i, seq)); }
+                if max.is_none_or(|(_, s)| seq > s) { max = Some((i, seq)); }
+            }
+        }
+        if let (Some((tail, _)), Some((head, seq))) = (min, max) {
+            log.tail = tail;
+            log.head = head;
+            log.head_seq = seq;
+            log.head_offset = log.scan_sector(head, &mut |_| {})?;
+        }
+        Ok(log)
+    }
+
+    /// ペイロードを1レコードとして追記する。
+    /// 書き込み中セクタに収まらなければ次セクタを消去して移る（満杯なら最古セクタを再利用）。
+    pub fn append(&mut self, payload: &[u8]) -> Result<(), LogError<F::Error>> {
+        if payload.len() > MAX_PAYLOAD {
+            return Err(LogError::TooLarge);
+        }
+        let size = align_up::<F>(RECORD_OVERHEAD + payload.len());
+        if self.used == 0 || self.head_offset as usize + size > F::ERASE_SIZE {
+            self.advance()?;
+        }
+
+        let mut buf = [ERASED; RECORD_BUF];
+        let n = payload.len();
+        buf[0] = RECORD_MARK;
+        buf[1] = n as u8;
+        buf[2..2 + n].copy_from_slice(payload);
+        let crc = crc32(&buf[1..2 + n]);
+        buf[2 + n..6 + n].copy_from_slice(&crc.to_le_bytes());
+
+        let addr = self.sector_addr(self.head) + self.head_offset;
+        self.flash.write(addr, &buf[..size]).map_err(LogError::Flash)?;
+        self.head_offset += size as u32;
+        Ok(())
+    }
+
+    /// 全レコードを古い順に `f` へ渡す（CRC 検証済みのもののみ）。
+    pub fn for_each(&mut self, mut f: impl FnMut(&[u8])) -> Result<(), LogError<F::Error>> {
+        self.for_each_with_sector(|_, rec| f(rec))
+    }
+
+    /// `for_each` と同じ順で、レコードが入っているセクタ（index）も渡す（`protect_from` の指定用）。
+    pub fn for_each_with_sector(&mut self, mut f: impl FnMut(u32, &[u8])) -> Result<(), LogError<F::Error>> {
+        if self.used == 0 {
+            return Ok(());
+        }
+        let mut i = self.tail;
+        for _ in 0..self.sectors {
+            if self.read_header(i)?.is_some() {
+                self.scan_sector(i, &mut |rec| f(i, rec))?;
+            }
+            if i == self.head {
+                break;
+            }
+            i = (i + 1) % self.sectors;
+        }
+        Ok(())
+    }
+
+    /// 使用中セクタ数（診断用）
+    pub fn used_sectors(&self) -> u32 {
+        self.used
+    }
+
+    /// 書き込み中セクタ（index）
+    pub fn head_sector(&self) -> u32 {
+        self.head
+    }
+
+    /// `sector` から head までを消去しないようにする（以前の保護は解除）。
+    pub fn protect_from(&mut self, sector: u32) {
+        self.keep = Some(sector % self.sectors);
+    }
+
+    /// 保護中の記録を消さずに新しく使えるセクタ数（未使用 + 保護セクタより古いセクタ）。
+    /// 保護指定がなければ未使用セクタのみ数える。
+    pub fn headroom(&self) -> u32 {
+        let free = self.sectors - self.used;
+        match self.keep {
+            Some(keep) if self.used > 0 => free + (keep + self.sectors - self.tail) % self.sectors,
+            _ => free,
+        }
+    }
+
+    /// 下位フラッシュ（ログ領域外の読み書き用。ログ領域には書き込まないこと）
+    pub fn flash_mut(&mut self) -> &mut F {
+        &mut self.flash
+    }
+
+    /// 次セクタへ移動（消去してヘッダを書き込む）
+    fn advance(&mut self) -> Result<(), LogError<F::Error>> {
+        let (next, seq) = if self.used == 0 {
+            (0, 0)
+        } else {
+            ((self.head + 1) % self.sectors, self.head_seq.wrapping_add(1))
+        };
+        if self.used == self.sectors {
+            // リング満杯: 最古セクタを捨てる（保護中なら書き込まない）
+            if self.keep == Some(self.tail) {
+                return Err(LogError::Full);
+            }
+            self.tail = (self.tail + 1) % self.sectors;
+        } else {
+            self.used += 1;
+            if self.used == 1 {
+                self.tail = next;
+            }
+        }
+
+        let addr = self.sector_addr(next);
+        self.flash
+            .erase(addr, addr + F::ERASE_SIZE as u32)
+            .map_err(LogError::Flash)?;
+        // seq → magic の順に書く（magic が書かれていればヘッダは完全）
+        let mut word = [ERASED; 8];
+        let w = align_up::<F>(4).min(8);
+        word[..4].copy_from_slice(&seq.to_le_bytes());
+        self.flash.write(addr, &word[..w]).map_err(LogError::Flash)?;
+        word = [ERASED; 8];
+        word[..4].copy_from_slice(&SECTOR_MAGIC.to_le_bytes());
+        self.flash.write(addr + 4, &word[..w]).map_err(LogError::Flash)?;
+
+        self.head = next;
+        self.head_seq = seq;
+        self.head_offset = align_up::<F>(HEADER_LEN) as u32;
+        Ok(())
+    }
+
+    /// セクタヘッダを読み、有効なら seq を返す
+    fn read_header(&mut self, sector: u32) -> Result<Option<u32>, LogError<F::Error>> {
+        let mut h = [0u8; HEADER_LEN];
+        self.flash.read(self.sector_addr(sector), &mut h).map_err(LogError::Flash)?;
+        let seq = u32::from_le_bytes([h[0], h[1], h[2], h[3]]);
+        let magic = u32::from_le_bytes([h[4], h[5], h[6], h[7]]);
+        Ok((magic == SECTOR_MAGIC).then_some(seq))
+    }
+
+    /// セクタ内のレコードを走査し、次の書き込み位置を返す。
+    /// 壊れたレコード（電源断）を見つけた場合はセクタを閉じたものとして ERASE_SIZE を返す。
+    fn scan_sector(&mut self, sector: u32, f: &mut dyn FnMut(&[u8])) -> Result<u32, LogError<F::Error>> {
+        let base = self.sector_addr(sector);
+        let mut off = align_up::<F>(HEADER_LEN);
+        let mut buf = [0u8; RECORD_BUF];
+        while off + RECORD_OVERHEAD <= F::ERASE_SIZE {
+            self.flash.read(base + off as u32, &mut buf[..2]).map_err(LogError::Flash)?;
+            if buf[0] == ERASED && buf[1] == ERASED {
+                return Ok(off as u32);
+            }
+            let n = buf[1] as usize;
+            let size = align_up::<F>(RECORD_OVERHEAD + n);
+            if buf[0] != RECORD_MARK || n > MAX_PAYLOAD || off + size > F::ERASE_SIZE {
+                return Ok(F::ERASE_SIZE as u32);
+            }
+            self.flash
+                .read(base + off as u32 + 2, &mut buf[2..6 + n])
+                .map_err(LogError::Flash)?;
+            let stored = u32::from_le_bytes([buf[2 + n], buf[3 + n], buf[4 + n], buf[5 + n]]);
+            if crc32(&buf[1..2 + n]) != stored {
+                return Ok(F::ERASE_SIZE as u32);
+            }
+            f(&buf[2..2 + n]);
+            off += size;
+        }
+        Ok(F::ERASE_SIZE as u32)
+    }
+
+    fn sector_addr(&self, sector: u32) -> u32 {
+        self.base + sector * F::ERASE_SIZE as u32
+    }
+}
+
+fn align_up<F: NorFlash>(n: usize) -> usize {
+    let w = F::WRITE_SIZE.max(1);
+    n.div_ceil(w) * w
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_storage::nor_flash::{ErrorType, NorFlashError, NorFlashErrorKind, ReadNorFlash};
+    use pretty_assertions::assert_eq;
+
+    const SECTOR: usize = 256;
+    const SECTORS: u32 = 3;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct MockError;
+
+    impl NorFlashError for MockError {
+        fn kind(&self) -> NorFlashErrorKind {
+            NorFlashErrorKind::Other
+        }
+    }
+
+    /// RAM 上の擬似フラッシュ（書き込みは 1→0 のみ、`budget` バイト書いたら電源断を模擬）
+    struct RamFlash {
+        mem: [u8; SECTOR * SECTORS as usize],
+        budget: Option<usize>,
+    }
+
+    impl RamFlash {
+        fn new() -> Self {
+            Self { mem: [0xFF; SECTOR * SECTORS as usize], budget: None }
+        }
+    }
+
+    impl ErrorType for RamFlash {
+        type Error = MockError;
+    }
+
+    impl ReadNorFlash for RamFlash {
+        const READ_SIZE: usize = 1;
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), MockError> {
+            let o = offset as usize;
+            bytes.copy_from_slice(&self.mem[o..o + bytes.len()]);
+            Ok(())
+        }
+        fn capacity(&self) -> usize {
+            self.mem.len()
+        }
+    }
+
+    impl NorFlash for RamFlash {
+        const WRITE_SIZE: usize = 1;
+        const ERASE_SIZE: usize = SECTOR;
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), MockError> {
+            self.mem[from as usize..to as usize].fill(0xFF);
+            Ok(())
+        }
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), MockError> {
+            for (i, b) in bytes.iter().enumerate() {
+                if let Some(left) = self.budget.as_mut() {
+                    if *left == 0 {
+                        return Err(MockError);
+                    }
+                    *left -= 1;
+                }
+                self.mem[offset as usize + i] &= *b;
+            }
+            Ok(())
+        }
+    }
+
+    fn collect(log: &mut FlashLog<&mut RamFlash>) -> heapless::Vec<u8, 256> {
+        let mut out = heapless::Vec::new();
+        log.for_each(|p| { let _ = out.push(p[0]); }).unwrap();
+        out
+    }
+
+    #[test]
+    fn append_and_remount() {
+        let mut flash = RamFlash::new();
+        {
+            let mut log = FlashLog::mount(&mut flash, 0, SECTORS).unwrap();
+            for i in 0..5u8 {
+                log.append(&[i, 0xAA, 0xBB]).unwrap();
+            }
+        }
+        let mut log = FlashLog::mount(&mut flash, 0, SECTORS).unwrap();
+        assert_eq!(collect(&mut log).as_slice(), &[0, 1, 2, 3, 4]);
+        // 追記位置も復元されている
+        log.append(&[5]).unwrap();
+        assert_eq!(collect(&mut log).as_slice(), &[0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn wraps_and_drops_oldest_sector() {
+        let mut flash = RamFlash::new();
+        let got = {
+            let mut log = FlashLog::mount(&mut flash, 0, SECTORS).unwrap();
+            // 1レコード = 6 + 10 = 16B、1セクタ = (256 - 8) / 16 = 15件
+            for i in 0..60u8 {
+                log.append(&[i; 10]).unwrap();
+            }
+            assert_eq!(log.used_sectors(), SECTORS);
+            collect(&mut log)
+        };
+        // 最古セクタ（0..=14）が再利用され、15 以降が残る
+        assert_eq!(got.first(), Some(&15));
+        assert_eq!(got.last(), Some(&59));
+        let mut log = FlashLog::mount(&mut flash, 0, SECTORS).unwrap();
+        assert_eq!(collect(&mut log), got);
+    }
+
+    #[test]
+    fn torn_record_is_skipped_after_power_loss() {
+        let mut flash = RamFlash::new();
+        {
+            let mut log = FlashLog::mount(&mut flash, 0, SECTORS).unwrap();
+            log.append(&[1, 1, 1, 1]).unwrap();
+            log.append(&[2, 2, 2, 2]).unwrap();
+        }
+        // 3件目の書き込み途中で電源断
+        flash.budget = Some(5);
+        {
+            let mut log = FlashLog::mount(&mut flash, 0, SECTORS).unwrap();
+            assert!(log.append(&[3, 3, 3, 3]).is_err());
+        }
+        flash.budget = None;
+        let mut log = FlashLog::mount(&mut flash, 0, SECTORS).unwrap();
+        assert_eq!(collect(&mut log).as_slice(), &[1, 2]);
+        // 壊れたセクタは閉じられ、次のセクタへ追記される
+        log.append(&[4]).unwrap();
+        assert_eq!(log.used_sectors(), 2);
+        assert_eq!(collect(&mut log).as_slice(), &[1, 2, 4]);
+    }
+
+    #[test]
+    fn protected_sector_is_never_erased() {
+        let mut flash = RamFlash::new();
+        let mut log = FlashLog::mount(&mut flash, 0, SECTORS).unwrap();
+        log.append(&[0xEE; 10]).unwrap();
+        log.protect_from(log.head_sector());
+        assert_eq!(log.headroom(), SECTORS - 1);
+        // 1セクタ = 15件。保護セクタ以外の2セクタを使い切るまでは書ける
+        for i in 0..44u8 {
+            log.append(&[i; 10]).unwrap();
+        }
+        assert_eq!(log.headroom(), 0);
+        assert_eq!(log.append(&[44; 10]), Err(LogError::Full));
+        let mut sectors = heapless::Vec::<u32, 64>::new();
+        log.for_each_with_sector(|sector, rec| if rec[0] == 0xEE { let _ = sectors.push(sector); }).unwrap();
+        assert_eq!(sectors.as_slice(), &[0]);
+
+        // 保護を先へ進めると最古セクタを再利用できる
+        log.protect_from(2);
+        assert_eq!(log.headroom(), 2);
+        log.append(&[44; 10]).unwrap();
+        let got = collect(&mut log);
+        assert_eq!(got.first(), Some(&14));
+        assert_eq!(got.last(), Some(&44));
+    }
+
+    #[test]
+    fn rejects_oversized_payload_and_bad_region() {
+        let mut flash = RamFlash::new();
+        assert!(matches!(FlashLog::mount(&mut flash, 1, SECTORS), Err(LogError::Region)));
+        let mut log = FlashLog::mount(&mut flash, 0, SECTORS).unwrap();
+        assert_eq!(log.append(&[0u8; MAX_PAYLOAD + 1]), Err(LogError::TooLarge));
+    }
+}
@@ -0,0 +1,142 @@
+//! GATT 設定サービスの書き込みの暗号化・認証（WiFi のパスワードを平文で飛ばさない）
+//! - 書き込み: [暗号文][タグ(8)]。平文はコンソールと同じ1行（`config set ssid MyHome` など）
+//! - 暗号化: 平文と鍵ストリーム HMAC-SHA256(鍵, "PicoStreet-CFG-KS" || チャレンジ || ブロック番号) の XOR
+//! - タグ: HMAC-SHA256(鍵, "PicoStreet-CFG" || チャレンジ || 暗号文) の先頭8バイト（暗号化してから認証）
+//! - チャレンジは gatt_control と共通（接続ごと・書き込みごとに変わる）
+//! - 鍵は制御コマンドとは別の管理者用（GATT_CONFIG_KEY）。空なら受け付けない
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::gatt_control::{CHALLENGE_LEN, TAG_LEN};
+
+/// 鍵ストリームに使うラベル
+const KEYSTREAM_LABEL: &[u8] = b"PicoStreet-CFG-KS";
+/// タグに使うラベル
+const TAG_LABEL: &[u8] = b"PicoStreet-CFG";
+
+/// 平文の最大長（`config set path` の64バイト + コマンド部分が入る長さ）
+pub const MAX_CONFIG_PLAINTEXT: usize = 96;
+/// 書き込みの最大長
+pub const MAX_CONFIG_WRITE: usize = MAX_CONFIG_PLAINTEXT + TAG_LEN;
+
+/// 書き込みを受け付けなかった理由
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigWriteError {
+    /// 鍵が設定されていない
+    Disabled,
+    /// 長さが違う
+    BadLength,
+    /// タグが一致しない
+    BadTag,
+    /// 平文が UTF-8 でない
+    NotUtf8,
+}
+
+impl ConfigWriteError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Disabled => "設定サービスは無効",
+            Self::BadLength => "長さ不正",
+            Self::BadTag => "認証失敗",
+            Self::NotUtf8 => "UTF-8 ではありません",
+        }
+    }
+}
+
+fn new_mac(key: &[u8], label: &[u8], challenge: &[u8; CHALLENGE_LEN]) -> Hmac<Sha256> {
+    // HMAC は任意長の鍵を受け付けるため失敗しない
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC key");
+    mac.update(label);
+    mac.update(challenge);
+    mac
+}
+
+/// `data` に鍵ストリームを XOR する（暗号化・復号は同じ操作）
+fn apply_keystream(key: &[u8], challenge: &[u8; CHALLENGE_LEN], data: &mut [u8]) {
+    for (i, chunk) in data.chunks_mut(32).enumerate() {
+        let mut mac = new_mac(key, KEYSTREAM_LABEL, challenge);
+        mac.update(&[i as u8]);
+        let ks = mac.finalize().into_bytes();
+        for (b, k) in chunk.iter_mut().zip(ks.iter()) {
+            *b ^= k;
+        }
+    }
+}
+
+fn tag_mac(key: &[u8], challenge: &[u8; CHALLENGE_LEN], ciphertext: &[u8]) -> Hmac<Sha256> {
+    let mut mac = new_mac(key, TAG_LABEL, challenge);
+    mac.update(ciphertext);
+    mac
+}
+
+/// 平文を暗号化してタグを付け、`out` に書いた長さを返す（アプリ側と同じ計算。テスト用）。
+pub fn seal(key: &[u8], challenge: &[u8; CHALLENGE_LEN], plaintext: &[u8], out: &mut [u8]) -> Option<usize> {
+    let len = plaintext.len() + TAG_LEN;
+    if plaintext.len() > MAX_CONFIG_PLAINTEXT || out.len() < len {
+        return None;
+    }
+    let (ct, rest) = out.split_at_mut(plaintext.len());
+    ct.copy_from_slice(plaintext);
+    apply_keystream(key, challenge, ct);
+    let tag = tag_mac(key, challenge, ct).finalize().into_bytes();
+    rest[..TAG_LEN].copy_from_slice(&tag[..TAG_LEN]);
+    Some(len)
+}
+
+/// 書き込まれた値を検証・復号し、平文の1行を返す。
+pub fn open<'a>(
+    key: &[u8],
+    challenge: &[u8; CHALLENGE_LEN],
+    data: &[u8],
+    out: &'a mut [u8; MAX_CONFIG_PLAINTEXT],
+) -> Result<&'a str, ConfigWriteError> {
+    if key.is_empty() {
+        return Err(ConfigWriteError::Disabled);
+    }
+    if data.len() <= TAG_LEN || data.len() > MAX_CONFIG_WRITE {
+        return Err(ConfigWriteError::BadLength);
+    }
+    let (ct, tag) = data.split_at(data.len() - TAG_LEN);
+    // タグは定数時間で比較する
+    tag_mac(key, challenge, ct).verify_truncated_left(tag).map_err(|_| ConfigWriteError::BadTag)?;
+    let pt = &mut out[..ct.len()];
+    pt.copy_from_slice(ct);
+    apply_keystream(key, challenge, pt);
+    core::str::from_utf8(pt).map_err(|_| ConfigWriteError::NotUtf8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const KEY: &[u8] = b"keyholder-admin-key";
+    const CHALLENGE: [u8; CHALLENGE_LEN] = [1, 2, 3, 4, 5, 6, 7, 8];
+
+    #[test]
+    fn seal_and_open_roundtrip() {
+        // 32バイトを超える平文（鍵ストリームが2ブロック以上）
+        let line = "config set psk correct horse battery staple";
+        let mut buf = [0u8; MAX_CONFIG_WRITE];
+        let n = seal(KEY, &CHALLENGE, line.as_bytes(), &mut buf).unwrap();
+        assert_eq!(n, line.len() + TAG_LEN);
+        // 平文がそのまま載っていない
+        assert!(!buf[..n].windows(5).any(|w| w == b"horse"));
+        let mut out = [0u8; MAX_CONFIG_PLAINTEXT];
+        assert_eq!(open(KEY, &CHALLENGE, &buf[..n], &mut out), Ok(line));
+    }
+
+    #[test]
+    fn rejects_wrong_key_challenge_and_tampering() {
+        let mut buf = [0u8; MAX_CONFIG_WRITE];
+        let n = seal(KEY, &CHALLENGE, b"config set ssid Home", &mut buf).unwrap();
+        let mut out = [0u8; MAX_CONFIG_PLAINTEXT];
+        assert_eq!(open(b"other", &CHALLENGE, &buf[..n], &mut out), Err(ConfigWriteError::BadTag));
+        assert_eq!(open(KEY, &[0; CHALLENGE_LEN], &buf[..n], &mut out), Err(ConfigWriteError::BadTag));
+        buf[3] ^= 0x01;
+        assert_eq!(open(KEY, &CHALLENGE, &buf[..n], &mut out), Err(ConfigWriteError::BadTag));
+        assert_eq!(open(KEY, &CHALLENGE, &buf[..TAG_LEN], &mut out), Err(ConfigWriteError::BadLength));
+        assert_eq!(open(b"", &CHALLENGE, &buf[..n], &mut out), Err(ConfigWriteError::Disabled));
+    }
+}
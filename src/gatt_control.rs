@@ -0,0 +1,126 @@
+//! GATT 情報サービスの制御コマンド（書き込み保護付き）
+//! - 書き込み: [コマンド(1)][タグ(8)]。タグ = HMAC-SHA256(鍵, "PicoStreet-CTL" || チャレンジ || コマンド) の先頭8バイト
+//! - チャレンジ（8バイト乱数）は接続ごと・書き込みごとに変わるため、盗聴した書き込みを再送しても通らない
+//! - 鍵が空なら制御コマンドは受け付けない
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// タグの計算に使うラベル
+const CONTROL_LABEL: &[u8] = b"PicoStreet-CTL";
+
+/// チャレンジの長さ
+pub const CHALLENGE_LEN: usize = 8;
+/// タグの長さ
+pub const TAG_LEN: usize = 8;
+
+/// 制御コマンド
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlCommand {
+    /// すぐに API へ送信する
+    UploadNow = 0x01,
+    /// すれ違いログを全消去する
+    ClearLog = 0x02,
+    /// 再起動する
+    Reboot = 0x03,
+}
+
+impl ControlCommand {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0x01 => Some(Self::UploadNow),
+            0x02 => Some(Self::ClearLog),
+            0x03 => Some(Self::Reboot),
+            _ => None,
+        }
+    }
+}
+
+/// 書き込みを受け付けなかった理由
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlError {
+    /// 鍵が設定されていない
+    Disabled,
+    /// 長さが違う
+    BadLength,
+    /// 未知のコマンド
+    UnknownCommand,
+    /// タグが一致しない
+    BadTag,
+}
+
+impl ControlError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Disabled => "制御コマンドは無効",
+            Self::BadLength => "長さ不正",
+            Self::UnknownCommand => "未知のコマンド",
+            Self::BadTag => "認証失敗",
+        }
+    }
+}
+
+fn mac(key: &[u8], challenge: &[u8; CHALLENGE_LEN], cmd: u8) -> Hmac<Sha256> {
+    // HMAC は任意長の鍵を受け付けるため失敗しない
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC key");
+    mac.update(CONTROL_LABEL);
+    mac.update(challenge);
+    mac.update(&[cmd]);
+    mac
+}
+
+/// 書き込むタグを計算する（アプリ側と同じ計算。テスト用）
+pub fn control_tag(key: &[u8], challenge: &[u8; CHALLENGE_LEN], cmd: u8) -> [u8; TAG_LEN] {
+    let tag = mac(key, challenge, cmd).finalize().into_bytes();
+    let mut out = [0u8; TAG_LEN];
+    out.copy_from_slice(&tag[..TAG_LEN]);
+    out
+}
+
+/// 書き込まれた値を検証してコマンドを返す。
+pub fn verify_control(key: &[u8], challenge: &[u8; CHALLENGE_LEN], data: &[u8]) -> Result<ControlCommand, ControlError> {
+    if key.is_empty() {
+        return Err(ControlError::Disabled);
+    }
+    let [cmd, tag @ ..] = data else { return Err(ControlError::BadLength) };
+    if tag.len() != TAG_LEN {
+        return Err(ControlError::BadLength);
+    }
+    // タグは定数時間で比較する
+    mac(key, challenge, *cmd).verify_truncated_left(tag).map_err(|_| ControlError::BadTag)?;
+    ControlCommand::from_u8(*cmd).ok_or(ControlError::UnknownCommand)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const KEY: &[u8] = b"keyholder-control-key";
+
+    fn write(challenge: &[u8; CHALLENGE_LEN], cmd: u8) -> std::vec::Vec<u8> {
+        let mut data = std::vec![cmd];
+        data.extend_from_slice(&control_tag(KEY, challenge, cmd));
+        data
+    }
+
+    #[test]
+    fn accepts_tagged_command() {
+        let challenge = [1, 2, 3, 4, 5, 6, 7, 8];
+        assert_eq!(verify_control(KEY, &challenge, &write(&challenge, 0x02)), Ok(ControlCommand::ClearLog));
+        assert_eq!(verify_control(KEY, &challenge, &write(&challenge, 0x7F)), Err(ControlError::UnknownCommand));
+    }
+
+    #[test]
+    fn rejects_replay_and_tampering() {
+        let challenge = [1, 2, 3, 4, 5, 6, 7, 8];
+        let data = write(&challenge, 0x03);
+        // チャレンジが変わると同じ書き込みは通らない
+        assert_eq!(verify_control(KEY, &[9; CHALLENGE_LEN], &data), Err(ControlError::BadTag));
+        let mut tampered = data.clone();
+        tampered[0] = 0x02;
+        assert_eq!(verify_control(KEY, &challenge, &tampered), Err(ControlError::BadTag));
+        assert_eq!(verify_control(KEY, &challenge, &data[..4]), Err(ControlError::BadLength));
+        assert_eq!(verify_control(b"", &challenge, &data), Err(ControlError::Disabled));
+    }
+}
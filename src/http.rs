@@ -0,0 +1,198 @@
+//! HTTP/1.1 の簡易解析
+//! - レスポンス先頭（ステータス行とヘッダ）
+//! - 設定ポータル用のリクエスト行と application/x-www-form-urlencoded のフォーム
+
+use heapless::String;
+
+/// ステータス行 "HTTP/1.x NNN ..." からステータスコードを取り出す
+pub fn parse_status(buf: &[u8]) -> Option<u16> {
+    if buf.len() < 12 { return None; }
+    if !buf.starts_with(b"HTTP/1.") { return None; }
+    // Find first space
+    let mut i = 0;
+    while i < buf.len() && buf[i] != b' ' { i += 1; }
+    if i + 4 > buf.len() { return None; }
+    let d1 = buf[i+1]; let d2 = buf[i+2]; let d3 = buf[i+3];
+    if (d1 as char).is_ascii_digit() && (d2 as char).is_ascii_digit() && (d3 as char).is_ascii_digit() {
+        let code = ((d1 - b'0') as u16) * 100 + ((d2 - b'0') as u16) * 10 + ((d3 - b'0') as u16);
+        Some(code)
+    } else {
+        None
+    }
+}
+
+/// ヘッダ `name`（大文字小文字を区別しない）の値を返す。前後の空白は除く。
+/// ヘッダ部の終わり（空行）またはバッファ末尾で探索を止める。
+pub fn find_header<'a>(buf: &'a [u8], name: &str) -> Option<&'a [u8]> {
+    // ステータス行を飛ばす
+    let mut lines = buf.split(|&b| b == b'\n').skip(1);
+    for line in &mut lines {
+        let line = trim(line);
+        if line.is_empty() {
+            break;
+        }
+        let Some(colon) = line.iter().position(|&b| b == b':') else { continue };
+        if line[..colon].eq_ignore_ascii_case(name.as_bytes()) {
+            return Some(trim(&line[colon + 1..]));
+        }
+    }
+    None
+}
+
+/// 10進の非負整数をパース（桁あふれ・数字以外は None）
+pub fn parse_u64(v: &[u8]) -> Option<u64> {
+    if v.is_empty() { return None; }
+    let mut n: u64 = 0;
+    for &b in v {
+        if !b.is_ascii_digit() { return None; }
+        n = n.checked_mul(10)?.checked_add((b - b'0') as u64)?;
+    }
+    Some(n)
+}
+
+/// Retry-After ヘッダ（秒数形式のみ対応。日付形式は None）
+pub fn parse_retry_after(buf: &[u8]) -> Option<u32> {
+    parse_u64(find_header(buf, "Retry-After")?)?.try_into().ok()
+}
+
+/// サーバが次回送信時刻（Unix秒）を指示するヘッダ
+pub const NEXT_UPLOAD_HEADER: &str = "X-PicoStreet-Next-Upload";
+
+/// 次回送信時刻の指示（NEXT_UPLOAD_HEADER）
+pub fn parse_next_upload(buf: &[u8]) -> Option<u64> {
+    parse_u64(find_header(buf, NEXT_UPLOAD_HEADER)?)
+}
+
+/// API 応答の判定（`Accepted` のときだけ送信した記録を確定してよい）
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UploadReply {
+    /// 2xx。サーバが次回送信時刻を指示していれば保持
+    Accepted { next_upload_at: Option<u64> },
+    /// 2xx 以外
+    Rejected { code: u16, retry_after: Option<u32> },
+    /// 応答が無い（タイムアウト・切断）。サーバが受理したか分からない
+    NoResponse,
+    /// ステータス行を解析できない。サーバが受理したか分からない
+    Garbled,
+}
+
+/// 読み取った応答の先頭から送信結果を判定する
+pub fn parse_upload_reply(buf: &[u8]) -> UploadReply {
+    if buf.is_empty() {
+        return UploadReply::NoResponse;
+    }
+    match parse_status(buf) {
+        Some(code) if (200..300).contains(&code) => UploadReply::Accepted { next_upload_at: parse_next_upload(buf) },
+        Some(code) => UploadReply::Rejected { code, retry_after: parse_retry_after(buf) },
+        None => UploadReply::Garbled,
+    }
+}
+
+/// リクエスト行 "METHOD /path HTTP/1.x" から (method, path) を取り出す
+pub fn parse_request_line(buf: &[u8]) -> Option<(&[u8], &[u8])> {
+    let line = buf.split(|&b| b == b'\n').next()?;
+    let mut parts = trim(line).split(|&b| b == b' ');
+    let method = parts.next().filter(|m| !m.is_empty())?;
+    let path = parts.next().filter(|p| p.starts_with(b"/"))?;
+    if !parts.next()?.starts_with(b"HTTP/1.") { return None; }
+    Some((method, path))
+}
+
+/// ヘッダ部の終わり（空行の直後）の位置
+pub fn header_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(4).position(|w| w == b"\r\n\r\n").map(|i| i + 4)
+}
+
+/// フォーム（`a=1&b=2`）から `name` の値を URL デコードして返す。
+/// 無い場合・UTF-8 でない場合・N バイトを超える場合は None。
+pub fn form_value<const N: usize>(body: &[u8], name: &str) -> Option<String<N>> {
+    let pair = body.split(|&b| b == b'&').find(|p| {
+        p.len() > name.len() && p.starts_with(name.as_bytes()) && p[name.len()] == b'='
+    })?;
+    let mut out: heapless::Vec<u8, N> = heapless::Vec::new();
+    let mut v = &pair[name.len() + 1..];
+    while let [b, rest @ ..] = v {
+        let (byte, rest) = match (*b, rest) {
+            (b'+', _) => (b' ', rest),
+            (b'%', [h, l, rest @ ..]) => ((hex_val(*h)? << 4) | hex_val(*l)?, rest),
+            (b'%', _) => return None,
+            (b, _) => (b, rest),
+        };
+        out.push(byte).ok()?;
+        v = rest;
+    }
+    String::from_utf8(out).ok()
+}
+
+fn hex_val(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn trim(mut s: &[u8]) -> &[u8] {
+    while let [first, rest @ ..] = s {
+        if first.is_ascii_whitespace() { s = rest; } else { break; }
+    }
+    while let [rest @ .., last] = s {
+        if last.is_ascii_whitespace() { s = rest; } else { break; }
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESP_429: &[u8] = b"HTTP/1.1 429 Too Many Requests\r\nContent-Type: text/plain\r\nretry-after:  120 \r\n\r\nslow down";
+
+    #[test]
+    fn parses_status_and_retry_after() {
+        assert_eq!(parse_status(RESP_429), Some(429));
+        assert_eq!(parse_retry_after(RESP_429), Some(120));
+        assert_eq!(find_header(RESP_429, "content-type"), Some(&b"text/plain"[..]));
+    }
+
+    #[test]
+    fn parses_next_upload_hint() {
+        let resp = b"HTTP/1.1 200 OK\r\nX-PicoStreet-Next-Upload: 1700003600\r\n\r\n";
+        assert_eq!(parse_next_upload(resp), Some(1_700_003_600));
+        assert_eq!(parse_next_upload(RESP_429), None);
+    }
+
+    #[test]
+    fn ignores_body_and_bad_values() {
+        let resp = b"HTTP/1.1 503 Unavailable\r\nRetry-After: Wed, 21 Oct 2015 07:28:00 GMT\r\n\r\nRetry-After: 5";
+        assert_eq!(parse_status(resp), Some(503));
+        assert_eq!(parse_retry_after(resp), None);
+        assert_eq!(parse_status(b"garbage"), None);
+    }
+
+    #[test]
+    fn only_2xx_reply_is_accepted() {
+        let ok = b"HTTP/1.1 201 Created\r\nX-PicoStreet-Next-Upload: 1700003600\r\n\r\n";
+        assert_eq!(parse_upload_reply(ok), UploadReply::Accepted { next_upload_at: Some(1_700_003_600) });
+        assert_eq!(parse_upload_reply(RESP_429), UploadReply::Rejected { code: 429, retry_after: Some(120) });
+        // 応答なし・壊れた応答は受理とみなさない（送信した記録を消さずに再送する）
+        assert_eq!(parse_upload_reply(b""), UploadReply::NoResponse);
+        assert_eq!(parse_upload_reply(b"HTTP/1.1 2"), UploadReply::Garbled);
+        assert_eq!(parse_upload_reply(b"<html>proxy error</html>"), UploadReply::Garbled);
+    }
+
+    #[test]
+    fn parses_portal_request_and_form() {
+        let req = b"POST /save HTTP/1.1\r\nHost: 192.168.4.1\r\nContent-Length: 40\r\n\r\nssid=My+Home&psk=p%40ss%26word&port=3000";
+        assert_eq!(parse_request_line(req), Some((&b"POST"[..], &b"/save"[..])));
+        assert_eq!(find_header(req, "content-length"), Some(&b"40"[..]));
+        let body = &req[header_end(req).unwrap()..];
+        assert_eq!(form_value::<32>(body, "ssid").unwrap().as_str(), "My Home");
+        assert_eq!(form_value::<32>(body, "psk").unwrap().as_str(), "p@ss&word");
+        assert_eq!(form_value::<4>(body, "port").unwrap().as_str(), "3000");
+        assert_eq!(form_value::<32>(body, "host"), None);
+        assert_eq!(form_value::<3>(body, "port"), None);
+        assert_eq!(form_value::<8>(b"x=%4", "x"), None);
+    }
+}
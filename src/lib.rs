@@ -11,8 +11,28 @@ pub mod constants {
 }
 
 pub mod adv_payload;
+pub mod api_json;
+pub mod ble_addr;
+pub mod captive;
+pub mod config_record;
+pub mod console;
+pub mod crc;
 pub mod device_id;
+pub mod encounter_log;
+pub mod encounter_rules;
+pub mod exchange;
+pub mod export_frame;
+pub mod flash_log;
 pub mod format;
+pub mod gatt_config;
+pub mod gatt_control;
+pub mod http;
+pub mod peer_stats;
+pub mod proximity;
+pub mod request_sig;
+pub mod retry;
+pub mod upload_window;
+pub mod wifi_select;
 
 // WiFi config (kept outside src to avoid committing secrets).
 // Make available to the binary via the crate path `pico_w_id_beacon::wifi_config`.
@@ -5,8 +5,11 @@ use defmt::*;
 use embassy_executor::Spawner;
 use embassy_rp::bind_interrupts;
 use embassy_rp::gpio::{Level, Output};
-use embassy_rp::peripherals::{DMA_CH0, PIO0};
+use embassy_rp::peripherals::{DMA_CH0, PIO0, USB};
 use embassy_rp::pio::{InterruptHandler, Pio};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{Duration, Timer};
 use static_cell::StaticCell;
 use trouble_host::prelude::ExternalController;
 use {defmt_rtt as _, embassy_time as _, panic_probe as _};
@@ -19,13 +22,23 @@ mod timekeeper;
 mod storage;
 mod scheduler;
 mod api_client;
+mod config;
+mod provisioning;
+mod usb_console;
 #[path = "../settings.rs"]
 mod settings;
 use pico_w_id_beacon::device_id;
 use pico_w_id_beacon::format::fmt_bytes_colon;
 
+/// WiFi 監視タスクと BLE ループで共有する CYW43 Control
+pub type SharedControl = Mutex<CriticalSectionRawMutex, cyw43::Control<'static>>;
+
+/// 起動時に WiFi 接続を待つ最大時間（超えたら BLE を先に開始）
+const BOOT_WIFI_WAIT: Duration = Duration::from_secs(20);
+
 bind_interrupts!(struct Irqs {
     PIO0_IRQ_0 => InterruptHandler<PIO0>;
+    USBCTRL_IRQ => embassy_rp::usb::InterruptHandler<USB>;
 });
 
 #[embassy_executor::task]
@@ -41,6 +54,30 @@ async fn main(spawner: Spawner) {
 
     let p = embassy_rp::init(Default::default());
 
+    // 設定を読み込み（保存済みが無ければ settings.rs の既定値）、設定ポータルに入るかを決める
+    // （WiFi 未設定 or BOOTSEL 3秒長押し）。以降のモジュールは config::get() を参照する
+    let mut flash = embassy_rp::flash::Flash::<_, embassy_rp::flash::Blocking, { storage::FLASH_SIZE }>::new_blocking(p.FLASH);
+    config::load(&mut flash);
+    let portal = provisioning::enabled() && (!config::is_configured() || provisioning::button_held(p.BOOTSEL).await);
+    if !provisioning::enabled() && !config::is_configured() {
+        warn!("WiFi未設定ですが PROVISION_AP_SECRET が空のため設定ポータルは起動しません（USB コンソールで設定してください）");
+    }
+
+    // フラッシュ上のすれ違いログを復元（失敗時はRAMのみで動作）。ポータル中は設定保存に使う
+    let portal_flash = if portal {
+        Some(flash)
+    } else {
+        match storage::mount(flash) {
+            Ok(log) => {
+                if spawner.spawn(storage::flash_writer_task(log)).is_err() {
+                    warn!("フラッシュ書き込みタスク起動失敗");
+                }
+            }
+            Err(e) => warn!("フラッシュログのマウント失敗: {}", defmt::Debug2Format(&e)),
+        }
+        None
+    };
+
     #[cfg(feature = "skip-cyw43-firmware")]
     let (fw, clm, btfw) = (&[], &[], &[]);
 
@@ -102,42 +139,51 @@ async fn main(spawner: Spawner) {
         embassy_time::Timer::after_millis(150).await;
     }
 
+    // 設定ポータル（保存後に再起動するため戻らない）
+    if let Some(flash) = portal_flash {
+        provisioning::run(spawner, control, net_device, flash, self_bd_addr).await;
+    }
+
     // BLE Host に接続
     let controller: ExternalController<_, 10> = ExternalController::new(bt_device);
 
-    // まずWiFiへ接続し、DHCP完了後にNTP同期（BLEより優先、ただし短時間で完了）
-    info!("WiFi接続とNTP同期を開始...");
-    let maybe_stack = match wifi::maintain_wifi_connection(spawner, &mut control, net_device).await {
-        Ok(s) => {
-            // NTP時刻同期（失敗しても続行）
-            if let Err(e) = wifi::sync_ntp_time(s).await {
-                warn!("NTP同期に失敗: {}", e);
-            }
-            Some(s)
-        }
-        Err(e) => {
-            warn!("WiFi初期化に失敗しました: {}", e);
-            None
-        }
-    };
+    // Control は WiFi 監視タスクと BLE ループ（LED 表示）で共有する
+    static CONTROL: StaticCell<SharedControl> = StaticCell::new();
+    let control: &'static SharedControl = CONTROL.init(Mutex::new(control));
 
-    // 送信スケジューラを起動（Dev:30秒/Prod:3時）
-    if let Some(stack) = maybe_stack {
-        if let Err(_e) = spawner.spawn(crate::scheduler::uploader_task(stack, self_bd_addr)) {
-            warn!("スケジューラ起動失敗");
-        }
-    } else {
-        // WiFi未接続時は開発モードの心拍ログを起動
-        let _ = spawner.spawn(crate::scheduler::dev_heartbeat());
+    // WiFi: ネットワークスタックを起動し、接続/再接続は監視タスクに任せる
+    info!("WiFi接続とNTP同期を開始...");
+    let stack = wifi::init_stack(spawner, net_device);
+    if spawner.spawn(wifi::wifi_supervisor_task(control, stack)).is_err() {
+        warn!("WiFi監視タスク起動失敗");
     }
 
-    if maybe_stack.is_some() {
+    // 送信スケジューラを起動（接続を待ってから送信。Dev:30秒/Prod:毎日3〜4時の端末ごとの時刻）
+    if let Err(_e) = spawner.spawn(crate::scheduler::uploader_task(stack, self_bd_addr)) {
+        warn!("スケジューラ起動失敗");
+    }
+    // WiFi未接続の間は開発モードの心拍ログを出す
+    let _ = spawner.spawn(crate::scheduler::dev_heartbeat(stack));
+
+    // USB シリアルコンソール（PC から状態確認・ログ取得・設定変更）
+    usb_console::start(spawner, embassy_rp::usb::Driver::new(p.USB, Irqs), control, stack, self_bd_addr);
+
+    // 初回接続とNTP同期を優先（BLEより先、ただし待ちすぎない）
+    let connected = embassy_time::with_timeout(BOOT_WIFI_WAIT, stack.wait_config_up()).await.is_ok();
+    if connected {
+        // NTP は監視タスクが接続直後に同期する。少しだけ完了を待つ
+        let _ = embassy_time::with_timeout(Duration::from_secs(5), async {
+            while timekeeper::now_unix().is_none() {
+                Timer::after_millis(200).await;
+            }
+        })
+        .await;
         info!("WiFi/NTP完了。BLE機能を開始します...");
     } else {
-        info!("WiFi未接続。BLE機能を開始します...");
+        info!("WiFi未接続（バックグラウンドで再試行）。BLE機能を開始します...");
     }
     info!("BLEホスト/コントローラ接続完了");
 
     // 時分割ループ開始（広告→スキャン）
-    ble::advertise_and_scan_loop(controller, &mut control, self_bd_addr).await;
+    ble::advertise_and_scan_loop(controller, control, self_bd_addr).await;
 }
@@ -0,0 +1,113 @@
+//! すれ違い相手ごとの集計（初回/最終検出時刻、検出回数、RSSI統計、滞在時間）
+
+/// 連続検出とみなす最大間隔（秒）。これ以内の再検出は滞在時間に加算する。
+pub const DWELL_GAP_SECS: u64 = 60;
+
+/// 相手1台ぶんの集計値
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PeerStats {
+    /// 初回検出（Unix秒、未同期時は0）
+    pub first_seen: u64,
+    /// 最終検出（Unix秒、未同期時は0）
+    pub last_seen: u64,
+    /// 検出回数
+    pub count: u32,
+    pub rssi_min: i8,
+    pub rssi_max: i8,
+    /// RSSI合計（平均算出用）
+    pub rssi_sum: i32,
+    /// 滞在時間の合計（秒）
+    pub dwell_secs: u32,
+}
+
+impl PeerStats {
+    /// 初回検出で集計を開始
+    pub fn new(timestamp: u64, rssi: i8) -> Self {
+        Self {
+            first_seen: timestamp,
+            last_seen: timestamp,
+            count: 1,
+            rssi_min: rssi,
+            rssi_max: rssi,
+            rssi_sum: rssi as i32,
+            dwell_secs: 0,
+        }
+    }
+
+    /// 確定前から続いていた滞在（`first_seen`〜`timestamp`）を含めて集計を開始する。
+    /// 検出回数・RSSI は `timestamp` の1回ぶん。
+    pub fn starting_at(first_seen: u64, timestamp: u64, rssi: i8) -> Self {
+        let first_seen = first_seen.min(timestamp);
+        Self { first_seen, dwell_secs: (timestamp - first_seen) as u32, ..Self::new(timestamp, rssi) }
+    }
+
+    /// 再検出を反映する。
+    /// 前回検出から DWELL_GAP_SECS 以内なら、その間隔を滞在時間に加算する。
+    pub fn observe(&mut self, timestamp: u64, rssi: i8) {
+        if timestamp != 0 && self.last_seen != 0 && timestamp >= self.last_seen {
+            let gap = timestamp - self.last_seen;
+            if gap <= DWELL_GAP_SECS {
+                self.dwell_secs = self.dwell_secs.saturating_add(gap as u32);
+            }
+        }
+        if self.first_seen == 0 {
+            self.first_seen = timestamp;
+        }
+        if timestamp > self.last_seen {
+            self.last_seen = timestamp;
+        }
+        self.count = self.count.saturating_add(1);
+        self.rssi_min = self.rssi_min.min(rssi);
+        self.rssi_max = self.rssi_max.max(rssi);
+        self.rssi_sum = self.rssi_sum.saturating_add(rssi as i32);
+    }
+
+    /// RSSI平均（小数切り捨て）
+    pub fn rssi_mean(&self) -> i8 {
+        if self.count == 0 {
+            return 0;
+        }
+        (self.rssi_sum / self.count as i32) as i8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn aggregates_rssi_and_dwell() {
+        let mut s = PeerStats::new(1000, -60);
+        s.observe(1010, -70);
+        s.observe(1030, -50);
+        // 長い空白は滞在時間に含めない
+        s.observe(1030 + DWELL_GAP_SECS + 1, -80);
+        assert_eq!(s.first_seen, 1000);
+        assert_eq!(s.last_seen, 1030 + DWELL_GAP_SECS + 1);
+        assert_eq!(s.count, 4);
+        assert_eq!((s.rssi_min, s.rssi_max), (-80, -50));
+        assert_eq!(s.rssi_mean(), -65);
+        assert_eq!(s.dwell_secs, 30);
+    }
+
+    #[test]
+    fn unsynced_time_only_counts() {
+        let mut s = PeerStats::new(0, -40);
+        s.observe(0, -42);
+        assert_eq!(s.count, 2);
+        assert_eq!(s.dwell_secs, 0);
+        // NTP同期後の最初の検出で first_seen が埋まる
+        s.observe(5000, -41);
+        assert_eq!((s.first_seen, s.last_seen), (5000, 5000));
+    }
+
+    #[test]
+    fn starting_at_includes_time_before_commit() {
+        let mut s = PeerStats::starting_at(1000, 1012, -60);
+        assert_eq!((s.first_seen, s.last_seen, s.count, s.dwell_secs), (1000, 1012, 1, 12));
+        s.observe(1020, -62);
+        assert_eq!((s.first_seen, s.last_seen, s.dwell_secs), (1000, 1020, 20));
+        assert_eq!(PeerStats::starting_at(1000, 1000, -60), PeerStats::new(1000, -60));
+    }
+}
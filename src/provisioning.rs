@@ -0,0 +1,298 @@
+//! 設定ポータル（プロビジョニングモード）
+//! - CYW43 をソフトAP（WPA2）として起動し、DHCP / DNS / HTTP で設定フォームを配信する
+//! - 送信された WiFi / API 接続先・動作モードを config へ保存して再起動する
+//! - 入る条件: 接続先の設定が無いとき、または起動時に BOOTSEL ボタンを3秒押し続けたとき
+//! - AP のパスワードは端末ごとに導出する（`captive::portal_psk`）。秘密値が未設定ならポータルは使わない
+use core::fmt::Write as _;
+
+use defmt::*;
+use embassy_futures::select::select3;
+use embassy_net::tcp::TcpSocket;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpAddress, IpEndpoint, Ipv4Address, Stack};
+use embassy_rp::peripherals::BOOTSEL;
+use embassy_rp::Peri;
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use embedded_io_async::Write;
+use heapless::String;
+use pico_w_id_beacon::captive::{dns_reply, portal_psk, DhcpServer, DHCP_REPLY_MAX};
+use pico_w_id_beacon::config_record::ConfigRecord;
+use pico_w_id_beacon::http;
+
+use crate::storage::FlashDev;
+use crate::{config, settings, wifi};
+
+/// ポータルの AP アドレス（端末には .2 以降を貸し出す）
+const AP_ADDR: [u8; 4] = [192, 168, 4, 1];
+/// ソフトAP のチャネル
+const AP_CHANNEL: u8 = 6;
+/// ポータルに入るための BOOTSEL 長押し時間
+const HOLD_TO_ENTER: Duration = Duration::from_secs(3);
+/// 受け付けるリクエストの最大長（ヘッダ + フォーム）
+const REQUEST_MAX: usize = 1024;
+
+/// 設定ポータルを使えるか（AP パスワードの秘密値が設定されているか）
+pub fn enabled() -> bool {
+    !settings::PROVISION_AP_SECRET.is_empty()
+}
+
+/// 起動時に BOOTSEL ボタンが押されていれば、HOLD_TO_ENTER の間押し続けられたかを返す。
+pub async fn button_held(mut bootsel: Peri<'_, BOOTSEL>) -> bool {
+    use embassy_rp::bootsel::is_bootsel_pressed;
+
+    if !is_bootsel_pressed(bootsel.reborrow()) {
+        return false;
+    }
+    info!("BOOTSEL押下を検出: 3秒押し続けると設定ポータルを起動します");
+    let t0 = Instant::now();
+    while Instant::now() - t0 < HOLD_TO_ENTER {
+        Timer::after_millis(100).await;
+        if !is_bootsel_pressed(bootsel.reborrow()) {
+            info!("BOOTSELが離されたため通常起動します");
+            return false;
+        }
+    }
+    true
+}
+
+/// 設定ポータルを実行する。設定が保存されると再起動するため戻らない。
+pub async fn run(
+    spawner: embassy_executor::Spawner,
+    mut control: cyw43::Control<'static>,
+    net_device: cyw43::NetDriver<'static>,
+    mut flash: FlashDev,
+    bd_addr: [u8; 6],
+) -> ! {
+    let mut ssid: String<32> = String::new();
+    let _ = write!(ssid, "PicoStreet-{:02X}{:02X}", bd_addr[4], bd_addr[5]);
+    let psk = portal_psk(settings::PROVISION_AP_SECRET, &bd_addr);
+    control.start_ap_wpa2(ssid.as_str(), psk.as_str(), AP_CHANNEL).await;
+    // ポータル中は内蔵LEDを点灯したままにする
+    control.gpio_set(0, true).await;
+    info!(
+        "設定ポータル開始: SSID='{}'（パスワード {}）に接続し http://{}.{}.{}.{}/ を開いてください",
+        ssid.as_str(), psk.as_str(), AP_ADDR[0], AP_ADDR[1], AP_ADDR[2], AP_ADDR[3]
+    );
+
+    let stack = wifi::init_ap_stack(spawner, net_device, AP_ADDR);
+    // DHCP / DNS は止まらない。HTTP は設定を保存すると戻る
+    select3(dhcp_server(stack), dns_server(stack), http_server(stack, &mut flash)).await;
+
+    info!("設定を保存しました。再起動します");
+    control.gpio_set(0, false).await;
+    Timer::after_secs(1).await;
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+/// ポータルに接続した端末へアドレスを配る
+async fn dhcp_server(stack: Stack<'static>) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buf = [0u8; 1024];
+    let mut tx_buf = [0u8; 1024];
+    let mut sock = UdpSocket::new(stack, &mut rx_meta, &mut rx_buf, &mut tx_meta, &mut tx_buf);
+    if sock.bind(67).is_err() {
+        warn!("DHCPサーバ: bind失敗");
+        core::future::pending::<()>().await;
+    }
+
+    let mut server = DhcpServer::new(AP_ADDR);
+    let mut req = [0u8; 576];
+    let mut resp = [0u8; DHCP_REPLY_MAX];
+    loop {
+        let Ok((n, _)) = sock.recv_from(&mut req).await else { continue };
+        if let Some(len) = server.handle(&req[..n], &mut resp) {
+            let to = IpEndpoint::new(IpAddress::Ipv4(Ipv4Address::BROADCAST), 68);
+            let _ = sock.send_to(&resp[..len], to).await;
+        }
+    }
+}
+
+/// すべての名前を AP アドレスへ解決する（OS のポータル検出でフォームを開かせる）
+async fn dns_server(stack: Stack<'static>) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buf = [0u8; 512];
+    let mut tx_buf = [0u8; 512];
+    let mut sock = UdpSocket::new(stack, &mut rx_meta, &mut rx_buf, &mut tx_meta, &mut tx_buf);
+    if sock.bind(53).is_err() {
+        warn!("DNS応答: bind失敗");
+        core::future::pending::<()>().await;
+    }
+
+    let mut req = [0u8; 256];
+    let mut resp = [0u8; 288];
+    loop {
+        let Ok((n, meta)) = sock.recv_from(&mut req).await else { continue };
+        if let Some(len) = dns_reply(&req[..n], AP_ADDR, &mut resp) {
+            let _ = sock.send_to(&resp[..len], meta.endpoint).await;
+        }
+    }
+}
+
+/// 設定フォームを配信し、保存に成功したら戻る
+async fn http_server(stack: Stack<'static>, flash: &mut FlashDev) {
+    let mut rx_buf = [0u8; 1024];
+    let mut tx_buf = [0u8; 2048];
+    let mut req = [0u8; REQUEST_MAX];
+    loop {
+        let mut sock = TcpSocket::new(stack, &mut rx_buf, &mut tx_buf);
+        sock.set_timeout(Some(Duration::from_secs(10)));
+        if sock.accept(80).await.is_err() {
+            continue;
+        }
+        let n = read_request(&mut sock, &mut req).await;
+        let saved = handle_request(&mut sock, &req[..n], flash).await;
+        let _ = sock.flush().await;
+        sock.close();
+        Timer::after_millis(100).await;
+        if saved {
+            return;
+        }
+    }
+}
+
+/// ヘッダと Content-Length 分のボディを読み込み、読んだ長さを返す
+async fn read_request(sock: &mut TcpSocket<'_>, buf: &mut [u8]) -> usize {
+    let mut n = 0;
+    while n < buf.len() {
+        match with_timeout(Duration::from_secs(5), sock.read(&mut buf[n..])).await {
+            Ok(Ok(0)) | Ok(Err(_)) | Err(_) => break,
+            Ok(Ok(m)) => n += m,
+        }
+        if let Some(end) = http::header_end(&buf[..n]) {
+            let body_len = http::find_header(&buf[..end], "Content-Length")
+                .and_then(http::parse_u64)
+                .unwrap_or(0) as usize;
+            if n >= end + body_len {
+                break;
+            }
+        }
+    }
+    n
+}
+
+/// リクエストを処理して応答を書く。設定を保存したら true。
+async fn handle_request(sock: &mut TcpSocket<'_>, req: &[u8], flash: &mut FlashDev) -> bool {
+    let is_save = matches!(http::parse_request_line(req), Some((b"POST", b"/save")));
+    if !is_save {
+        // キャプティブポータル: どのパスにもフォームを返す
+        let _ = write_form(sock, "200 OK", None).await;
+        return false;
+    }
+    let body = http::header_end(req).map_or(&[][..], |end| &req[end..]);
+    let rec = match parse_form(body) {
+        Ok(rec) => rec,
+        Err(msg) => {
+            let _ = write_form(sock, "400 Bad Request", Some(msg)).await;
+            return false;
+        }
+    };
+    if let Err(e) = config::store(flash, &rec) {
+        warn!("設定の保存に失敗: {}", defmt::Debug2Format(&e));
+        let _ = write_form(sock, "500 Internal Server Error", Some("フラッシュへの保存に失敗しました")).await;
+        return false;
+    }
+    info!("設定を保存: SSID='{}' API={}:{}{}", rec.wifi_ssid.as_str(), rec.api_host.as_str(), rec.api_port, rec.api_path.as_str());
+    let _ = write_page(sock, "200 OK", "<h1>保存しました</h1><p>再起動して接続します。この画面は閉じてください。</p>").await;
+    true
+}
+
+/// フォームの値を検証して設定レコードにする。空欄の API 項目は現在の設定を引き継ぐ。
+fn parse_form(body: &[u8]) -> Result<ConfigRecord, &'static str> {
+    let mut rec = config::get().clone();
+    rec.wifi_ssid = http::form_value(body, "ssid").ok_or("SSIDは1〜32バイトで入力してください")?;
+    if rec.wifi_ssid.is_empty() {
+        return Err("SSIDを入力してください");
+    }
+    rec.wifi_psk = http::form_value(body, "psk").ok_or("パスワードが長すぎます")?;
+    if !(8..=63).contains(&rec.wifi_psk.len()) {
+        return Err("パスワードは8〜63文字で入力してください");
+    }
+    let api_host: String<64> = http::form_value(body, "host").ok_or("APIホストが長すぎます")?;
+    if !api_host.is_empty() {
+        rec.api_host = api_host;
+    }
+    let port: String<5> = http::form_value(body, "port").unwrap_or_default();
+    if !port.is_empty() {
+        rec.api_port = http::parse_u64(port.as_bytes())
+            .and_then(|p| u16::try_from(p).ok())
+            .filter(|&p| p != 0)
+            .ok_or("APIポートは1〜65535で入力してください")?;
+    }
+    let api_path: String<64> = http::form_value(body, "path").ok_or("APIパスが長すぎます")?;
+    if !api_path.is_empty() {
+        if !api_path.starts_with('/') {
+            return Err("APIパスは / で始めてください");
+        }
+        rec.api_path = api_path;
+    }
+    // チェックボックスは未チェックだと送られない
+    rec.developer_mode = http::form_value::<8>(body, "dev").is_some();
+    Ok(rec)
+}
+
+/// 設定フォーム（現在の設定を初期値にする。パスワードは表示しない）
+async fn write_form(sock: &mut TcpSocket<'_>, status: &str, error: Option<&str>) -> Result<(), embassy_net::tcp::Error> {
+    write_head(sock, status).await?;
+    sock.write_all("<h1>PicoStreet 設定</h1>".as_bytes()).await?;
+    if let Some(msg) = error {
+        sock.write_all(b"<p style=\"color:red\">").await?;
+        write_escaped(sock, msg).await?;
+        sock.write_all(b"</p>").await?;
+    }
+    let cfg = config::get();
+    let mut port: String<5> = String::new();
+    let _ = write!(port, "{}", cfg.api_port);
+
+    sock.write_all(b"<form method=\"post\" action=\"/save\"><p>WiFi SSID<br><input name=\"ssid\" maxlength=\"32\" required value=\"").await?;
+    write_escaped(sock, cfg.wifi_ssid.as_str()).await?;
+    sock.write_all("\"></p><p>WiFi パスワード<br><input name=\"psk\" type=\"password\" minlength=\"8\" maxlength=\"63\" required></p><p>API ホスト<br><input name=\"host\" maxlength=\"64\" value=\"".as_bytes()).await?;
+    write_escaped(sock, cfg.api_host.as_str()).await?;
+    sock.write_all("\"></p><p>API ポート<br><input name=\"port\" type=\"number\" min=\"1\" max=\"65535\" value=\"".as_bytes()).await?;
+    sock.write_all(port.as_bytes()).await?;
+    sock.write_all("\"></p><p>API パス<br><input name=\"path\" maxlength=\"64\" value=\"".as_bytes()).await?;
+    write_escaped(sock, cfg.api_path.as_str()).await?;
+    sock.write_all("\"></p><p><label><input name=\"dev\" type=\"checkbox\" value=\"1\"".as_bytes()).await?;
+    if cfg.developer_mode {
+        sock.write_all(b" checked").await?;
+    }
+    sock.write_all("> デベロッパーモード（30秒毎に送信）</label></p><p><button type=\"submit\">保存して再起動</button></p></form></body></html>".as_bytes()).await
+}
+
+/// 短いメッセージだけのページ
+async fn write_page(sock: &mut TcpSocket<'_>, status: &str, html: &str) -> Result<(), embassy_net::tcp::Error> {
+    write_head(sock, status).await?;
+    sock.write_all(html.as_bytes()).await?;
+    sock.write_all(b"</body></html>").await
+}
+
+/// ステータス行・ヘッダと HTML の先頭（長さは接続を閉じて示す）
+async fn write_head(sock: &mut TcpSocket<'_>, status: &str) -> Result<(), embassy_net::tcp::Error> {
+    sock.write_all(b"HTTP/1.1 ").await?;
+    sock.write_all(status.as_bytes()).await?;
+    sock.write_all(b"\r\nContent-Type: text/html; charset=utf-8\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n").await?;
+    sock.write_all(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><meta name=\"viewport\" content=\"width=device-width,initial-scale=1\"><title>PicoStreet 設定</title></head><body>"
+            .as_bytes(),
+    )
+    .await
+}
+
+/// HTML 属性値/本文として安全な形で書き出す
+async fn write_escaped(sock: &mut TcpSocket<'_>, s: &str) -> Result<(), embassy_net::tcp::Error> {
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        let esc: &[u8] = match c {
+            '&' => b"&amp;",
+            '"' => b"&quot;",
+            '<' => b"&lt;",
+            '>' => b"&gt;",
+            _ => continue,
+        };
+        sock.write_all(&s.as_bytes()[start..i]).await?;
+        sock.write_all(esc).await?;
+        start = i + 1;
+    }
+    sock.write_all(&s.as_bytes()[start..]).await
+}
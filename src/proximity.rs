@@ -0,0 +1,105 @@
+//! RSSI と相手の TX 電力からの距離推定（対数距離パスロスモデル）
+//! - 距離 d[m] = 10 ^ ((TX電力 - 1mでの損失 - RSSI) / (10 × n))
+//! - 浮動小数点を使わないよう 10^(k/20) の表で計算する（誤差は数%程度。目安として使う）
+
+/// 1m でのパスロス（dB）。2.4GHz の自由空間損失 + アンテナ損失の目安
+pub const PATH_LOSS_1M_DB: i16 = 41;
+
+/// パスロス指数 n の10倍（屋内の目安 2.5）
+pub const PATH_LOSS_EXPONENT_X10: u16 = 25;
+
+/// 推定距離の上限（cm）
+pub const MAX_DISTANCE_CM: u32 = 10_000;
+
+/// Immediate とみなす距離（cm 未満）
+pub const IMMEDIATE_CM: u32 = 50;
+/// Near とみなす距離（cm 未満）
+pub const NEAR_CM: u32 = 300;
+
+/// 近さの区分
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Proximity {
+    /// 〜50cm（手渡しできる距離）
+    Immediate,
+    /// 〜3m（会話できる距離）
+    Near,
+    /// 3m〜
+    Far,
+    /// TX 電力が分からない
+    Unknown,
+}
+
+impl Proximity {
+    pub fn from_distance_cm(cm: Option<u32>) -> Self {
+        match cm {
+            None => Self::Unknown,
+            Some(cm) if cm < IMMEDIATE_CM => Self::Immediate,
+            Some(cm) if cm < NEAR_CM => Self::Near,
+            Some(_) => Self::Far,
+        }
+    }
+
+    /// 送信・表示用の名前
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Immediate => "immediate",
+            Self::Near => "near",
+            Self::Far => "far",
+            Self::Unknown => "unknown",
+        }
+    }
+}
+
+/// 10^(k/20) × 1000（k = 0..19）
+const POW10_20THS: [u32; 20] = [
+    1000, 1122, 1259, 1413, 1585, 1778, 1995, 2239, 2512, 2818, 3162, 3548, 3981, 4467, 5012, 5623, 6310, 7079, 7943,
+    8913,
+];
+
+/// 推定距離（cm）。TX 電力が分からなければ None。`MAX_DISTANCE_CM` で頭打ち。
+pub fn estimate_distance_cm(tx_power: Option<i8>, rssi: i8) -> Option<u32> {
+    let loss = tx_power? as i16 - PATH_LOSS_1M_DB - rssi as i16;
+    // d = 10^(loss / (10n)) = 10^(k / 20)、k = 20 × loss / (10n)
+    let k = 20 * loss as i32 / PATH_LOSS_EXPONENT_X10 as i32;
+    let (tens, frac) = (k.div_euclid(20), k.rem_euclid(20) as usize);
+    let mut cm = POW10_20THS[frac] / 10; // 1m = 100cm
+    if tens < 0 {
+        for _ in 0..-tens {
+            cm /= 10;
+        }
+        return Some(cm);
+    }
+    for _ in 0..tens {
+        cm = cm.saturating_mul(10);
+        if cm >= MAX_DISTANCE_CM {
+            return Some(MAX_DISTANCE_CM);
+        }
+    }
+    Some(cm.min(MAX_DISTANCE_CM))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn distance_follows_path_loss_model() {
+        // 損失が 1m 分だけなら 1m
+        assert_eq!(estimate_distance_cm(Some(0), -41), Some(100));
+        // n=2.5 で +25dB なら 10m
+        assert_eq!(estimate_distance_cm(Some(0), -66), Some(1000));
+        // 1m より強ければ 1m 未満
+        assert_eq!(estimate_distance_cm(Some(0), -31), Some(39));
+        assert_eq!(estimate_distance_cm(Some(4), -120), Some(MAX_DISTANCE_CM));
+        assert_eq!(estimate_distance_cm(None, -50), None);
+    }
+
+    #[test]
+    fn buckets() {
+        assert_eq!(Proximity::from_distance_cm(estimate_distance_cm(Some(0), -31)), Proximity::Immediate);
+        assert_eq!(Proximity::from_distance_cm(estimate_distance_cm(Some(0), -45)), Proximity::Near);
+        assert_eq!(Proximity::from_distance_cm(estimate_distance_cm(Some(0), -70)), Proximity::Far);
+        assert_eq!(Proximity::from_distance_cm(None), Proximity::Unknown);
+    }
+}
@@ -0,0 +1,64 @@
+//! 送信失敗時の再試行ポリシー（指数バックオフ + ジッタ）
+
+/// 再試行ポリシー
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// 1回目の再試行までの基準待ち時間（秒）
+    pub base_secs: u32,
+    /// 待ち時間の上限（秒）。Retry-After もこの値で頭打ちにする
+    pub max_secs: u32,
+    /// 最大試行回数（初回送信を含む）
+    pub max_attempts: u32,
+}
+
+impl RetryPolicy {
+    /// `attempt` 回目（1始まり）の失敗後に待つ秒数。
+    /// 指数バックオフ `base * 2^(attempt-1)` を上限で切り、その後半分〜全体の範囲でジッタをかける
+    /// （`rand` は任意の乱数）。
+    pub fn delay_secs(&self, attempt: u32, rand: u32) -> u32 {
+        let exp = attempt.saturating_sub(1).min(31);
+        let ceil = self.base_secs.saturating_mul(1u32 << exp).min(self.max_secs).max(1);
+        let half = ceil / 2;
+        half + rand % (ceil - half + 1)
+    }
+
+    /// サーバ指定の Retry-After があればそれを優先する（上限で頭打ち）
+    pub fn next_delay_secs(&self, attempt: u32, retry_after: Option<u32>, rand: u32) -> u32 {
+        match retry_after {
+            Some(s) => s.min(self.max_secs),
+            None => self.delay_secs(attempt, rand),
+        }
+    }
+
+    /// まだ再試行してよいか（`attempt` は失敗済みの試行回数）
+    pub fn should_retry(&self, attempt: u32) -> bool {
+        attempt < self.max_attempts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: RetryPolicy = RetryPolicy { base_secs: 30, max_secs: 3600, max_attempts: 6 };
+
+    #[test]
+    fn grows_exponentially_within_jitter_bounds() {
+        for (attempt, ceil) in [(1, 30), (2, 60), (3, 120), (4, 240)] {
+            assert_eq!(POLICY.delay_secs(attempt, 0), ceil / 2);
+            for r in [1u32, 7, 12345, u32::MAX] {
+                let d = POLICY.delay_secs(attempt, r);
+                assert!(d >= ceil / 2 && d <= ceil, "attempt={} d={}", attempt, d);
+            }
+        }
+    }
+
+    #[test]
+    fn caps_at_max_and_honors_retry_after() {
+        assert!(POLICY.delay_secs(30, 999) <= 3600);
+        assert_eq!(POLICY.next_delay_secs(1, Some(90), 0), 90);
+        assert_eq!(POLICY.next_delay_secs(1, Some(86_400), 0), 3600);
+        assert!(POLICY.should_retry(5));
+        assert!(!POLICY.should_retry(6));
+    }
+}
@@ -1,69 +1,157 @@
-//! 送信スケジューラ（毎日3時に送信）
+//! 送信スケジューラ（本番: 毎日の送信ウィンドウ内で端末ごとにずらした時刻に送信）
+//! - `request_upload` で待ち時間を打ち切ってすぐに送信する（BLE の制御コマンドから）
+//! - 送信（スナップショット → POST → 確定）は `upload_once` 内で1つずつ行う（USB コンソールの upload now と重ならない）
+use core::cell::Cell;
+
 use defmt::*;
+use embassy_futures::select::select;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::mutex::Mutex as AsyncMutex;
+use embassy_sync::signal::Signal;
 use embassy_time::{Timer, Duration};
 use embassy_net::Stack;
 
-use crate::api_client::{ApiPayload, send_encounters_to_server};
+use embassy_rp::clocks::RoscRng;
+use rand_core::RngCore;
+
+use crate::api_client::{ApiError, ApiPayload, send_encounters_to_server};
 use crate::storage::{EncounterLog, MAX_ENCOUNTERS, snapshot};
+use pico_w_id_beacon::retry::RetryPolicy;
+use pico_w_id_beacon::upload_window::{hint_delay_secs, UploadWindow, JST_OFFSET_SECS};
+
+/// 本番モードの送信再試行（30秒から倍々、最大1時間、初回含め8回まで）
+const RETRY_POLICY: RetryPolicy = RetryPolicy { base_secs: 30, max_secs: 3600, max_attempts: 8 };
+
+/// 本番モードの送信ウィンドウ（JST）
+const UPLOAD_WINDOW: UploadWindow = UploadWindow {
+    start_secs: crate::settings::UPLOAD_WINDOW_START_SECS,
+    length_secs: crate::settings::UPLOAD_WINDOW_LEN_SECS,
+    utc_offset_secs: JST_OFFSET_SECS,
+};
+
+/// すぐに送信する要求（WiFi 未接続なら接続後に送信）
+static UPLOAD_REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// 送信中（同時に送ると同じ行を二重に POST し、確定も競合するため1つずつ行う）
+static UPLOAD_LOCK: AsyncMutex<CriticalSectionRawMutex, ()> = AsyncMutex::new(());
+
+/// 最後に送信に成功した時刻（Unix秒、未同期で送信した場合は0）
+static LAST_UPLOAD: Mutex<CriticalSectionRawMutex, Cell<Option<u64>>> = Mutex::new(Cell::new(None));
+
+/// すぐに送信するよう要求する。
+pub fn request_upload() {
+    UPLOAD_REQUEST.signal(());
+}
+
+/// 起動後に最後に送信に成功した時刻（Unix秒）。まだ送信していなければ None。
+pub fn last_upload() -> Option<u64> {
+    LAST_UPLOAD.lock(|c| c.get())
+}
+
+/// `d` だけ待つ。途中で送信要求があればすぐに戻る。
+async fn wait_or_request(d: Duration) {
+    select(Timer::after(d), UPLOAD_REQUEST.wait()).await;
+}
 
-/// スケジューラタスクを起動（現在時刻が取得できている前提）。
+/// スケジューラタスク（起動直後から spawn してよい。WiFi 接続を待ってから送信する）。
 #[embassy_executor::task]
 pub async fn uploader_task(stack: Stack<'static>, device_id: [u8; 6]) -> ! {
+    // サーバから指示された次回送信時刻（本番モードのみ使用）
+    let mut next_hint: Option<u64> = None;
     loop {
-        if crate::settings::is_developer_mode() {
+        // 未接続の間は待機（再接続は wifi_supervisor_task が行う）
+        if !stack.is_config_up() {
+            stack.wait_config_up().await;
+            info!("WiFi接続を確認。送信スケジューラを開始します");
+        }
+        if crate::config::is_developer_mode() {
             // Dev: 30秒毎に送信
-            Timer::after(Duration::from_secs(30)).await;
-            let reported_at = crate::timekeeper::now_unix().unwrap_or(0);
-            info!("[DEV] 送信タイミング到来（30秒） reported_at={}", reported_at as u32);
-            let mut buf: heapless::Vec<EncounterLog, MAX_ENCOUNTERS> = heapless::Vec::new();
-            let count = snapshot(&mut buf);
-            if count == 0 {
-                info!("[DEV] 送信対象0件（スキップ）");
-                continue;
-            }
-            let payload = ApiPayload { device_id, encounters: &buf[..count], reported_at };
-            match send_encounters_to_server(stack, &payload).await {
-                Ok(()) => info!("[DEV] API送信成功 件数={}", count as u32),
+            wait_or_request(Duration::from_secs(30)).await;
+            info!("[DEV] 送信タイミング到来（30秒） reported_at={}", crate::timekeeper::now_unix().unwrap_or(0) as u32);
+            match upload_once(stack, device_id).await {
+                Ok((0, _)) => info!("[DEV] 送信対象0件（スキップ）"),
+                Ok((count, _)) => info!("[DEV] API送信成功 件数={}", count as u32),
                 Err(e) => warn!("[DEV] API送信失敗: {}", e),
             }
         } else {
-            // Prod: 毎日3時
+            // Prod: 毎日の送信ウィンドウ内（端末ごとのオフセット）またはサーバ指示の時刻
             // 現在時刻を取得（未同期なら少し待って再試行）
             let now = match crate::timekeeper::now_unix() {
                 Some(x) => x,
                 None => { Timer::after(Duration::from_secs(10)).await; continue; }
             };
 
-            // 次の3時（JST）まで待機
-            let local = now + 9 * 3600;
-            let sec_day = local % 86_400;
-            let target = 3 * 3600u64; // 03:00:00
-            let sleep = if sec_day <= target { target - sec_day } else { 86_400 - (sec_day - target) };
+            let sleep = match hint_delay_secs(now, next_hint.take()) {
+                Some(s) => {
+                    info!("サーバ指示の時刻に送信します");
+                    s
+                }
+                None => UPLOAD_WINDOW.secs_until_next(now, &device_id),
+            };
             info!("次の送信まで{}秒", sleep);
-            Timer::after(Duration::from_secs(sleep)).await;
+            wait_or_request(Duration::from_secs(sleep)).await;
 
-            let mut buf: heapless::Vec<EncounterLog, MAX_ENCOUNTERS> = heapless::Vec::new();
-            let count = snapshot(&mut buf);
-            if count == 0 { continue; }
-            let payload = ApiPayload { device_id, encounters: &buf[..count], reported_at: now };
-            match send_encounters_to_server(stack, &payload).await {
-                Ok(()) => {
-                    info!("送信成功。バッファをクリアします");
-                    crate::storage::clear();
+            // 送信（失敗時は指数バックオフで再試行し、翌日まで持ち越さない）
+            let mut attempt = 0u32;
+            loop {
+                attempt += 1;
+                match upload_once(stack, device_id).await {
+                    Ok((0, _)) => break,
+                    Ok((count, hint)) => {
+                        info!("送信成功 件数={}。送信済み分を削除しました", count as u32);
+                        next_hint = hint;
+                        break;
+                    }
+                    Err(e) => {
+                        if !e.is_retryable() || !RETRY_POLICY.should_retry(attempt) {
+                            warn!("送信失敗（再試行しない, 試行{}回）: {}", attempt, e);
+                            break;
+                        }
+                        let wait = RETRY_POLICY.next_delay_secs(attempt, e.retry_after(), RoscRng.next_u32());
+                        warn!("送信失敗（{}秒後に再試行, 試行{}回）: {}", wait, attempt, e);
+                        Timer::after(Duration::from_secs(wait as u64)).await;
+                    }
                 }
-                Err(e) => warn!("送信失敗: {}", e),
             }
         }
     }
 }
 
+/// スナップショットを取り、未送信分を1回送信する（USB コンソールの upload now からも使う）。
+/// 他の送信中は終わるまで待ち、その確定後のスナップショットを送る。
+/// 成功時は (送信件数（0=送信対象なし）, サーバ指示の次回送信時刻)。
+pub async fn upload_once(stack: Stack<'static>, device_id: [u8; 6]) -> Result<(usize, Option<u64>), ApiError> {
+    let _sending = UPLOAD_LOCK.lock().await;
+    let mut buf: heapless::Vec<EncounterLog, MAX_ENCOUNTERS> = heapless::Vec::new();
+    let count = snapshot(&mut buf).await;
+    if count == 0 {
+        return Ok((0, None));
+    }
+    // 送信時点の時刻（署名のタイムスタンプにも使う）
+    let reported_at = crate::timekeeper::now_unix().unwrap_or(0);
+    let payload = ApiPayload { device_id, encounters: &buf[..count], reported_at };
+    let res = send_encounters_to_server(stack, &payload).await?;
+    LAST_UPLOAD.lock(|c| c.set(Some(reported_at)));
+    commit_sent(&buf[..count]).await;
+    Ok((count, res.next_upload_at))
+}
+
+/// 送信できた行の最大 seq までを確定し、storage から削除する。
+/// スナップショット後に保存・更新された行は seq が大きいため残る。
+async fn commit_sent(sent: &[EncounterLog]) {
+    if let Some(upto) = sent.iter().map(|e| e.seq).max() {
+        crate::storage::commit(upto).await;
+    }
+}
+
 /// DevモードでWiFi未接続時のハートビート（30秒毎に状況を出力）
 #[embassy_executor::task]
-pub async fn dev_heartbeat() -> ! {
+pub async fn dev_heartbeat(stack: Stack<'static>) -> ! {
     loop {
-        if crate::settings::is_developer_mode() {
+        if crate::config::is_developer_mode() && !stack.is_config_up() {
             let mut buf: heapless::Vec<EncounterLog, MAX_ENCOUNTERS> = heapless::Vec::new();
-            let count = snapshot(&mut buf);
+            let count = snapshot(&mut buf).await;
             info!("[DEV] WiFi未接続 or 初期化前。件数={}（送信スキップ）", count as u32);
             Timer::after(Duration::from_secs(30)).await;
         } else {
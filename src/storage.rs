@@ -1,47 +1,288 @@
 //! 簡易すれ違いログ保存（no_std, heapless）
+//! - RAM 上のバッファに加え、フラッシュ予約領域（memory.x の STORAGE）へ追記して再起動後も保持
+//! - フラッシュ書き込みは `flash_writer_task` がキュー経由で行う（BLEコールバックから直接書かない）
+//! - 検出は差分として追記し、ログ領域が埋まる前に集計表全体（チェックポイント）を書き直す。
+//!   最新のチェックポイントより古いセクタだけを再利用するので、未送信の集計が消えない
+//! - 検出は `encounter_rules` の条件（RSSI 下限・検出回数・滞在時間）を満たした相手だけを記録する
 use core::cell::RefCell;
 
 use defmt::*;
-use portable_atomic::{AtomicU32, Ordering};
+use portable_atomic::{AtomicBool, AtomicU32, Ordering};
+use pico_w_id_beacon::adv_payload::{Nickname, MAX_NICKNAME_LEN};
+use pico_w_id_beacon::encounter_rules::{EncounterGate, EncounterRules, Verdict};
+use pico_w_id_beacon::flash_log::{FlashLog, LogError};
 use pico_w_id_beacon::format::fmt_bytes_colon;
+use pico_w_id_beacon::peer_stats::PeerStats;
+use embassy_futures::select::{select, Either};
+use embassy_rp::flash::{Blocking, Flash};
+use embassy_rp::peripherals::FLASH;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_sync::channel::Channel;
 use embassy_sync::mutex::{Mutex, TryLockError};
+use embassy_time::{Duration, Instant, Timer};
 use heapless::Vec;
 
-/// すれ違いログ1件
-#[derive(Clone, Copy, PartialEq, Eq)]
-pub struct EncounterLog {
-    pub mac_addr: [u8; 6],
-    pub timestamp: u64, // Unix秒（未取得時は0でも可）
-    pub rssi: i8,
-}
+use crate::settings;
+
+pub use pico_w_id_beacon::encounter_log::EncounterLog;
 
-impl defmt::Format for EncounterLog {
+/// ログ出力用（EncounterLog は lib 側の型なので defmt 表示はここで包んで実装する）
+struct LogLine<'a>(&'a EncounterLog);
+
+impl defmt::Format for LogLine<'_> {
     fn format(&self, f: defmt::Formatter) {
+        let e = self.0;
         defmt::write!(
             f,
-            "{{ mac={:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}, ts={}, rssi={} }}",
-            self.mac_addr[0], self.mac_addr[1], self.mac_addr[2],
-            self.mac_addr[3], self.mac_addr[4], self.mac_addr[5],
-            self.timestamp, self.rssi
+            "{{ seq={}, mac={:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}, first={}, last={}, count={}, rssi={}/{}/{}, dwell={}s, proximity={}, mutual={}, id={} }}",
+            e.seq,
+            e.mac_addr[0], e.mac_addr[1], e.mac_addr[2],
+            e.mac_addr[3], e.mac_addr[4], e.mac_addr[5],
+            e.stats.first_seen, e.stats.last_seen, e.stats.count,
+            e.stats.rssi_min, e.stats.rssi_mean(), e.stats.rssi_max,
+            e.stats.dwell_secs,
+            e.proximity().as_str(),
+            e.mutual,
+            e.id_kind()
         );
+        if let Some(n) = &e.nickname {
+            defmt::write!(f, " nickname={}", n.as_str());
+        }
+    }
+}
+/// 検出1回ぶん（フラッシュにはこの単位で追記し、起動時に再集計する）
+#[derive(Clone, Copy)]
+struct Sighting {
+    seq: u32,
+    mac_addr: [u8; 6],
+    timestamp: u64, // Unix秒（未取得時は0でも可）
+    rssi: i8,
+    tx_power: Option<i8>,
+    /// `mac_addr` が一時ID か
+    ephemeral: bool,
+    /// 新しい行の初回検出を `timestamp` の何秒前にするか（すれ違い確定までの区間。既存の行には使わない）
+    lead_secs: u32,
+}
+
+impl Sighting {
+    /// フラッシュ記録用のバイト列長（旧形式は TX 電力なしの19バイト、フラグなしの20バイト、開始時刻なしの21バイト）
+    const ENCODED_LEN: usize = 25;
+    const ENCODED_LEN_V1: usize = 19;
+    /// TX 電力なし（HCI と同じ 127 = 不明）
+    const TX_POWER_UNKNOWN: u8 = 0x7F;
+    /// フラグ: 一時ID
+    const FLAG_EPHEMERAL: u8 = 0x01;
+
+    fn encode(&self) -> [u8; Self::ENCODED_LEN] {
+        let mut b = [0u8; Self::ENCODED_LEN];
+        b[0..6].copy_from_slice(&self.mac_addr);
+        b[6..14].copy_from_slice(&self.timestamp.to_le_bytes());
+        b[14] = self.rssi as u8;
+        b[15..19].copy_from_slice(&self.seq.to_le_bytes());
+        b[19] = self.tx_power.map_or(Self::TX_POWER_UNKNOWN, |p| p as u8);
+        b[20] = if self.ephemeral { Self::FLAG_EPHEMERAL } else { 0 };
+        b[21..25].copy_from_slice(&self.lead_secs.to_le_bytes());
+        b
+    }
+
+    fn decode(b: &[u8]) -> Option<Self> {
+        if b.len() < Self::ENCODED_LEN_V1 {
+            return None;
+        }
+        let mut mac_addr = [0u8; 6];
+        mac_addr.copy_from_slice(&b[0..6]);
+        let mut ts = [0u8; 8];
+        ts.copy_from_slice(&b[6..14]);
+        let seq = u32::from_le_bytes([b[15], b[16], b[17], b[18]]);
+        let tx_power = b.get(19).filter(|&&p| p != Self::TX_POWER_UNKNOWN).map(|&p| p as i8);
+        let ephemeral = b.get(20).is_some_and(|&f| f & Self::FLAG_EPHEMERAL != 0);
+        let lead_secs = b.get(21..25).map_or(0, |v| u32::from_le_bytes([v[0], v[1], v[2], v[3]]));
+        Some(Self { seq, mac_addr, timestamp: u64::from_le_bytes(ts), rssi: b[14] as i8, tx_power, ephemeral, lead_secs })
     }
 }
 
-/// ログ最大件数
+/// ログ最大件数（集計する相手の最大数）
 pub const MAX_ENCOUNTERS: usize = 100;
 
+/// Pico W のフラッシュ容量
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+/// ログ保存に使うフラッシュドライバ
+pub type FlashDev = Flash<'static, FLASH, Blocking, FLASH_SIZE>;
+
+/// ログ領域（フラッシュ先頭からのオフセット）。memory.x の STORAGE 先頭と一致させること。
+const LOG_REGION_OFFSET: u32 = 0x1E_0000;
+/// ログ領域のセクタ数（4KB × 28 = 112KB）。STORAGE の残り 16KB は設定保存用に空けておく。
+const LOG_REGION_SECTORS: u32 = 28;
+
+/// フラッシュ上のレコード種別（ペイロード先頭1バイト）
+/// 0x01 は旧形式（seq なしの検出記録）のため読み捨てる
+const REC_CLEAR: u8 = 0x02;
+const REC_SIGHTING: u8 = 0x03;
+/// 送信確認カーソル（この seq までサーバが受理済み）
+const REC_COMMIT: u8 = 0x04;
+/// 相互確認済み（続く6バイトの相手）
+const REC_MUTUAL: u8 = 0x05;
+/// ニックネーム（続く6バイトの相手、長さ1バイト、UTF-8）
+const REC_NICKNAME: u8 = 0x06;
+/// チェックポイント開始（続く4バイトは次に割り当てる seq）。END までの集計行で集計表を置き換える
+const REC_CHECKPOINT: u8 = 0x07;
+/// 集計行1件（チェックポイント内でのみ書く）
+const REC_ROW: u8 = 0x08;
+/// チェックポイント終了（ここまで書けていれば開始セクタより古い記録は不要）
+const REC_CHECKPOINT_END: u8 = 0x09;
+
+/// 新しく使えるセクタがこの数以下になったらチェックポイントを書く（集計表全体で最大3セクタ程度 + 余裕）
+const CHECKPOINT_RESERVE: u32 = 6;
+/// チェックポイントの取得でロックが空いていなかったときの再試行間隔
+const CHECKPOINT_RETRY: Duration = Duration::from_millis(100);
+
+/// 集計行のフラッシュ記録用バイト列長
+const ROW_LEN: usize = 42;
+/// 集計行のフラグ: 相互確認済み
+const ROW_FLAG_MUTUAL: u8 = 0x01;
+/// 集計行のフラグ: 一時ID
+const ROW_FLAG_EPHEMERAL: u8 = 0x02;
+
+fn encode_row(e: &EncounterLog) -> [u8; 1 + ROW_LEN] {
+    let mut b = [0u8; 1 + ROW_LEN];
+    b[0] = REC_ROW;
+    b[1..7].copy_from_slice(&e.mac_addr);
+    b[7..11].copy_from_slice(&e.seq.to_le_bytes());
+    b[11..19].copy_from_slice(&e.stats.first_seen.to_le_bytes());
+    b[19..27].copy_from_slice(&e.stats.last_seen.to_le_bytes());
+    b[27..31].copy_from_slice(&e.stats.count.to_le_bytes());
+    b[31] = e.stats.rssi_min as u8;
+    b[32] = e.stats.rssi_max as u8;
+    b[33..37].copy_from_slice(&e.stats.rssi_sum.to_le_bytes());
+    b[37..41].copy_from_slice(&e.stats.dwell_secs.to_le_bytes());
+    b[41] = e.tx_power.map_or(Sighting::TX_POWER_UNKNOWN, |p| p as u8);
+    b[42] = (if e.mutual { ROW_FLAG_MUTUAL } else { 0 }) | (if e.ephemeral { ROW_FLAG_EPHEMERAL } else { 0 });
+    b
+}
+
+/// `b` は種別バイトを除いた集計行（ニックネームは別レコード）
+fn decode_row(b: &[u8]) -> Option<EncounterLog> {
+    if b.len() < ROW_LEN {
+        return None;
+    }
+    let u32_at = |i: usize| u32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]]);
+    let u64_at = |i: usize| {
+        let mut v = [0u8; 8];
+        v.copy_from_slice(&b[i..i + 8]);
+        u64::from_le_bytes(v)
+    };
+    let mut mac_addr = [0u8; 6];
+    mac_addr.copy_from_slice(&b[0..6]);
+    Some(EncounterLog {
+        mac_addr,
+        ephemeral: b[41] & ROW_FLAG_EPHEMERAL != 0,
+        seq: u32_at(6),
+        stats: PeerStats {
+            first_seen: u64_at(10),
+            last_seen: u64_at(18),
+            count: u32_at(26),
+            rssi_min: b[30] as i8,
+            rssi_max: b[31] as i8,
+            rssi_sum: u32_at(32) as i32,
+            dwell_secs: u32_at(36),
+        },
+        tx_power: (b[40] != Sighting::TX_POWER_UNKNOWN).then_some(b[40] as i8),
+        mutual: b[41] & ROW_FLAG_MUTUAL != 0,
+        nickname: None,
+    })
+}
+
+/// フラッシュ書き込み要求
+enum FlashOp {
+    Append(Sighting),
+    Clear,
+    Commit(u32),
+    Mutual([u8; 6]),
+    Nickname([u8; 6], Nickname),
+    /// 設定領域への保存（内容は config が保持）
+    SaveConfig,
+}
+
+static FLASH_OPS: Channel<CriticalSectionRawMutex, FlashOp, 16> = Channel::new();
+
+/// キュー満杯で書けなかった要求がある（次のチェックポイントで RAM の集計表全体を書き直す）
+static RESYNC: AtomicBool = AtomicBool::new(false);
+
+/// フラッシュ書き込みタスクが動いているか（マウント失敗時は RAM のみで動作し、キューへ積まない）
+static WRITER_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// 書き込み要求をキューへ積む（待たない。ロックを保持したまま呼んでよい）。
+/// 満杯なら次のチェックポイントで書き直すよう予約する。書き込みタスクが無ければ何もしない。
+fn enqueue(op: FlashOp, what: &str) {
+    if !WRITER_RUNNING.load(Ordering::Relaxed) {
+        return;
+    }
+    if FLASH_OPS.try_send(op).is_err() {
+        RESYNC.store(true, Ordering::Relaxed);
+        warn!("フラッシュ書き込みキュー満杯: {}は次のチェックポイントで書きます", what);
+    }
+}
+
 // Mutexの中にRefCell<Vec<..>>を入れる（ロック後に可変借用するため）
 static ENCOUNTER_BUFFER: Mutex<CriticalSectionRawMutex, RefCell<Vec<EncounterLog, MAX_ENCOUNTERS>>> =
     Mutex::new(RefCell::new(Vec::new()));
 
+/// すれ違いの判定条件（settings.rs）
+const ENCOUNTER_RULES: EncounterRules = EncounterRules {
+    min_rssi: settings::ENCOUNTER_MIN_RSSI,
+    min_sightings: settings::ENCOUNTER_MIN_SIGHTINGS,
+    window_secs: settings::ENCOUNTER_WINDOW_SECS,
+    min_dwell_secs: settings::ENCOUNTER_MIN_DWELL_SECS,
+};
+
+/// 確定前の候補と RSSI の平滑化状態
+static GATE: BlockingMutex<CriticalSectionRawMutex, RefCell<EncounterGate>> =
+    BlockingMutex::new(RefCell::new(EncounterGate::new(ENCOUNTER_RULES)));
+
+/// 相互確認が記録より先に済んだ相手（記録した時点で相互確認済みにする）
+static MUTUAL_PENDING: BlockingMutex<CriticalSectionRawMutex, RefCell<Vec<[u8; 6], 8>>> =
+    BlockingMutex::new(RefCell::new(Vec::new()));
+
 static TOTAL_SAVED: AtomicU32 = AtomicU32::new(0);
 
-/// 連続重複の閾値（秒）。同一MACがこの秒数以内に再検出されたら連続重複としてスキップ。
-const DEDUP_WINDOW_SECS: u64 = 30;
+/// 次に割り当てる検出の通し番号（1始まり、フラッシュから復元）
+static NEXT_SEQ: AtomicU32 = AtomicU32::new(1);
+
+/// サーバが受理済みの最大 seq（0=未送信）
+static COMMITTED_SEQ: AtomicU32 = AtomicU32::new(0);
+
+/// 検出を集計表へ反映する。新しい相手なら行を追加し true を返す。
+/// 満杯時は最終検出が最も古い相手を追い出す。
+fn apply_sighting(vec: &mut Vec<EncounterLog, MAX_ENCOUNTERS>, s: &Sighting) -> bool {
+    if let Some(row) = vec.iter_mut().find(|e| e.mac_addr == s.mac_addr) {
+        row.seq = s.seq;
+        row.stats.observe(s.timestamp, s.rssi);
+        row.tx_power = s.tx_power.or(row.tx_power);
+        return false;
+    }
+    if vec.len() == MAX_ENCOUNTERS {
+        if let Some((idx, _)) = vec.iter().enumerate().min_by_key(|(_, e)| e.stats.last_seen) {
+            let _ = vec.swap_remove(idx);
+        }
+    }
+    let _ = vec.push(EncounterLog {
+        mac_addr: s.mac_addr,
+        ephemeral: s.ephemeral,
+        seq: s.seq,
+        stats: PeerStats::starting_at(s.timestamp.saturating_sub(s.lead_secs as u64), s.timestamp, s.rssi),
+        tx_power: s.tx_power,
+        mutual: false,
+        nickname: None,
+    });
+    true
+}
 
-/// ログを保存（連続重複は抑制）。ロック取得に失敗した場合はfalseを返す。
-pub fn save_encounter(mac_addr: [u8; 6], timestamp: u64, rssi: i8) -> bool {
+/// 検出を保存（相手ごとに集計）。ロック取得に失敗した場合はfalseを返す。
+/// `tx_power` は相手の広告の TX 電力（距離推定用、無ければ None）。`ephemeral` は `mac_addr` が一時ID か。
+/// すれ違いの条件を満たすまでは候補として追跡するだけで記録せず、記録する RSSI は平滑化後の値。
+pub fn save_encounter(mac_addr: [u8; 6], ephemeral: bool, timestamp: u64, rssi: i8, tx_power: Option<i8>) -> bool {
     match ENCOUNTER_BUFFER.try_lock() {
         Err(TryLockError) => {
             // ロック競合時はスキップ（割り込み抑制のため）
@@ -49,67 +290,377 @@ pub fn save_encounter(mac_addr: [u8; 6], timestamp: u64, rssi: i8) -> bool {
         }
         Ok(guard) => {
             let mut vec = guard.borrow_mut();
-
-            // 連続重複抑制：最後の1件と比較
-            if let Some(last) = vec.last() {
-                if last.mac_addr == mac_addr {
-                    if last.timestamp == 0 || timestamp == 0 {
-                        // タイムスタンプが無いなら単純にスキップ
-                        return true;
-                    }
-                    if timestamp.saturating_sub(last.timestamp) <= DEDUP_WINDOW_SECS {
-                        return true;
-                    }
-                }
+            let known = vec.iter().any(|e| e.mac_addr == mac_addr);
+            let now_ms = Instant::now().as_millis();
+            let (rssi, since_ms) = match GATE.lock(|g| g.borrow_mut().observe(&mac_addr, now_ms, rssi, known)) {
+                Verdict::Record { rssi, since_ms, .. } => (rssi, since_ms),
+                Verdict::Pending | Verdict::Weak => return true,
+            };
+            // 新しい行は確定の検出ではなく、条件を満たし始めた検出を初回検出にする（未同期時は時刻なし）
+            let lead_secs = if known || timestamp == 0 { 0 } else { ((now_ms - since_ms) / 1000) as u32 };
+            let seq = NEXT_SEQ.fetch_add(1, Ordering::Relaxed);
+            let sighting = Sighting { seq, mac_addr, timestamp, rssi, tx_power, ephemeral, lead_secs };
+            let is_new = apply_sighting(&mut vec, &sighting);
+            enqueue(FlashOp::Append(sighting), "検出");
+            if is_new && MUTUAL_PENDING.lock(|p| take_pending(&mut p.borrow_mut(), &mac_addr)) {
+                set_mutual(&mut vec, &mac_addr);
             }
-
-            // 末尾へ追加。満杯なら最古を削除してから追加。
-            if vec.len() == MAX_ENCOUNTERS {
-                let _ = vec.remove(0);
+            if is_new {
+                let total = TOTAL_SAVED.fetch_add(1, Ordering::Relaxed) + 1;
+                let s = fmt_bytes_colon(&mac_addr);
+                info!("保存: mac={} ts={} rssi={} (total={})", s.as_str(), timestamp, rssi, total);
             }
-            let _ = vec.push(EncounterLog { mac_addr, timestamp, rssi });
-            let total = TOTAL_SAVED.fetch_add(1, Ordering::Relaxed) + 1;
-            let s = fmt_bytes_colon(&mac_addr);
-            info!("保存: mac={} ts={} rssi={} (total={})", s.as_str(), timestamp, rssi, total);
             true
         }
     }
 }
 
+/// 相互確認済みにしてフラッシュへ記録する
+fn set_mutual(vec: &mut Vec<EncounterLog, MAX_ENCOUNTERS>, mac_addr: &[u8; 6]) {
+    let Some(row) = vec.iter_mut().find(|e| e.mac_addr == *mac_addr) else { return };
+    if row.mutual {
+        return;
+    }
+    row.mutual = true;
+    enqueue(FlashOp::Mutual(*mac_addr), "相互確認");
+}
+
+/// 保留中の相互確認に `mac_addr` があれば取り除いて true
+fn take_pending(pending: &mut Vec<[u8; 6], 8>, mac_addr: &[u8; 6]) -> bool {
+    match pending.iter().position(|p| p == mac_addr) {
+        Some(i) => {
+            pending.remove(i);
+            true
+        }
+        None => false,
+    }
+}
+
+/// GATT での ID 交換が済んだ相手を相互確認済みにする。記録済みの相手に反映したら true。
+/// まだ記録していない相手（すれ違いの条件を満たす前など）は保留し、記録した時点で反映する（false）。
+pub async fn mark_mutual(mac_addr: [u8; 6]) -> bool {
+    let guard = ENCOUNTER_BUFFER.lock().await;
+    let s = fmt_bytes_colon(&mac_addr);
+    let newly = guard.borrow_mut().iter_mut().find(|e| e.mac_addr == mac_addr).map(|row| {
+        let newly = !row.mutual;
+        row.mutual = true;
+        newly
+    });
+    let Some(newly) = newly else {
+        MUTUAL_PENDING.lock(|p| {
+            let mut p = p.borrow_mut();
+            if p.contains(&mac_addr) {
+                return;
+            }
+            if p.is_full() {
+                p.remove(0);
+            }
+            let _ = p.push(mac_addr);
+        });
+        info!("相互確認（未記録の相手、記録時に反映）: mac={}", s.as_str());
+        return false;
+    };
+    if newly {
+        // ロックを保持したまま積む（後続の記録より先にフラッシュへ書かれる）
+        enqueue(FlashOp::Mutual(mac_addr), "相互確認");
+    }
+    info!("相互確認: mac={}", s.as_str());
+    true
+}
+
+/// 記録済みの相手のニックネームを更新し、変わっていればフラッシュへ記録する。
+/// まだ記録していない相手は無視する（すれ違いが確定した後の広告・スキャン応答で付く）。
+pub fn set_nickname(mac_addr: [u8; 6], nickname: Nickname) {
+    let Ok(guard) = ENCOUNTER_BUFFER.try_lock() else { return };
+    let mut vec = guard.borrow_mut();
+    let Some(row) = vec.iter_mut().find(|e| e.mac_addr == mac_addr) else { return };
+    if row.nickname == Some(nickname) {
+        return;
+    }
+    row.nickname = Some(nickname);
+    let s = fmt_bytes_colon(&mac_addr);
+    info!("ニックネーム: mac={} name={}", s.as_str(), nickname.as_str());
+    enqueue(FlashOp::Nickname(mac_addr, nickname), "ニックネーム");
+}
+
+/// 記録済みでまだ相互確認していない相手か（ID 交換の対象）
+pub fn needs_exchange(mac_addr: &[u8; 6]) -> bool {
+    match ENCOUNTER_BUFFER.try_lock() {
+        Ok(guard) => guard.borrow().iter().any(|e| e.mac_addr == *mac_addr && !e.mutual),
+        Err(TryLockError) => false,
+    }
+}
+
 /// すべてのログをdefmtへ出力
 pub fn dump_logs() {
     if let Ok(guard) = ENCOUNTER_BUFFER.try_lock() {
         let vec = guard.borrow();
         info!("保存件数={}件", vec.len());
         for (i, e) in vec.iter().enumerate() {
-            info!("#{}, {}", i, e);
+            info!("#{}, {}", i, LogLine(e));
         }
     }
 }
 
-/// ログを全消去
-pub fn clear() {
-    if let Ok(guard) = ENCOUNTER_BUFFER.try_lock() {
-        guard.borrow_mut().clear();
-        info!("ログをクリアしました");
-    }
+/// ログを全消去し、消去した件数を返す
+pub async fn clear() -> usize {
+    let guard = ENCOUNTER_BUFFER.lock().await;
+    let cleared = {
+        let mut vec = guard.borrow_mut();
+        let n = vec.len();
+        vec.clear();
+        n
+    };
+    // 消した相手は再び条件を満たしてから記録する
+    GATE.lock(|g| g.borrow_mut().reset());
+    enqueue(FlashOp::Clear, "消去");
+    info!("ログをクリアしました（{}件）", cleared);
+    cleared
+}
+
+/// `upto` 以下の seq の行を送信済みとして削除し、削除した件数を返す
+/// （送信中に更新された行は seq が大きいので残る）。
+pub async fn commit(upto: u32) -> usize {
+    let guard = ENCOUNTER_BUFFER.lock().await;
+    let (removed, left) = {
+        let mut vec = guard.borrow_mut();
+        let before = vec.len();
+        vec.retain(|e| {
+            let keep = e.seq > upto;
+            if !keep {
+                // 削除した相手は新しいすれ違いとして数え直す（確定済みのままだと次の検出で条件なしに行ができる）
+                GATE.lock(|g| g.borrow_mut().forget(&e.mac_addr));
+            }
+            keep
+        });
+        (before - vec.len(), vec.len())
+    };
+    COMMITTED_SEQ.fetch_max(upto, Ordering::Relaxed);
+    enqueue(FlashOp::Commit(upto), "送信確認");
+    info!("送信確認 seq<={}: {}件を削除（残り{}件）", upto, removed, left);
+    removed
+}
+
+/// サーバが受理済みの最大 seq を返す。
+pub fn committed_seq() -> u32 {
+    COMMITTED_SEQ.load(Ordering::Relaxed)
+}
+
+/// すれ違い確定待ちの候補の数を返す。
+pub fn pending_candidates() -> usize {
+    GATE.lock(|g| g.borrow().pending())
 }
 
-/// 総保存件数を返す（起動後の累計）。
+/// 総保存件数を返す（起動後に新規追加した相手の累計）。
 pub fn total_saved() -> u32 {
     TOTAL_SAVED.load(Ordering::Relaxed)
 }
 
 /// バッファのスナップショットを`out`へコピーして件数を返す。
-pub fn snapshot(out: &mut heapless::Vec<EncounterLog, MAX_ENCOUNTERS>) -> usize {
+pub async fn snapshot(out: &mut heapless::Vec<EncounterLog, MAX_ENCOUNTERS>) -> usize {
+    let guard = ENCOUNTER_BUFFER.lock().await;
+    let vec = guard.borrow();
+    out.clear();
+    for e in vec.iter() {
+        let _ = out.push(*e);
+    }
+    vec.len()
+}
+
+/// 設定の保存を書き込みタスクへ依頼する。キュー満杯なら false。
+pub fn request_config_save() -> bool {
+    FLASH_OPS.try_send(FlashOp::SaveConfig).is_ok()
+}
+
+/// フラッシュのログ領域をマウントし、保存済みレコードを RAM バッファへ復元する。
+/// 起動時に1回だけ呼ぶ（BLE/スケジューラ起動前）。
+pub fn mount(flash: FlashDev) -> Result<FlashLog<FlashDev>, LogError<embassy_rp::flash::Error>> {
+    let mut log = FlashLog::mount(flash, LOG_REGION_OFFSET, LOG_REGION_SECTORS)?;
+    let mut restored: Vec<EncounterLog, MAX_ENCOUNTERS> = Vec::new();
+    // 書きかけのチェックポイント（END まで読めたら restored と置き換える）
+    let mut staging: Option<(u32, Vec<EncounterLog, MAX_ENCOUNTERS>)> = None;
+    let mut checkpoint_sector = None;
+    let mut max_seq = 0u32;
+    let mut committed = 0u32;
+    log.for_each_with_sector(|sector, rec| {
+        match rec.first() {
+            Some(&REC_CHECKPOINT) if rec.len() >= 5 => {
+                let next = u32::from_le_bytes([rec[1], rec[2], rec[3], rec[4]]);
+                max_seq = max_seq.max(next.saturating_sub(1));
+                staging = Some((sector, Vec::new()));
+                return;
+            }
+            Some(&REC_ROW) => {
+                if let (Some((_, rows)), Some(row)) = (staging.as_mut(), decode_row(&rec[1..])) {
+                    max_seq = max_seq.max(row.seq);
+                    let _ = rows.push(row);
+                }
+                return;
+            }
+            Some(&REC_CHECKPOINT_END) => {
+                if let Some((start, rows)) = staging.take() {
+                    restored = rows;
+                    checkpoint_sector = Some(start);
+                }
+                return;
+            }
+            Some(&REC_COMMIT) if rec.len() >= 5 => {
+                committed = committed.max(u32::from_le_bytes([rec[1], rec[2], rec[3], rec[4]]));
+            }
+            Some(&REC_SIGHTING) => {
+                if let Some(s) = Sighting::decode(&rec[1..]) {
+                    max_seq = max_seq.max(s.seq);
+                }
+            }
+            _ => {}
+        }
+        // 差分レコードは集計表と書きかけのチェックポイントの両方に反映する
+        // （チェックポイントが途中で切れていても、その後の記録を失わない）
+        replay(&mut restored, rec);
+        if let Some((_, rows)) = staging.as_mut() {
+            replay(rows, rec);
+        }
+    })?;
+    if let Some(sector) = checkpoint_sector {
+        log.protect_from(sector);
+    }
+    NEXT_SEQ.store(max_seq.max(committed) + 1, Ordering::Relaxed);
+    COMMITTED_SEQ.store(committed, Ordering::Relaxed);
     if let Ok(guard) = ENCOUNTER_BUFFER.try_lock() {
-        let vec = guard.borrow();
-        out.clear();
-        for e in vec.iter() {
-            let _ = out.push(*e);
+        *guard.borrow_mut() = restored;
+        info!(
+            "フラッシュから復元: {}件 (使用セクタ={}, 空き={}, committed={}, next_seq={})",
+            guard.borrow().len(), log.used_sectors(), log.headroom(), committed, max_seq.max(committed) + 1
+        );
+    }
+    Ok(log)
+}
+
+/// 差分レコード1件を集計表へ反映する（チェックポイントの開始・集計行・終了以外）
+fn replay(rows: &mut Vec<EncounterLog, MAX_ENCOUNTERS>, rec: &[u8]) {
+    match rec.first() {
+        Some(&REC_SIGHTING) => {
+            if let Some(s) = Sighting::decode(&rec[1..]) {
+                apply_sighting(rows, &s);
+            }
+        }
+        Some(&REC_CLEAR) => rows.clear(),
+        Some(&REC_MUTUAL) if rec.len() >= 7 => {
+            if let Some(row) = rows.iter_mut().find(|e| e.mac_addr[..] == rec[1..7]) {
+                row.mutual = true;
+            }
+        }
+        Some(&REC_NICKNAME) if rec.len() >= 8 => {
+            let len = (rec[7] as usize).min(rec.len() - 8);
+            if let Some(row) = rows.iter_mut().find(|e| e.mac_addr[..] == rec[1..7]) {
+                row.nickname = Nickname::new(&rec[8..8 + len]).or(row.nickname);
+            }
+        }
+        Some(&REC_COMMIT) if rec.len() >= 5 => {
+            let upto = u32::from_le_bytes([rec[1], rec[2], rec[3], rec[4]]);
+            rows.retain(|e| e.seq > upto);
+        }
+        _ => {}
+    }
+}
+
+/// フラッシュ書き込みタスク（キューに積まれた追記/消去記録・設定の保存を順に書き込む）。
+/// 消去を伴う書き込みは数十ms ブロックするため、専用タスクで逐次処理する。
+/// ログ領域の残りが少なくなったとき、またはキュー満杯で要求を取りこぼしたときはチェックポイントを書く。
+#[embassy_executor::task]
+pub async fn flash_writer_task(mut log: FlashLog<FlashDev>) -> ! {
+    // BLE・コンソールなどの記録元はこのタスクより後に起動する
+    WRITER_RUNNING.store(true, Ordering::Relaxed);
+    let mut checkpoint_due = log.headroom() <= CHECKPOINT_RESERVE;
+    loop {
+        let op = if checkpoint_due {
+            match select(FLASH_OPS.receive(), Timer::after(CHECKPOINT_RETRY)).await {
+                Either::First(op) => Some(op),
+                Either::Second(()) => None,
+            }
+        } else {
+            Some(FLASH_OPS.receive().await)
+        };
+        if let Some(op) = op {
+            if let Err(e) = write_op(&mut log, op) {
+                warn!("フラッシュ書き込み失敗: {}", defmt::Debug2Format(&e));
+                checkpoint_due = true;
+            }
+        }
+        checkpoint_due |= RESYNC.swap(false, Ordering::Relaxed) || log.headroom() <= CHECKPOINT_RESERVE;
+        if checkpoint_due {
+            match write_checkpoint(&mut log) {
+                Ok(true) => checkpoint_due = false,
+                // 集計表を更新中（次の要求かタイマーで再試行）
+                Ok(false) => {}
+                Err(e) => warn!("チェックポイント書き込み失敗: {}", defmt::Debug2Format(&e)),
+            }
         }
-        vec.len()
-    } else {
-        0
     }
 }
+
+fn write_op(log: &mut FlashLog<FlashDev>, op: FlashOp) -> Result<(), LogError<embassy_rp::flash::Error>> {
+    match op {
+        FlashOp::Append(e) => {
+            let mut rec = [0u8; 1 + Sighting::ENCODED_LEN];
+            rec[0] = REC_SIGHTING;
+            rec[1..].copy_from_slice(&e.encode());
+            log.append(&rec)
+        }
+        FlashOp::Clear => log.append(&[REC_CLEAR]),
+        FlashOp::Commit(upto) => {
+            let b = upto.to_le_bytes();
+            log.append(&[REC_COMMIT, b[0], b[1], b[2], b[3]])
+        }
+        FlashOp::Mutual(m) => log.append(&[REC_MUTUAL, m[0], m[1], m[2], m[3], m[4], m[5]]),
+        FlashOp::Nickname(m, n) => write_nickname(log, &m, &n),
+        FlashOp::SaveConfig => {
+            crate::config::write_pending(log.flash_mut());
+            Ok(())
+        }
+    }
+}
+
+fn write_nickname(log: &mut FlashLog<FlashDev>, m: &[u8; 6], n: &Nickname) -> Result<(), LogError<embassy_rp::flash::Error>> {
+    let name = n.as_str().as_bytes();
+    let mut rec = [0u8; 8 + MAX_NICKNAME_LEN];
+    rec[0] = REC_NICKNAME;
+    rec[1..7].copy_from_slice(m);
+    rec[7] = name.len() as u8;
+    rec[8..8 + name.len()].copy_from_slice(name);
+    log.append(&rec[..8 + name.len()])
+}
+
+/// RAM の集計表全体を書き直し、開始セクタより古いセクタを再利用できるようにする。
+/// 集計表を更新中（ロック競合）なら何もせず false。
+fn write_checkpoint(log: &mut FlashLog<FlashDev>) -> Result<bool, LogError<embassy_rp::flash::Error>> {
+    let Ok(guard) = ENCOUNTER_BUFFER.try_lock() else { return Ok(false) };
+    // キューに残っている要求は RAM に反映済み（ロック中に積まれる）なので、集計表に含めて捨てる
+    let mut save_config = false;
+    while let Ok(op) = FLASH_OPS.try_receive() {
+        save_config |= matches!(op, FlashOp::SaveConfig);
+    }
+    let rows = guard.borrow().clone();
+    let next_seq = NEXT_SEQ.load(Ordering::Relaxed);
+    let committed = COMMITTED_SEQ.load(Ordering::Relaxed);
+    drop(guard);
+
+    let b = next_seq.to_le_bytes();
+    log.append(&[REC_CHECKPOINT, b[0], b[1], b[2], b[3]])?;
+    let start = log.head_sector();
+    let b = committed.to_le_bytes();
+    log.append(&[REC_COMMIT, b[0], b[1], b[2], b[3]])?;
+    for row in rows.iter() {
+        log.append(&encode_row(row))?;
+        if let Some(n) = &row.nickname {
+            write_nickname(log, &row.mac_addr, n)?;
+        }
+    }
+    log.append(&[REC_CHECKPOINT_END])?;
+    log.protect_from(start);
+    info!("チェックポイント: {}件 (空き={})", rows.len(), log.headroom());
+    if save_config {
+        crate::config::write_pending(log.flash_mut());
+    }
+    Ok(true)
+}
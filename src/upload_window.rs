@@ -0,0 +1,90 @@
+//! 本番モードの送信時刻の決定
+//! - 送信ウィンドウ（例: 03:00〜04:00 JST）の中で、BD_ADDR から決まる端末ごとのオフセットに送信する
+//!   （全端末が 03:00:00 に一斉接続しないようにする）
+//! - サーバが次回送信時刻を指示した場合はそちらを優先する
+
+/// 日本標準時（UTC+9）
+pub const JST_OFFSET_SECS: i64 = 9 * 3600;
+
+/// サーバ指示の次回送信時刻として受け付ける最大の先送り（7日）
+pub const MAX_HINT_AHEAD_SECS: u64 = 7 * 86_400;
+
+/// 送信ウィンドウ（ローカル時刻）
+#[derive(Clone, Copy, Debug)]
+pub struct UploadWindow {
+    /// 開始時刻（ローカルの 0:00 からの秒数）
+    pub start_secs: u32,
+    /// ウィンドウ幅（秒、1以上）
+    pub length_secs: u32,
+    /// UTC からのオフセット（秒）
+    pub utc_offset_secs: i64,
+}
+
+impl UploadWindow {
+    /// 端末固有のオフセット（0..length_secs）。BD_ADDR の FNV-1a ハッシュから決定的に求める。
+    pub fn device_offset_secs(&self, bd_addr: &[u8; 6]) -> u32 {
+        let mut h: u32 = 0x811C_9DC5;
+        for &b in bd_addr {
+            h ^= b as u32;
+            h = h.wrapping_mul(0x0100_0193);
+        }
+        h % self.length_secs.max(1)
+    }
+
+    /// `now`（Unix秒）から、この端末の次の送信時刻までの秒数（0 にはならない）
+    pub fn secs_until_next(&self, now: u64, bd_addr: &[u8; 6]) -> u64 {
+        let local = (now as i64 + self.utc_offset_secs).rem_euclid(86_400) as u64;
+        let target = (self.start_secs as u64 + self.device_offset_secs(bd_addr) as u64) % 86_400;
+        if local < target { target - local } else { 86_400 - (local - target) }
+    }
+}
+
+/// サーバ指示の次回送信時刻（Unix秒）を検証し、待ち秒数を返す。
+/// 過去の時刻や MAX_HINT_AHEAD_SECS より先の時刻は無視する。
+pub fn hint_delay_secs(now: u64, next_upload_at: Option<u64>) -> Option<u64> {
+    let at = next_upload_at?;
+    if at <= now || at - now > MAX_HINT_AHEAD_SECS {
+        return None;
+    }
+    Some(at - now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const WINDOW: UploadWindow = UploadWindow { start_secs: 3 * 3600, length_secs: 3600, utc_offset_secs: JST_OFFSET_SECS };
+    const ADDR_A: [u8; 6] = [0x28, 0xCD, 0xC1, 0x15, 0x26, 0x11];
+    const ADDR_B: [u8; 6] = [0x28, 0xCD, 0xC1, 0x15, 0x26, 0x12];
+
+    // 2023-11-14 22:13:20 UTC = 2023-11-15 07:13:20 JST
+    const NOW: u64 = 1_700_000_000;
+
+    #[test]
+    fn offset_is_deterministic_and_spread() {
+        let a = WINDOW.device_offset_secs(&ADDR_A);
+        assert_eq!(a, WINDOW.device_offset_secs(&ADDR_A));
+        assert!(a < 3600);
+        assert!(a != WINDOW.device_offset_secs(&ADDR_B));
+    }
+
+    #[test]
+    fn next_upload_falls_inside_window() {
+        let wait = WINDOW.secs_until_next(NOW, &ADDR_A);
+        let at_local = (NOW + wait) as i64 + JST_OFFSET_SECS;
+        let sec_day = at_local.rem_euclid(86_400) as u32;
+        assert!((3 * 3600..4 * 3600).contains(&sec_day));
+        assert_eq!(sec_day - 3 * 3600, WINDOW.device_offset_secs(&ADDR_A));
+        // 送信直後は翌日まで待つ
+        assert_eq!(WINDOW.secs_until_next(NOW + wait, &ADDR_A), 86_400);
+    }
+
+    #[test]
+    fn server_hint_is_bounded() {
+        assert_eq!(hint_delay_secs(NOW, Some(NOW + 600)), Some(600));
+        assert_eq!(hint_delay_secs(NOW, Some(NOW - 1)), None);
+        assert_eq!(hint_delay_secs(NOW, Some(NOW + MAX_HINT_AHEAD_SECS + 1)), None);
+        assert_eq!(hint_delay_secs(NOW, None), None);
+    }
+}
@@ -0,0 +1,378 @@
+//! USB シリアル（CDC-ACM）コンソール
+//! - PC の端末ソフト（例: `screen /dev/ttyACM0`、Windows は TeraTerm）から USB ケーブルだけで操作する
+//! - コマンドの解析は lib の `console`、処理は storage / config / timekeeper / api_client の既存 API を使う
+use core::fmt::Write as _;
+
+use defmt::*;
+use embassy_net::Stack;
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::Driver;
+use embassy_time::{Instant, Timer};
+use embassy_usb::class::cdc_acm::{CdcAcmClass, State};
+use embassy_usb::driver::EndpointError;
+use embassy_usb::{Builder, UsbDevice};
+use heapless::String;
+use pico_w_id_beacon::config_record::ConfigRecord;
+use pico_w_id_beacon::console::{self, apply_config, Command, LineBuffer, LineEvent, ParseError};
+use pico_w_id_beacon::export_frame::{
+    encode_frame, ExportHeader, ExportRecord, EXPORT_VERSION, FRAME_END, FRAME_HEADER, FRAME_RECORD, MAX_FRAME_LEN,
+};
+use pico_w_id_beacon::format::fmt_bytes_colon;
+use static_cell::StaticCell;
+
+use crate::storage::{self, EncounterLog, MAX_ENCOUNTERS};
+use crate::{ble, config, scheduler, timekeeper, SharedControl};
+
+/// USB ドライバ（RP2040 内蔵 USB）
+pub type UsbDriver = Driver<'static, USB>;
+
+/// USB パケット最大長（フルスピード）
+const MAX_PACKET: usize = 64;
+
+/// USB デバイスとコンソールタスクを起動する。
+pub fn start(
+    spawner: embassy_executor::Spawner,
+    driver: UsbDriver,
+    control: &'static SharedControl,
+    stack: Stack<'static>,
+    bd_addr: [u8; 6],
+) {
+    // Raspberry Pi の VID と Pico SDK の CDC 用 PID（OS 標準のドライバで認識される）
+    let mut usb_config = embassy_usb::Config::new(0x2E8A, 0x000A);
+    usb_config.manufacturer = Some("PicoStreet");
+    usb_config.product = Some("PicoStreet Keyholder");
+    static SERIAL: StaticCell<String<12>> = StaticCell::new();
+    let serial = SERIAL.init(String::new());
+    for b in bd_addr {
+        let _ = write!(serial, "{:02X}", b);
+    }
+    usb_config.serial_number = Some(serial.as_str());
+    usb_config.max_power = 100;
+    usb_config.max_packet_size_0 = MAX_PACKET as u8;
+
+    static CONFIG_DESC: StaticCell<[u8; 256]> = StaticCell::new();
+    static BOS_DESC: StaticCell<[u8; 256]> = StaticCell::new();
+    static CONTROL_BUF: StaticCell<[u8; 64]> = StaticCell::new();
+    static STATE: StaticCell<State> = StaticCell::new();
+    let mut builder = Builder::new(
+        driver,
+        usb_config,
+        CONFIG_DESC.init([0; 256]),
+        BOS_DESC.init([0; 256]),
+        &mut [], // MS OS ディスクリプタなし
+        CONTROL_BUF.init([0; 64]),
+    );
+    let class = CdcAcmClass::new(&mut builder, STATE.init(State::new()), MAX_PACKET as u16);
+    let usb = builder.build();
+
+    if spawner.spawn(usb_task(usb)).is_err() {
+        warn!("USBタスク起動失敗");
+    }
+    let console = Console { class, control, stack, bd_addr };
+    if spawner.spawn(console_task(console)).is_err() {
+        warn!("USBコンソール起動失敗");
+    }
+}
+
+#[embassy_executor::task]
+async fn usb_task(mut usb: UsbDevice<'static, UsbDriver>) -> ! {
+    usb.run().await
+}
+
+#[embassy_executor::task]
+async fn console_task(mut console: Console) -> ! {
+    loop {
+        console.class.wait_connection().await;
+        info!("USBコンソール接続");
+        let _ = console.session().await;
+        info!("USBコンソール切断");
+    }
+}
+
+struct Console {
+    class: CdcAcmClass<'static, UsbDriver>,
+    control: &'static SharedControl,
+    stack: Stack<'static>,
+    bd_addr: [u8; 6],
+}
+
+/// 1行ぶんの出力バッファ
+type Line = String<192>;
+
+impl Console {
+    /// 切断されるまでコマンドを処理する
+    async fn session(&mut self) -> Result<(), EndpointError> {
+        let mut lb: LineBuffer<128> = LineBuffer::new();
+        self.println("PicoStreet コンソール（help でコマンド一覧）").await?;
+        self.write(b"> ").await?;
+        let mut packet = [0u8; MAX_PACKET];
+        loop {
+            let n = self.class.read_packet(&mut packet).await?;
+            for &b in &packet[..n] {
+                match lb.feed(b) {
+                    LineEvent::None => {}
+                    LineEvent::Echo(c) => self.write(&[c]).await?,
+                    LineEvent::Erase => self.write(b"\x08 \x08").await?,
+                    LineEvent::Line => {
+                        self.write(b"\r\n").await?;
+                        let mut line: String<128> = String::new();
+                        let _ = line.push_str(lb.line());
+                        lb.clear();
+                        self.execute(&line).await?;
+                        self.write(b"> ").await?;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn execute(&mut self, line: &str) -> Result<(), EndpointError> {
+        let cmd = match console::parse(line) {
+            Ok(cmd) => cmd,
+            Err(ParseError::Empty) => return Ok(()),
+            Err(ParseError::Unknown) => return self.println("不明なコマンドです（help で一覧）").await,
+            Err(ParseError::MissingArg) => return self.println("引数が足りません（help で一覧）").await,
+            Err(ParseError::BadKey) => return self.println("key は dev ssid psk host port path tls ca key のいずれか").await,
+        };
+        match cmd {
+            Command::Help => self.print_multiline(console::HELP).await,
+            Command::Status => self.status().await,
+            Command::LogDump => self.log_dump().await,
+            Command::LogClear => {
+                let cleared = storage::clear().await;
+                let mut s = Line::new();
+                let _ = write!(s, "すれ違いログを消去しました（{}件）", cleared);
+                self.println(&s).await
+            }
+            Command::Export => self.export().await,
+            Command::ConfigGet => self.config_get().await,
+            Command::ConfigSet { key, value } => {
+                let mut rec = config::latest();
+                if let Err(e) = apply_config(&mut rec, key, value) {
+                    return self.println(e).await;
+                }
+                match config::save(rec).await {
+                    Ok(()) => self.println("保存しました（reboot で反映）").await,
+                    Err(e) => self.println(e).await,
+                }
+            }
+            Command::Time => self.time().await,
+            Command::WifiScan => self.wifi_scan().await,
+            Command::UploadNow => self.upload_now().await,
+            Command::Reboot => {
+                self.println("再起動します").await?;
+                Timer::after_millis(200).await;
+                cortex_m::peripheral::SCB::sys_reset()
+            }
+        }
+    }
+
+    async fn status(&mut self) -> Result<(), EndpointError> {
+        let mut s = Line::new();
+        let _ = write!(s, "稼働時間: {}秒 / デベロッパーモード: {}", Instant::now().as_secs(), config::is_developer_mode());
+        self.println(&s).await?;
+        self.time().await?;
+
+        s.clear();
+        match self.stack.config_v4() {
+            Some(v4) if self.stack.is_config_up() => {
+                let _ = write!(s, "WiFi: 接続中 {}", v4.address);
+            }
+            _ => {
+                let _ = s.push_str("WiFi: 未接続");
+            }
+        }
+        self.println(&s).await?;
+
+        let mut buf: heapless::Vec<EncounterLog, MAX_ENCOUNTERS> = heapless::Vec::new();
+        let count = storage::snapshot(&mut buf).await;
+        s.clear();
+        let _ = write!(
+            s,
+            "すれ違い: 未送信{}件 / 起動後の新規{}件 / 送信済み seq<={} / 確定待ち{}台",
+            count,
+            storage::total_saved(),
+            storage::committed_seq(),
+            storage::pending_candidates()
+        );
+        self.println(&s).await?;
+
+        let rx = ble::rx_stats();
+        s.clear();
+        let _ = write!(
+            s,
+            "BLE受信: PicoStreet={} / 対象外={} UUID違い={} AD不正={} 種別違い={} 未対応版={} 長さ不正={}",
+            rx.ok, rx.no_service_data, rx.wrong_uuid, rx.malformed_ad, rx.wrong_device_type, rx.unsupported_version, rx.bad_length
+        );
+        self.println(&s).await
+    }
+
+    async fn log_dump(&mut self) -> Result<(), EndpointError> {
+        let mut buf: heapless::Vec<EncounterLog, MAX_ENCOUNTERS> = heapless::Vec::new();
+        let count = storage::snapshot(&mut buf).await;
+        let mut s = Line::new();
+        let _ = write!(s, "保存件数={}件", count);
+        self.println(&s).await?;
+        for (i, e) in buf.iter().enumerate() {
+            s.clear();
+            let _ = write!(
+                s,
+                "#{} seq={} mac={} first={} last={} count={} rssi={}/{}/{} dwell={}s {}{}{}{}",
+                i,
+                e.seq,
+                fmt_bytes_colon(&e.mac_addr).as_str(),
+                e.stats.first_seen,
+                e.stats.last_seen,
+                e.stats.count,
+                e.stats.rssi_min,
+                e.stats.rssi_mean(),
+                e.stats.rssi_max,
+                e.stats.dwell_secs,
+                e.proximity().as_str(),
+                if e.mutual { " 相互" } else { "" },
+                if e.nickname.is_some() { " " } else { "" },
+                e.nickname.as_ref().map_or("", |n| n.as_str())
+            );
+            self.println(&s).await?;
+        }
+        Ok(())
+    }
+
+    /// 未送信のすれ違いログをフレーム（Header → Record × 件数 → End）で出力する
+    async fn export(&mut self) -> Result<(), EndpointError> {
+        let mut buf: heapless::Vec<EncounterLog, MAX_ENCOUNTERS> = heapless::Vec::new();
+        let count = storage::snapshot(&mut buf).await;
+        info!("エクスポート: {}件", count);
+        let header = ExportHeader {
+            version: EXPORT_VERSION,
+            device_id: self.bd_addr,
+            count: count as u16,
+            exported_at: timekeeper::now_unix().unwrap_or(0),
+        };
+        self.write_frame(FRAME_HEADER, &header.encode()).await?;
+        for e in buf.iter() {
+            let record = ExportRecord { seq: e.seq, mac_addr: e.mac_addr, stats: e.stats, tx_power: e.tx_power, mutual: e.mutual, ephemeral: e.ephemeral };
+            self.write_frame(FRAME_RECORD, &record.encode()).await?;
+        }
+        self.write_frame(FRAME_END, &[]).await?;
+        self.write(b"\r\n").await
+    }
+
+    async fn write_frame(&mut self, frame_type: u8, payload: &[u8]) -> Result<(), EndpointError> {
+        let mut frame = [0u8; MAX_FRAME_LEN];
+        match encode_frame(frame_type, payload, &mut frame) {
+            Some(n) => self.write(&frame[..n]).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn config_get(&mut self) -> Result<(), EndpointError> {
+        let running = config::get();
+        self.print_config("現在の設定", running).await?;
+        if let Some(staged) = config::staged().filter(|s| s != running) {
+            self.print_config("保存済み（再起動後に反映）", &staged).await?;
+        }
+        Ok(())
+    }
+
+    async fn print_config(&mut self, title: &str, c: &ConfigRecord) -> Result<(), EndpointError> {
+        let mut s = Line::new();
+        let _ = write!(s, "[{}]", title);
+        self.println(&s).await?;
+        s.clear();
+        let _ = write!(s, "dev={} ssid={} psk={}", if c.developer_mode { "on" } else { "off" }, c.wifi_ssid.as_str(), if c.wifi_psk.is_empty() { "" } else { "********" });
+        self.println(&s).await?;
+        s.clear();
+        let _ = write!(
+            s,
+            "host={} port={} path={} tls={} ca={}",
+            c.api_host.as_str(), c.api_port, c.api_path.as_str(), if c.api_tls { "on" } else { "off" }, c.api_ca
+        );
+        self.println(&s).await?;
+        s.clear();
+        // 署名鍵は表示しない（設定済みかどうかだけ）
+        let _ = write!(s, "key={}", if c.api_key.is_empty() { "未設定" } else { "設定済み" });
+        self.println(&s).await
+    }
+
+    async fn time(&mut self) -> Result<(), EndpointError> {
+        let mut s = Line::new();
+        match timekeeper::now_unix() {
+            Some(unix) => {
+                let sec_day = (unix + 9 * 3600) % 86_400;
+                let _ = write!(s, "時刻: unix={} ({:02}:{:02}:{:02} JST)", unix, sec_day / 3600, sec_day % 3600 / 60, sec_day % 60);
+            }
+            None => {
+                let _ = s.push_str("時刻: 未同期（WiFi 接続後に NTP で同期）");
+            }
+        }
+        self.println(&s).await
+    }
+
+    async fn wifi_scan(&mut self) -> Result<(), EndpointError> {
+        self.println("スキャン中...").await?;
+        // スキャン結果はロック中に集め、表示はロック解放後に行う
+        let mut found: heapless::Vec<(String<32>, i16, u16, [u8; 6]), 16> = heapless::Vec::new();
+        {
+            let mut control = self.control.lock().await;
+            let mut scanner = control.scan(cyw43::ScanOptions::default()).await;
+            while let Some(bss) = scanner.next().await {
+                let len = (bss.ssid_len as usize).min(bss.ssid.len());
+                let ssid = core::str::from_utf8(&bss.ssid[..len]).unwrap_or("?");
+                let _ = found.push((ssid.try_into().unwrap_or_default(), bss.rssi, bss.chanspec & 0xFF, bss.bssid));
+            }
+        }
+        for (ssid, rssi, ch, bssid) in found.iter() {
+            let mut s = Line::new();
+            let _ = write!(s, "{} ch={} rssi={} '{}'", fmt_bytes_colon(bssid).as_str(), ch, rssi, ssid.as_str());
+            self.println(&s).await?;
+        }
+        let mut s = Line::new();
+        let _ = write!(s, "{}件", found.len());
+        self.println(&s).await
+    }
+
+    async fn upload_now(&mut self) -> Result<(), EndpointError> {
+        if !self.stack.is_config_up() {
+            return self.println("WiFi 未接続のため送信できません").await;
+        }
+        self.println("送信中...").await?;
+        let mut s = Line::new();
+        match scheduler::upload_once(self.stack, self.bd_addr).await {
+            Ok((0, _)) => {
+                let _ = s.push_str("送信対象がありません");
+            }
+            Ok((count, _)) => {
+                let _ = write!(s, "送信成功: {}件", count);
+            }
+            Err(e) => {
+                let _ = write!(s, "送信失敗: {}", e);
+            }
+        }
+        self.println(&s).await
+    }
+
+    /// 改行（\n）を含む文字列を CRLF で出力する
+    async fn print_multiline(&mut self, text: &str) -> Result<(), EndpointError> {
+        for line in text.lines() {
+            self.println(line).await?;
+        }
+        Ok(())
+    }
+
+    async fn println(&mut self, s: &str) -> Result<(), EndpointError> {
+        self.write(s.as_bytes()).await?;
+        self.write(b"\r\n").await
+    }
+
+    /// パケット単位に分けて送る（最大長ちょうどで終わる場合は空パケットで区切る）
+    async fn write(&mut self, data: &[u8]) -> Result<(), EndpointError> {
+        for chunk in data.chunks(MAX_PACKET) {
+            self.class.write_packet(chunk).await?;
+        }
+        if !data.is_empty() && data.len() % MAX_PACKET == 0 {
+            self.class.write_packet(&[]).await?;
+        }
+        Ok(())
+    }
+}
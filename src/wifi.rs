@@ -4,35 +4,45 @@
 //! - WiFi functionality is always enabled in this version
 //! - LED patterns indicate connection state as requested.
 //! - Includes full network stack with TCP/IP, DHCP, and HTTP connectivity test
+//! - A supervisor task re-joins with backoff whenever the link drops
 
 use defmt::*;
 use embassy_time::{Timer, Duration, Instant};
 
+use pico_w_id_beacon::retry::RetryPolicy;
+use pico_w_id_beacon::wifi_select::{connect_order, match_ssid, KnownNetwork, MAX_KNOWN_NETWORKS};
+
 // Import WiFi config from the library crate
 
+/// Switch the on-board LED. The shared control is locked only for the GPIO write,
+/// so blink timing never blocks BLE or other users of the chip.
+async fn led_set(control: &crate::SharedControl, on: bool) {
+    control.lock().await.gpio_set(0, on).await;
+}
+
 /// Blink pattern: during connection attempt (500ms interval).
-pub async fn led_connecting(control: &mut cyw43::Control<'_>, cycles: u32) {
+pub async fn led_connecting(control: &crate::SharedControl, cycles: u32) {
     for _ in 0..cycles {
-        let _ = control.gpio_set(0, true).await;
+        led_set(control, true).await;
         Timer::after(Duration::from_millis(500)).await;
-        let _ = control.gpio_set(0, false).await;
+        led_set(control, false).await;
         Timer::after(Duration::from_millis(500)).await;
     }
 }
 
 /// Blink pattern: connection completed (steady 2s ON).
-pub async fn led_connected(control: &mut cyw43::Control<'_>) {
-    let _ = control.gpio_set(0, true).await;
+pub async fn led_connected(control: &crate::SharedControl) {
+    led_set(control, true).await;
     Timer::after(Duration::from_secs(2)).await;
-    let _ = control.gpio_set(0, false).await;
+    led_set(control, false).await;
 }
 
 /// Blink pattern: connection failed (fast 100ms blink x5).
-pub async fn led_connect_failed(control: &mut cyw43::Control<'_>) {
+pub async fn led_connect_failed(control: &crate::SharedControl) {
     for _ in 0..5 {
-        let _ = control.gpio_set(0, true).await;
+        led_set(control, true).await;
         Timer::after(Duration::from_millis(100)).await;
-        let _ = control.gpio_set(0, false).await;
+        led_set(control, false).await;
         Timer::after(Duration::from_millis(100)).await;
     }
 }
@@ -54,42 +64,43 @@ async fn net_task(mut runner: embassy_net::Runner<'static, cyw43::NetDriver<'sta
     runner.run().await
 }
 
-/// WiFiへ接続してネットワークスタックを起動し、`Stack`を返す
-pub async fn maintain_wifi_connection(
+/// ネットワークスタック（DHCPv4）を起動して `Stack` を返す。
+/// AP への接続は `wifi_supervisor_task` が行い、リンクが上がると DHCP が走る。
+pub fn init_stack(
     spawner: embassy_executor::Spawner,
-    mut control: &mut cyw43::Control<'_>,
     net_device: cyw43::NetDriver<'static>,
-) -> Result<embassy_net::Stack<'static>, &'static str> {
-    use crate::settings::{WIFI_PSK, WIFI_SSID};
-    use embassy_net::{Config, Stack, StackResources};
-    use static_cell::StaticCell;
-
-    info!("WiFi接続開始: SSID='{}'", WIFI_SSID);
-
-    control
-        .set_power_management(cyw43::PowerManagementMode::PowerSave)
-        .await;
+) -> embassy_net::Stack<'static> {
+    spawn_stack(spawner, net_device, embassy_net::Config::dhcpv4(Default::default()))
+}
 
-    led_connecting(&mut control, 2).await;
+/// 設定ポータル（ソフトAP）用のネットワークスタックを固定アドレス `addr`/24 で起動する。
+pub fn init_ap_stack(
+    spawner: embassy_executor::Spawner,
+    net_device: cyw43::NetDriver<'static>,
+    addr: [u8; 4],
+) -> embassy_net::Stack<'static> {
+    use embassy_net::{Ipv4Address, Ipv4Cidr, StaticConfigV4};
 
-    let t0 = Instant::now();
-    if let Err(e) = control
-        .join(WIFI_SSID, cyw43::JoinOptions::new(WIFI_PSK.as_bytes()))
-        .await
-    {
-        warn!("WiFi接続失敗: {}", defmt::Debug2Format(&e));
-        led_connect_failed(&mut control).await;
-        return Err("AP接続失敗");
-    }
+    let config = embassy_net::Config::ipv4_static(StaticConfigV4 {
+        address: Ipv4Cidr::new(Ipv4Address::new(addr[0], addr[1], addr[2], addr[3]), 24),
+        gateway: None,
+        dns_servers: heapless::Vec::new(),
+    });
+    spawn_stack(spawner, net_device, config)
+}
 
-    let ms = (Instant::now() - t0).as_millis();
-    info!("WiFi接続成功: '{}' ({}ms)", WIFI_SSID, ms);
-    led_connected(&mut control).await;
+/// スタックを生成して net_task を起動する（1回の起動でどちらか一方のみ呼ぶ）
+fn spawn_stack(
+    spawner: embassy_executor::Spawner,
+    net_device: cyw43::NetDriver<'static>,
+    config: embassy_net::Config,
+) -> embassy_net::Stack<'static> {
+    use embassy_net::{Stack, StackResources};
+    use static_cell::StaticCell;
 
-    // DHCPv4でネットワークスタック起動
-    static RESOURCES: StaticCell<StackResources<3>> = StaticCell::new(); // DHCP(1)+DNS(1)+UDP(1)
+    // 局モード: DHCP(1)+DNS(1)+UDP(1) / ポータル: DHCPサーバ(1)+DNS応答(1)+HTTP(1)
+    static RESOURCES: StaticCell<StackResources<3>> = StaticCell::new();
     static STACK: StaticCell<Stack<'static>> = StaticCell::new();
-    let config = Config::dhcpv4(Default::default());
     let seed = 0x1357_9bdf_2468_abcdu64;
     let (stack_tmp, runner) = embassy_net::new(
         net_device,
@@ -99,25 +110,143 @@ pub async fn maintain_wifi_connection(
     );
     let stack = *STACK.init(stack_tmp);
 
-    spawner
-        .spawn(net_task(runner))
-        .map_err(|_| "ネットワークタスク起動失敗")
-        .ok();
+    if spawner.spawn(net_task(runner)).is_err() {
+        warn!("ネットワークタスク起動失敗");
+    }
+    stack
+}
 
-    // DHCP待ち（タイムアウト付き）
-    use embassy_time::with_timeout;
-    if with_timeout(Duration::from_secs(10), stack.wait_config_up())
-        .await
-        .is_err()
+/// 再接続の待ち時間（5秒から倍々、最大5分。回数制限なし）
+const REJOIN_POLICY: RetryPolicy = RetryPolicy { base_secs: 5, max_secs: 300, max_attempts: u32::MAX };
+
+/// 接続候補の一覧（config の AP を最優先、続いて settings::WIFI_NETWORKS の残り）
+fn known_networks() -> heapless::Vec<KnownNetwork, MAX_KNOWN_NETWORKS> {
+    let cfg = crate::config::get();
+    let mut v = heapless::Vec::new();
+    if !cfg.wifi_ssid.is_empty() {
+        let _ = v.push(KnownNetwork { ssid: cfg.wifi_ssid.as_str(), psk: cfg.wifi_psk.as_str(), priority: u8::MAX });
+    }
+    for n in crate::settings::WIFI_NETWORKS.iter().filter(|n| n.ssid != cfg.wifi_ssid.as_str()) {
+        if v.push(*n).is_err() {
+            break;
+        }
+    }
+    v
+}
+
+/// 登録済みネットワークをスキャンし、接続を試す順番（`known` の index 列）を返す。
+async fn scan_known_networks(
+    control: &'static crate::SharedControl,
+    known: &[KnownNetwork],
+) -> heapless::Vec<usize, MAX_KNOWN_NETWORKS> {
+    let mut best_rssi: [Option<i16>; MAX_KNOWN_NETWORKS] = [None; MAX_KNOWN_NETWORKS];
     {
-        return Err("DHCPタイムアウト");
+        let mut control = control.lock().await;
+        let mut scanner = control.scan(cyw43::ScanOptions::default()).await;
+        while let Some(bss) = scanner.next().await {
+            let len = (bss.ssid_len as usize).min(bss.ssid.len());
+            if let Some(i) = match_ssid(known, &bss.ssid[..len]) {
+                if i < MAX_KNOWN_NETWORKS && best_rssi[i].is_none_or(|r| bss.rssi > r) {
+                    best_rssi[i] = Some(bss.rssi);
+                }
+            }
+        }
+    }
+    for (i, n) in known.iter().enumerate().take(MAX_KNOWN_NETWORKS) {
+        match best_rssi[i] {
+            Some(r) => info!("WiFiスキャン: '{}' rssi={} (優先度{})", n.ssid, r, n.priority),
+            None => info!("WiFiスキャン: '{}' 圏外/非公開 (優先度{})", n.ssid, n.priority),
+        }
     }
+    connect_order(known, &best_rssi)
+}
+
+/// 登録済みネットワークへ順に接続を試み、DHCPでIPv4が得られるまで待つ。
+async fn join_and_wait_dhcp(
+    control: &'static crate::SharedControl,
+    stack: embassy_net::Stack<'static>,
+) -> Result<(), &'static str> {
+    use embassy_time::with_timeout;
 
-    if let Some(v4) = stack.config_v4() {
-        info!("IPv4取得成功: {}", defmt::Debug2Format(&v4.address));
+    let known = known_networks();
+    if known.is_empty() {
+        return Err("接続先のAPが未設定");
     }
+    let order = scan_known_networks(control, &known).await;
+    for &i in order.iter() {
+        let net = &known[i];
+        info!("WiFi接続開始: SSID='{}'", net.ssid);
+        // ロックは join の間だけ持つ（LED の点滅中は BLE 側が Control を使える）
+        led_connecting(control, 2).await;
+
+        let t0 = Instant::now();
+        let joined = control
+            .lock()
+            .await
+            .join(net.ssid, cyw43::JoinOptions::new(net.psk.as_bytes()))
+            .await;
+        if let Err(e) = joined {
+            warn!("WiFi接続失敗: '{}' {}", net.ssid, defmt::Debug2Format(&e));
+            led_connect_failed(control).await;
+            continue;
+        }
+
+        let ms = (Instant::now() - t0).as_millis();
+        info!("WiFi接続成功: '{}' ({}ms)", net.ssid, ms);
+        led_connected(control).await;
+
+        // DHCP待ち（タイムアウト付き）
+        if with_timeout(Duration::from_secs(10), stack.wait_config_up())
+            .await
+            .is_err()
+        {
+            warn!("DHCPタイムアウト: '{}'", net.ssid);
+            control.lock().await.leave().await;
+            continue;
+        }
 
-    Ok(stack)
+        if let Some(v4) = stack.config_v4() {
+            info!("IPv4取得成功: {}", defmt::Debug2Format(&v4.address));
+        }
+        return Ok(());
+    }
+    Err("全ての登録済みAPに接続失敗")
+}
+
+/// WiFiリンク監視タスク。
+/// - 未接続なら接続を試み、失敗時は指数バックオフ（ジッタ付き）で再試行
+/// - 接続後は NTP 同期し、リンク断（LINK/DEAUTH/DISASSOC イベント）を待って再接続
+#[embassy_executor::task]
+pub async fn wifi_supervisor_task(control: &'static crate::SharedControl, stack: embassy_net::Stack<'static>) -> ! {
+    use embassy_rp::clocks::RoscRng;
+    use rand_core::RngCore;
+
+    control
+        .lock()
+        .await
+        .set_power_management(cyw43::PowerManagementMode::PowerSave)
+        .await;
+
+    let mut attempt = 0u32;
+    loop {
+        match join_and_wait_dhcp(control, stack).await {
+            Ok(()) => {
+                attempt = 0;
+                // NTP時刻同期（失敗しても続行）
+                if let Err(e) = sync_ntp_time(stack).await {
+                    warn!("NTP同期に失敗: {}", e);
+                }
+                stack.wait_link_down().await;
+                warn!("WiFiリンク断を検出。再接続します");
+            }
+            Err(e) => {
+                attempt = attempt.saturating_add(1);
+                let wait = REJOIN_POLICY.delay_secs(attempt, RoscRng.next_u32());
+                warn!("WiFi接続失敗: {}（{}秒後に再試行）", e, wait);
+                Timer::after(Duration::from_secs(wait as u64)).await;
+            }
+        }
+    }
 }
 
 /// 簡易SNTPでNTP時刻同期（JSTでログ）
@@ -0,0 +1,79 @@
+//! 登録済みWiFiネットワークの選択（スキャン結果と優先度から接続順を決める）
+
+use heapless::Vec;
+
+/// 登録できるネットワークの最大数
+pub const MAX_KNOWN_NETWORKS: usize = 8;
+
+/// 登録済みネットワーク
+#[derive(Clone, Copy, Debug)]
+pub struct KnownNetwork {
+    pub ssid: &'static str,
+    pub psk: &'static str,
+    /// 優先度（大きいほど優先）
+    pub priority: u8,
+}
+
+/// スキャンで見つかった SSID が登録済みなら、その index を返す
+pub fn match_ssid(known: &[KnownNetwork], ssid: &[u8]) -> Option<usize> {
+    known.iter().position(|n| n.ssid.as_bytes() == ssid)
+}
+
+/// 接続を試す順番（`known` の index 列）を返す。
+/// - スキャンで見えたもの: 優先度の高い順、同じ優先度なら RSSI の強い順
+/// - 見えなかったもの（ステルスSSIDなど）: その後ろに優先度順
+///
+/// `best_rssi[i]` は `known[i]` の最良 RSSI（見えなければ None）。
+pub fn connect_order(known: &[KnownNetwork], best_rssi: &[Option<i16>]) -> Vec<usize, MAX_KNOWN_NETWORKS> {
+    let mut order: Vec<usize, MAX_KNOWN_NETWORKS> = Vec::new();
+    for i in 0..known.len().min(MAX_KNOWN_NETWORKS) {
+        let _ = order.push(i);
+    }
+    let key = |i: usize| {
+        let rssi = best_rssi.get(i).copied().flatten();
+        // 見えたもの → 優先度 → RSSI の順に比較（大きい方が先）
+        (rssi.is_some(), known[i].priority, rssi.unwrap_or(i16::MIN))
+    };
+    // 件数が少ないので挿入ソート（安定）
+    for j in 1..order.len() {
+        let mut k = j;
+        while k > 0 && key(order[k - 1]) < key(order[k]) {
+            order.swap(k - 1, k);
+            k -= 1;
+        }
+    }
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const KNOWN: [KnownNetwork; 4] = [
+        KnownNetwork { ssid: "home", psk: "a", priority: 10 },
+        KnownNetwork { ssid: "school", psk: "b", priority: 5 },
+        KnownNetwork { ssid: "office", psk: "c", priority: 5 },
+        KnownNetwork { ssid: "hidden", psk: "d", priority: 20 },
+    ];
+
+    #[test]
+    fn visible_networks_first_by_priority_then_rssi() {
+        // home は圏外、school(-70) と office(-50) が見えている
+        let rssi = [None, Some(-70), Some(-50), None];
+        let order = connect_order(&KNOWN, &rssi);
+        assert_eq!(order.as_slice(), &[2, 1, 3, 0]);
+    }
+
+    #[test]
+    fn nothing_visible_falls_back_to_priority() {
+        let order = connect_order(&KNOWN, &[None; 4]);
+        assert_eq!(order.as_slice(), &[3, 0, 1, 2]);
+    }
+
+    #[test]
+    fn matches_exact_ssid() {
+        assert_eq!(match_ssid(&KNOWN, b"school"), Some(1));
+        assert_eq!(match_ssid(&KNOWN, b"schoo"), None);
+    }
+}
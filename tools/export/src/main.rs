@@ -0,0 +1,242 @@
+//! すれ違いログのエクスポートツール（Linux）
+//! - USB シリアル（CDC-ACM）のコンソールに `export` を送り、フレーム（export_frame）で全件を受け取る
+//! - CSV / JSON に書き出す（WiFi / API サーバが使えない場所でのオフライン解析用）
+//! - フレーム形式・CRC はファームと同じソースを `#[path]` で取り込む
+//!
+//! 使い方:
+//!   picostreet-export [--port /dev/ttyACM0] [--format csv|json] [--out FILE]
+
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::process::{self, Command};
+use std::time::{Duration, Instant};
+
+#[path = "../../../src/crc.rs"]
+#[allow(dead_code)]
+mod crc;
+#[path = "../../../src/export_frame.rs"]
+#[allow(dead_code)]
+mod export_frame;
+#[path = "../../../src/peer_stats.rs"]
+#[allow(dead_code)]
+mod peer_stats;
+#[path = "../../../src/proximity.rs"]
+#[allow(dead_code)]
+mod proximity;
+
+use export_frame::{ExportHeader, ExportRecord, FrameDecoder, EXPORT_VERSION, FRAME_END, FRAME_HEADER, FRAME_RECORD};
+use proximity::{estimate_distance_cm, Proximity};
+
+/// データが途切れてから諦めるまでの時間
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Csv,
+    Json,
+}
+
+struct Args {
+    port: String,
+    format: Format,
+    out: Option<String>,
+}
+
+fn main() {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!("{e}");
+            eprintln!("使い方: picostreet-export [--port /dev/ttyACM0] [--format csv|json] [--out FILE]");
+            process::exit(2);
+        }
+    };
+    if let Err(e) = run(&args) {
+        eprintln!("エラー: {e}");
+        process::exit(1);
+    }
+}
+
+fn parse_args(mut it: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut args = Args { port: "/dev/ttyACM0".into(), format: Format::Csv, out: None };
+    while let Some(a) = it.next() {
+        let mut value = || it.next().ok_or(format!("{a} の値がありません"));
+        match a.as_str() {
+            "--port" | "-p" => args.port = value()?,
+            "--format" | "-f" => {
+                args.format = match value()?.as_str() {
+                    "csv" => Format::Csv,
+                    "json" => Format::Json,
+                    other => return Err(format!("不明な形式: {other}（csv / json）")),
+                }
+            }
+            "--out" | "-o" => args.out = Some(value()?),
+            other => return Err(format!("不明な引数: {other}")),
+        }
+    }
+    Ok(args)
+}
+
+fn run(args: &Args) -> Result<(), String> {
+    // raw モード・エコーなし・読み込みは最大 1 秒で戻る
+    let status = Command::new("stty")
+        .args(["-F", &args.port, "raw", "-echo", "min", "0", "time", "10"])
+        .status()
+        .map_err(|e| format!("stty を実行できません: {e}"))?;
+    if !status.success() {
+        return Err(format!("{} の設定に失敗しました", args.port));
+    }
+    let mut port = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&args.port)
+        .map_err(|e| format!("{} を開けません: {e}", args.port))?;
+
+    // 入力途中の行があれば CR で確定させてから export を送る
+    port.write_all(b"\rexport\r").map_err(|e| format!("送信失敗: {e}"))?;
+
+    let (header, records) = receive(&mut port)?;
+    // 古い形式（v1: TX 電力なし、v2: 相互確認なし）のファームからも読める
+    if header.version == 0 || header.version > EXPORT_VERSION {
+        return Err(format!("未対応の形式バージョン: {}", header.version));
+    }
+    if records.len() != header.count as usize {
+        return Err(format!("件数が一致しません（ヘッダ {} 件 / 受信 {} 件）", header.count, records.len()));
+    }
+    eprintln!("{} 件を受信（device={}）", records.len(), hex_colon(&header.device_id));
+
+    let text = match args.format {
+        Format::Csv => to_csv(&header, &records),
+        Format::Json => to_json(&header, &records),
+    };
+    match &args.out {
+        Some(path) => File::create(path).and_then(|mut f| f.write_all(text.as_bytes())),
+        None => io::stdout().write_all(text.as_bytes()),
+    }
+    .map_err(|e| format!("書き出し失敗: {e}"))
+}
+
+/// Header → Record × 件数 → End を受け取る
+fn receive(port: &mut File) -> Result<(ExportHeader, Vec<ExportRecord>), String> {
+    let mut dec = FrameDecoder::new();
+    let mut header = None;
+    let mut records = Vec::new();
+    let mut buf = [0u8; 256];
+    let mut last_rx = Instant::now();
+    loop {
+        let n = port.read(&mut buf).map_err(|e| format!("受信失敗: {e}"))?;
+        if n == 0 {
+            if last_rx.elapsed() > IDLE_TIMEOUT {
+                return Err("応答がありません（コンソールが開いているか、ポートが正しいか確認）".into());
+            }
+            continue;
+        }
+        last_rx = Instant::now();
+        for &b in &buf[..n] {
+            let Some(frame) = dec.push(b) else { continue };
+            match frame.frame_type {
+                FRAME_HEADER => {
+                    header = Some(ExportHeader::decode(&frame.payload).ok_or("ヘッダが不正です")?);
+                    records.clear();
+                }
+                FRAME_RECORD if header.is_some() => {
+                    records.push(ExportRecord::decode(&frame.payload).ok_or("レコードが不正です")?);
+                }
+                FRAME_END => {
+                    if let Some(h) = header {
+                        return Ok((h, records));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+const COLUMNS: &str = "device_id,exported_at,seq,mac_addr,first_seen,last_seen,count,rssi_min,rssi_mean,rssi_max,dwell_secs,tx_power,distance_cm,proximity,mutual,id_kind";
+
+/// 最接近時（RSSI 最大）の推定距離と近さの区分
+fn distance(r: &ExportRecord) -> (Option<u32>, Proximity) {
+    let cm = estimate_distance_cm(r.tx_power, r.stats.rssi_max);
+    (cm, Proximity::from_distance_cm(cm))
+}
+
+/// ID の種類（一時ID の行は epoch ごとの集計）
+fn id_kind(r: &ExportRecord) -> &'static str {
+    if r.ephemeral { "ephemeral" } else { "bd_addr" }
+}
+
+/// None は `none` に置き換えて表示する
+fn or<T: std::fmt::Display>(v: Option<T>, none: &str) -> String {
+    v.map_or_else(|| none.to_string(), |v| v.to_string())
+}
+
+fn to_csv(h: &ExportHeader, records: &[ExportRecord]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "{COLUMNS}");
+    for r in records {
+        let s = &r.stats;
+        let (cm, proximity) = distance(r);
+        let _ = writeln!(
+            out,
+            "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            hex_colon(&h.device_id),
+            h.exported_at,
+            r.seq,
+            hex_colon(&r.mac_addr),
+            s.first_seen,
+            s.last_seen,
+            s.count,
+            s.rssi_min,
+            s.rssi_mean(),
+            s.rssi_max,
+            s.dwell_secs,
+            or(r.tx_power, ""),
+            or(cm, ""),
+            proximity.as_str(),
+            r.mutual,
+            id_kind(r)
+        );
+    }
+    out
+}
+
+fn to_json(h: &ExportHeader, records: &[ExportRecord]) -> String {
+    let mut out = String::new();
+    let _ = write!(
+        out,
+        "{{\"device_id\":\"{}\",\"exported_at\":{},\"encounters\":[",
+        hex_colon(&h.device_id),
+        h.exported_at
+    );
+    for (i, r) in records.iter().enumerate() {
+        let s = &r.stats;
+        let (cm, proximity) = distance(r);
+        let _ = write!(
+            out,
+            "{}\n  {{\"seq\":{},\"mac_addr\":\"{}\",\"first_seen\":{},\"last_seen\":{},\"count\":{},\"rssi_min\":{},\"rssi_mean\":{},\"rssi_max\":{},\"dwell_secs\":{},\"tx_power\":{},\"distance_cm\":{},\"proximity\":\"{}\",\"mutual\":{},\"id_kind\":\"{}\"}}",
+            if i == 0 { "" } else { "," },
+            r.seq,
+            hex_colon(&r.mac_addr),
+            s.first_seen,
+            s.last_seen,
+            s.count,
+            s.rssi_min,
+            s.rssi_mean(),
+            s.rssi_max,
+            s.dwell_secs,
+            or(r.tx_power, "null"),
+            or(cm, "null"),
+            proximity.as_str(),
+            r.mutual,
+            id_kind(r)
+        );
+    }
+    out.push_str("\n]}\n");
+    out
+}
+
+fn hex_colon(b: &[u8]) -> String {
+    b.iter().map(|x| format!("{x:02X}")).collect::<Vec<_>>().join(":")
+}